@@ -0,0 +1,2 @@
+DROP TYPE IF EXISTS token_type;
+DROP TYPE IF EXISTS user_role;
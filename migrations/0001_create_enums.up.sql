@@ -0,0 +1,3 @@
+-- Postgres enums backing user_lib::UserRoles and token_lib::TokenType
+CREATE TYPE user_role AS ENUM ('admin', 'normal');
+CREATE TYPE token_type AS ENUM ('access_token', 'refresh_token');
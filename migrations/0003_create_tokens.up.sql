@@ -0,0 +1,10 @@
+CREATE TABLE tokens (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    token_string VARCHAR NOT NULL,
+    token_type token_type NOT NULL,
+    blacklisted BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX tokens_token_string_idx ON tokens (token_string);
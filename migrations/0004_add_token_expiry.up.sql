@@ -0,0 +1,4 @@
+-- NULL means the token never expires
+ALTER TABLE tokens ADD COLUMN expires_at TIMESTAMP NULL;
+
+CREATE INDEX tokens_expires_at_idx ON tokens (expires_at);
@@ -0,0 +1,3 @@
+ALTER TABLE tokens DROP COLUMN IF EXISTS revocation_reason;
+ALTER TABLE tokens DROP COLUMN IF EXISTS revoked_at;
+DROP TYPE IF EXISTS revocation_reason;
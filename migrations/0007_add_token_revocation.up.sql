@@ -0,0 +1,6 @@
+-- backs token_store::RevocationReason
+CREATE TYPE revocation_reason AS ENUM ('logout', 'password_change', 'admin', 'reuse_detected');
+
+-- set when a token is blacklisted; rotated tokens have revoked_at but no reason
+ALTER TABLE tokens ADD COLUMN revoked_at TIMESTAMP NULL;
+ALTER TABLE tokens ADD COLUMN revocation_reason revocation_reason NULL;
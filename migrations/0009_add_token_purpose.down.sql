@@ -0,0 +1,2 @@
+ALTER TABLE tokens DROP COLUMN IF EXISTS purpose;
+DROP TYPE IF EXISTS token_purpose;
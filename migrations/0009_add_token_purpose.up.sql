@@ -0,0 +1,5 @@
+-- backs token_store::TokenPurpose, tokens other than sessions are single use
+-- tokens mailed to a user and are never accepted as sessions
+CREATE TYPE token_purpose AS ENUM ('session', 'email_confirmation');
+
+ALTER TABLE tokens ADD COLUMN purpose token_purpose NOT NULL DEFAULT 'session';
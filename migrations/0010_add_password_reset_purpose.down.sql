@@ -0,0 +1,8 @@
+-- enum values can not be dropped, the type is recreated without it
+DELETE FROM tokens WHERE purpose = 'password_reset';
+ALTER TABLE tokens ALTER COLUMN purpose DROP DEFAULT;
+ALTER TYPE token_purpose RENAME TO token_purpose_old;
+CREATE TYPE token_purpose AS ENUM ('session', 'email_confirmation');
+ALTER TABLE tokens ALTER COLUMN purpose TYPE token_purpose USING purpose::text::token_purpose;
+ALTER TABLE tokens ALTER COLUMN purpose SET DEFAULT 'session';
+DROP TYPE token_purpose_old;
@@ -0,0 +1 @@
+ALTER TYPE token_purpose ADD VALUE 'password_reset';
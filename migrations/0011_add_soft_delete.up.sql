@@ -0,0 +1,4 @@
+-- soft deleted rows are kept with deleted_at set and hidden from reads,
+-- they still hold their username and email until they are hard deleted
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMP NULL;
+ALTER TABLE tokens ADD COLUMN deleted_at TIMESTAMP NULL;
@@ -0,0 +1,19 @@
+-- backs audit::AuditOperation
+CREATE TYPE audit_operation AS ENUM ('insert', 'update', 'patch', 'delete');
+
+-- one row per store write, changes maps every changed column to its before
+-- and after value; actor_id is kept without a foreign key so the history
+-- outlives the actor, and created_at is the clock time rather than the
+-- transaction start so writes of one transaction stay in order
+CREATE TABLE audit_log (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    actor_id UUID NULL,
+    entity VARCHAR NOT NULL,
+    entity_id UUID NOT NULL,
+    operation audit_operation NOT NULL,
+    changes JSONB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT clock_timestamp()
+);
+
+CREATE INDEX audit_log_entity_idx ON audit_log (entity, entity_id, created_at);
+CREATE INDEX audit_log_actor_id_idx ON audit_log (actor_id, created_at);
@@ -0,0 +1,6 @@
+-- bumped by every write that sets updated_at, update_if_version and
+-- patch_if_version only write when it still has the value the caller read;
+-- login bookkeeping (failed_login_count, locked_until, last_login_at) sets
+-- neither
+ALTER TABLE users ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
+ALTER TABLE tokens ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
@@ -0,0 +1,2 @@
+CREATE INDEX IF NOT EXISTS tokens_token_string_idx ON tokens (token_string);
+ALTER TABLE tokens DROP CONSTRAINT IF EXISTS tokens_token_string_key;
@@ -0,0 +1,17 @@
+-- upsert on tokens detects an existing token by its token_string, the
+-- constraint replaces the plain index.
+--
+-- A database holding a token_string in several rows is refused instead of
+-- cleaned up here, which rows to keep is for the operator to decide (the
+-- blacklisted or revoked one, where one is). List them with
+--   SELECT token_string, count(*) FROM tokens GROUP BY token_string HAVING count(*) > 1;
+-- delete the rows to drop and run the migration again.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM tokens GROUP BY token_string HAVING count(*) > 1) THEN
+        RAISE EXCEPTION 'tokens holds duplicate token_string values, remove them before adding tokens_token_string_key';
+    END IF;
+END
+$$;
+ALTER TABLE tokens ADD CONSTRAINT tokens_token_string_key UNIQUE (token_string);
+DROP INDEX IF EXISTS tokens_token_string_idx;
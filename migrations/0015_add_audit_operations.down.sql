@@ -0,0 +1,7 @@
+-- enum values can not be dropped, the type is recreated without them and
+-- their entries are kept as updates
+ALTER TYPE audit_operation RENAME TO audit_operation_old;
+CREATE TYPE audit_operation AS ENUM ('insert', 'update', 'patch', 'delete');
+ALTER TABLE audit_log ALTER COLUMN operation TYPE audit_operation
+    USING (CASE WHEN operation::text IN ('revoke', 'restore') THEN 'update' ELSE operation::text END)::audit_operation;
+DROP TYPE audit_operation_old;
@@ -0,0 +1,3 @@
+-- revocations and restores are told apart from plain updates in the history
+ALTER TYPE audit_operation ADD VALUE 'revoke';
+ALTER TYPE audit_operation ADD VALUE 'restore';
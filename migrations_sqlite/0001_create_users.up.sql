@@ -0,0 +1,14 @@
+-- SQLite has no enum types, user_role is text limited by a check constraint.
+-- StoreError relies on the users_username_key and users_email_key constraint names
+CREATE TABLE users (
+    id BLOB PRIMARY KEY NOT NULL,
+    username VARCHAR NOT NULL,
+    email VARCHAR NOT NULL,
+    password_hash VARCHAR NOT NULL,
+    user_role TEXT NOT NULL DEFAULT 'normal' CONSTRAINT user_role_check CHECK (user_role IN ('admin', 'normal')),
+    confirmed BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT users_username_key UNIQUE (username),
+    CONSTRAINT users_email_key UNIQUE (email)
+);
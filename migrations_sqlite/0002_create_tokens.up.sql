@@ -0,0 +1,10 @@
+CREATE TABLE tokens (
+    id BLOB PRIMARY KEY NOT NULL,
+    token_string VARCHAR NOT NULL,
+    token_type TEXT NOT NULL CONSTRAINT token_type_check CHECK (token_type IN ('access_token', 'refresh_token')),
+    blacklisted BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX tokens_token_string_idx ON tokens (token_string);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS tokens_expires_at_idx;
+ALTER TABLE tokens DROP COLUMN expires_at;
@@ -0,0 +1,19 @@
+-- SQLite can not drop a column used by a foreign key, so the table is rebuilt
+CREATE TABLE tokens_without_user (
+    id BLOB PRIMARY KEY NOT NULL,
+    token_string VARCHAR NOT NULL,
+    token_type TEXT NOT NULL CONSTRAINT token_type_check CHECK (token_type IN ('access_token', 'refresh_token')),
+    blacklisted BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NULL
+);
+
+INSERT INTO tokens_without_user (id, token_string, token_type, blacklisted, created_at, updated_at, expires_at)
+SELECT id, token_string, token_type, blacklisted, created_at, updated_at, expires_at FROM tokens;
+
+DROP TABLE tokens;
+ALTER TABLE tokens_without_user RENAME TO tokens;
+
+CREATE INDEX tokens_token_string_idx ON tokens (token_string);
+CREATE INDEX tokens_expires_at_idx ON tokens (expires_at);
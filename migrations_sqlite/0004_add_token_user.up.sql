@@ -0,0 +1,4 @@
+-- NULL for tokens that are not tied to a user, tokens go with their user
+ALTER TABLE tokens ADD COLUMN user_id BLOB NULL REFERENCES users (id) ON DELETE CASCADE;
+
+CREATE INDEX tokens_user_id_idx ON tokens (user_id);
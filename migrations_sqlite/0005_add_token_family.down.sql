@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS tokens_family_id_idx;
+ALTER TABLE tokens DROP COLUMN replaced_by;
+ALTER TABLE tokens DROP COLUMN family_id;
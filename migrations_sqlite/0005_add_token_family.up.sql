@@ -0,0 +1,6 @@
+-- refresh tokens rotated from the same login share a family, named by its
+-- first token; replaced_by points at the successor of a rotated token
+ALTER TABLE tokens ADD COLUMN family_id BLOB NULL;
+ALTER TABLE tokens ADD COLUMN replaced_by BLOB NULL;
+
+CREATE INDEX tokens_family_id_idx ON tokens (family_id);
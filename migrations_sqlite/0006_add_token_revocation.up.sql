@@ -0,0 +1,3 @@
+-- set when a token is blacklisted; rotated tokens have revoked_at but no reason
+ALTER TABLE tokens ADD COLUMN revoked_at TIMESTAMP NULL;
+ALTER TABLE tokens ADD COLUMN revocation_reason TEXT NULL CONSTRAINT revocation_reason_check CHECK (revocation_reason IN ('logout', 'password_change', 'admin', 'reuse_detected'));
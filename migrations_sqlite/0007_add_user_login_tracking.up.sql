@@ -0,0 +1,5 @@
+-- failed logins since the last successful one, the account refuses logins
+-- until locked_until once the lockout policy kicks in
+ALTER TABLE users ADD COLUMN failed_login_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN locked_until TIMESTAMP NULL;
+ALTER TABLE users ADD COLUMN last_login_at TIMESTAMP NULL;
@@ -0,0 +1,3 @@
+-- tokens other than sessions are single use tokens mailed to a user and are
+-- never accepted as sessions
+ALTER TABLE tokens ADD COLUMN purpose TEXT NOT NULL DEFAULT 'session' CONSTRAINT purpose_check CHECK (purpose IN ('session', 'email_confirmation'));
@@ -0,0 +1,5 @@
+DELETE FROM tokens WHERE purpose = 'password_reset';
+ALTER TABLE tokens ADD COLUMN purpose_without_reset TEXT NOT NULL DEFAULT 'session' CONSTRAINT purpose_check CHECK (purpose_without_reset IN ('session', 'email_confirmation'));
+UPDATE tokens SET purpose_without_reset = purpose;
+ALTER TABLE tokens DROP COLUMN purpose;
+ALTER TABLE tokens RENAME COLUMN purpose_without_reset TO purpose;
@@ -0,0 +1,6 @@
+-- check constraints can not be altered, the column is swapped for one with
+-- the wider check
+ALTER TABLE tokens ADD COLUMN purpose_with_reset TEXT NOT NULL DEFAULT 'session' CONSTRAINT purpose_check CHECK (purpose_with_reset IN ('session', 'email_confirmation', 'password_reset'));
+UPDATE tokens SET purpose_with_reset = purpose;
+ALTER TABLE tokens DROP COLUMN purpose;
+ALTER TABLE tokens RENAME COLUMN purpose_with_reset TO purpose;
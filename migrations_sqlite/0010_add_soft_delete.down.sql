@@ -0,0 +1,4 @@
+DELETE FROM tokens WHERE deleted_at IS NOT NULL;
+DELETE FROM users WHERE deleted_at IS NOT NULL;
+ALTER TABLE tokens DROP COLUMN deleted_at;
+ALTER TABLE users DROP COLUMN deleted_at;
@@ -0,0 +1,3 @@
+-- bumped by every write that sets updated_at
+ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE tokens ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
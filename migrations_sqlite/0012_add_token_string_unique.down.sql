@@ -0,0 +1,2 @@
+CREATE INDEX IF NOT EXISTS tokens_token_string_idx ON tokens (token_string);
+DROP INDEX IF EXISTS tokens_token_string_key;
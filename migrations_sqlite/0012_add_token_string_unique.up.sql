@@ -0,0 +1,6 @@
+-- SQLite can not add a constraint to a table, the unique index stands in for
+-- the tokens_token_string_key constraint of Postgres. Like there, a database
+-- holding a token_string in several rows fails here and has to be cleaned up
+-- by hand first, see the Postgres migration.
+CREATE UNIQUE INDEX tokens_token_string_key ON tokens (token_string);
+DROP INDEX IF EXISTS tokens_token_string_idx;
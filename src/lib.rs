@@ -1,3 +1,4 @@
+pub mod migrations;
 pub mod stores;
 use crate::stores::{store::Store,user_store::UserPGStore,store::StoreError,user_store::UserRow};
 use user_lib::user::user::{User,UserRoles};
@@ -6,7 +7,9 @@ use user_lib::user::user::{User,UserRoles};
 #[cfg(test)]
 mod tests {
     use serde::Serialize;
-    use stores::{store::StoreTrait, token_store::{TokenPGStore, TokenRow}};
+    use stores::{store::{StoreTrait, TypedStore}, token_store::{NewToken, RevocationReason, TokenPGStore, TokenRow}};
+    use stores::audit::{AuditOperation, AuditPGStore, REDACTED};
+    use stores::{auth::{needs_rehash, AuthError, ConfirmationPolicy, LockoutPolicy, PasswordResetPolicy}, cursor::{PageRequest, MAX_PAGE_LIMIT}, filter::{compare_json, Filter, Operator, SortKey}, columns::SqlType, token_hash::TokenHasher};
     use random_string::generate;
     use user_lib::user;
     use sqlx::{postgres::PgPoolOptions, Postgres,Pool};
@@ -17,8 +20,15 @@ mod tests {
     use token_lib::token::token::{Token,TokenType};
     use super::*;
 
+    // the stores refuse to make up keys, every test runs with the same ones
+    fn set_test_secrets(){
+        env::set_var("STORE_TOKEN_SECRET","test token secret");
+        env::set_var("STORE_CURSOR_SECRET","test cursor secret");
+    }
+
     async fn get_connection(db_url:&str)-> Result<Pool<Postgres>, Box<dyn std::error::Error>>
     {
+        set_test_secrets();
         let db = match PgPoolOptions::new()
             .max_connections(10)
             .connect(db_url)
@@ -33,6 +43,7 @@ mod tests {
                 std::process::exit(1);
             }
         };
+        migrations::run_migrations(&db).await?;
         Ok(db)
     }
     fn get_random_string(length: usize)->String{
@@ -46,6 +57,1479 @@ mod tests {
         User::new(mString,String::from("rillo"),email,UserRoles::Normal)
     }
 
+    fn get_sample_user_named(username:&str)->User{
+        User::new(username.to_string(),String::from("rillo"),format!("{}@gmail.com",get_random_string(10)),UserRoles::Normal)
+    }
+
+    #[tokio::test]
+    async fn migrations_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+
+        let version = migrations::current_version(&db_connection).await.expect("unable to read schema version");
+        assert_eq!(version,Some(migrations::latest_version()));
+
+        // running again on an up to date database is a no-op
+        migrations::run_migrations(&db_connection).await.expect("second migration run failed");
+        let version = migrations::current_version(&db_connection).await.expect("unable to read schema version");
+        assert_eq!(version,Some(migrations::latest_version()));
+    }
+
+    #[tokio::test]
+    async fn transaction_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+
+        // commit
+        let dummy_user = get_sample_user();
+        let user_json = serde_json::to_value(&dummy_user).expect("serialization failed");
+        let token_json = serde_json::to_value(&Token::new(get_random_string(10),TokenType::AccessToken)).expect("serialization failed");
+        Store::transaction(&db_connection, |tx| Box::pin(async move {
+            UserPGStore::default().insert(&mut **tx, user_json).await?;
+            TokenPGStore::default().insert(&mut **tx, token_json).await
+        })).await.expect("transaction failed");
+        user_store.get_by_username(&db_connection,dummy_user.get_name()).await.expect("committed user not found");
+
+        // rollback
+        let dummy_user = get_sample_user();
+        let user_json = serde_json::to_value(&dummy_user).expect("serialization failed");
+        let result:Result<(),StoreError> = Store::transaction(&db_connection, |tx| Box::pin(async move {
+            UserPGStore::default().insert(&mut **tx, user_json).await?;
+            Err(StoreError::NotFound)
+        })).await;
+        assert!(result.is_err());
+        let user_data = user_store.get_by_username(&db_connection,dummy_user.get_name()).await;
+        assert!(matches!(user_data,Err(StoreError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn typed_store_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let mut conn = db_connection.acquire().await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+        let token_store = TokenPGStore::default();
+
+        let dummy_user = get_sample_user();
+        user_store.insert_row(&mut conn,dummy_user.clone()).await.expect("insertion failed");
+        let json_slug = serde_json::json!({
+            "username": dummy_user.get_name()
+        });
+        let user_rows = user_store.get_rows_by_slug(&mut conn,json_slug).await.expect("unable to get user with name");
+        let user_row = user_rows.first().expect("user not found").to_owned();
+        let user_row = user_store.get_row(&mut conn,user_row.id).await.expect("unable to get user with id").expect("user not found");
+        let returned_data:User = user_row.into();
+        assert_eq!(dummy_user.get_name(),returned_data.get_name());
+        assert_eq!(dummy_user.get_role(),returned_data.get_role());
+
+        let token_string = get_random_string(10);
+        token_store.insert_row(&mut conn,Token::new(token_string.clone(),TokenType::RefreshToken)).await.expect("insertion failed");
+        let json_slug = serde_json::json!({
+            "token_string": token_string
+        });
+        let token_rows = token_store.get_rows_by_slug(&mut conn,json_slug).await.expect("unable to get token");
+        let returned_token:Token = token_rows.first().expect("token not found").to_owned().into();
+        assert_eq!(returned_token.get_token(),TokenHasher::global().unwrap().hash(&token_string));
+        assert_eq!(returned_token.get_type(),TokenType::RefreshToken);
+
+        let missing = token_store.get_row(&mut conn,Uuid::nil()).await.expect("unable to get token");
+        assert!(missing.is_none());
+    }
+
+    #[tokio::test]
+    async fn column_whitelist_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+        let token_store = TokenPGStore::default();
+
+        let json_slug = serde_json::json!({
+            "username = username OR 1=1 --": "anything"
+        });
+        let result = user_store.get_by_slug(&db_connection,json_slug).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+
+        let json_slug = serde_json::json!({
+            "password_hash": "anything"
+        });
+        let result = user_store.get_by_slug(&db_connection,json_slug).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+
+        let json_patch = serde_json::json!({
+            "created_at": "2020-01-01T00:00:00"
+        });
+        let result = token_store.patch(&db_connection,Uuid::nil(),json_patch).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+
+        let json_patch = serde_json::json!({
+            "id": Uuid::nil()
+        });
+        let result = user_store.patch(&db_connection,Uuid::nil(),json_patch).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+
+        // values are bound with the type of their column
+        let json_slug = serde_json::json!({
+            "blacklisted": false,
+            "token_type": TokenType::AccessToken
+        });
+        token_store.get_by_slug(&db_connection,json_slug).await.expect("typed filter failed");
+    }
+
+    #[tokio::test]
+    async fn filter_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+
+        let mut names = Vec::new();
+        for _ in 0..3 {
+            let dummy_user = get_sample_user();
+            let user_json = serde_json::to_value(&dummy_user).expect("serialization failed");
+            user_store.insert(&db_connection,user_json).await.expect("insertion failed");
+            names.push(dummy_user.get_name().to_string());
+        }
+        let mut sorted_names = names.clone();
+        sorted_names.sort();
+
+        // IN with ordering and limit
+        let json_filter = serde_json::json!({
+            "username": {"$in": names},
+            "$order_by": "-username",
+            "$limit": 2
+        });
+        let user_data = user_store.get_by_slug(&db_connection,json_filter).await.expect("in filter failed");
+        let returned_names:Vec<String> = user_data.iter().map(|user| user["username"].as_str().unwrap().to_string()).collect();
+        assert_eq!(returned_names,vec![sorted_names[2].clone(),sorted_names[1].clone()]);
+
+        // OR group
+        let json_filter = serde_json::json!({
+            "$or": [
+                {"username": names[0]},
+                {"email": {"$ilike": format!("{}@GMAIL.COM",names[1].to_uppercase())}}
+            ]
+        });
+        let user_data = user_store.get_by_slug(&db_connection,json_filter).await.expect("or filter failed");
+        assert_eq!(user_data.len(),2);
+
+        // range and inequality
+        let json_filter = serde_json::json!({
+            "username": {"$in": names, "$ne": names[0]},
+            "created_at": {"$gte": "2000-01-01T00:00:00", "$lt": "2100-01-01T00:00:00"}
+        });
+        let user_data = user_store.get_by_slug(&db_connection,json_filter).await.expect("range filter failed");
+        assert_eq!(user_data.len(),2);
+
+        let json_filter = serde_json::json!({
+            "confirmed": {"$like": "tr%"}
+        });
+        let result = user_store.get_by_slug(&db_connection,json_filter).await;
+        assert!(matches!(result,Err(StoreError::InvalidFilter(_))));
+
+        // in memory, enums order as Postgres declares them, not by label
+        let ordering = compare_json(SqlType::TokenPurpose,&serde_json::json!("session"),&serde_json::json!("email_confirmation")).expect("compare failed");
+        assert_eq!(ordering,Some(std::cmp::Ordering::Less));
+        let ordering = compare_json(SqlType::RevocationReason,&serde_json::json!("reuse_detected"),&serde_json::json!("admin")).expect("compare failed");
+        assert_eq!(ordering,Some(std::cmp::Ordering::Greater));
+        let ordering = compare_json(SqlType::RevocationReason,&serde_json::json!("logout"),&serde_json::json!("admin")).expect("compare failed");
+        assert_eq!(ordering,Some(std::cmp::Ordering::Less));
+    }
+
+    #[tokio::test]
+    async fn cursor_pagination_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+
+        let mut names = Vec::new();
+        for _ in 0..5 {
+            let dummy_user = get_sample_user();
+            let user_json = serde_json::to_value(&dummy_user).expect("serialization failed");
+            user_store.insert(&db_connection,user_json).await.expect("insertion failed");
+            names.push(dummy_user.get_name().to_string());
+        }
+        names.sort();
+        let page_names = |items:&Vec<serde_json::Value>| -> Vec<String> {
+            items.iter().map(|user| user["username"].as_str().unwrap().to_string()).collect()
+        };
+        let request = PageRequest::new(2)
+            .filter(Filter::condition("username",Operator::In,serde_json::json!(names)))
+            .sort_by(vec![SortKey::asc("username")]);
+
+        let first_page = user_store.get_page(&db_connection,request.clone()).await.expect("first page failed");
+        assert_eq!(page_names(&first_page.items),names[0..2].to_vec());
+        assert!(first_page.prev_cursor.is_none());
+        let next_cursor = first_page.next_cursor.expect("missing next cursor");
+
+        let second_page = user_store.get_page(&db_connection,request.clone().cursor(&next_cursor)).await.expect("second page failed");
+        assert_eq!(page_names(&second_page.items),names[2..4].to_vec());
+        let next_cursor = second_page.next_cursor.expect("missing next cursor");
+
+        let last_page = user_store.get_page(&db_connection,request.clone().cursor(&next_cursor)).await.expect("last page failed");
+        assert_eq!(page_names(&last_page.items),names[4..5].to_vec());
+        assert!(last_page.next_cursor.is_none());
+        let prev_cursor = last_page.prev_cursor.expect("missing prev cursor");
+
+        let previous_page = user_store.get_page(&db_connection,request.clone().cursor(&prev_cursor)).await.expect("previous page failed");
+        assert_eq!(page_names(&previous_page.items),names[2..4].to_vec());
+        assert!(previous_page.prev_cursor.is_some());
+
+        // tampered cursors and cursors of another ordering are rejected
+        let tampered = format!("x{}",next_cursor);
+        let result = user_store.get_page(&db_connection,request.clone().cursor(&tampered)).await;
+        assert!(matches!(result,Err(StoreError::InvalidCursor)));
+        let result = user_store.get_page(&db_connection,PageRequest::new(2).cursor(&next_cursor)).await;
+        assert!(matches!(result,Err(StoreError::InvalidCursor)));
+        // as are cursors of another filter
+        let widened = request.clone().filter(Filter::default()).cursor(&next_cursor);
+        let result = user_store.get_page(&db_connection,widened).await;
+        assert!(matches!(result,Err(StoreError::InvalidCursor)));
+
+        let mut oversized = request.clone();
+        oversized.limit = MAX_PAGE_LIMIT + 1;
+        let result = user_store.get_page(&db_connection,oversized).await;
+        assert!(matches!(result,Err(StoreError::InvalidFilter(_))));
+        let mut oversized = request.clone();
+        oversized.limit = i64::MAX;
+        let result = user_store.get_page(&db_connection,oversized).await;
+        assert!(matches!(result,Err(StoreError::InvalidFilter(_))));
+    }
+
+    #[tokio::test]
+    async fn returning_rows_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+        let token_store = TokenPGStore::default();
+
+        // insert hands back the generated columns
+        let dummy_user = get_sample_user();
+        let user_json = serde_json::to_value(&dummy_user).expect("serialization failed");
+        let user_data = user_store.insert(&db_connection,user_json).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        assert_eq!(user_row.username,dummy_user.get_name());
+        assert_ne!(user_row.id,Uuid::nil());
+        let id = user_row.id;
+
+        let new_user = get_sample_user();
+        let new_user_json = serde_json::to_value(&new_user).expect("serialization failed");
+        let user_data = user_store.update(&db_connection,id,new_user_json.clone()).await.expect("user update failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        assert_eq!(user_row.id,id);
+        assert_eq!(user_row.username,new_user.get_name());
+
+        let new_name = get_random_string(10);
+        let user_data = user_store.patch(&db_connection,id,serde_json::json!({"username": new_name})).await.expect("unable to patch user");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        assert_eq!(user_row.username,new_name);
+        assert!(user_row.updated_at >= user_row.created_at);
+
+        // writes to a missing row fail instead of silently succeeding
+        user_store.delete(&db_connection,id).await.expect("delete by id failed");
+        let result = user_store.delete(&db_connection,id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let result = user_store.update(&db_connection,id,new_user_json).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let result = user_store.patch(&db_connection,id,serde_json::json!({"confirmed": true})).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        let mut conn = db_connection.acquire().await.expect("could not acquire connection");
+        let token_string = get_random_string(10);
+        let token_row = token_store.insert_row(&mut conn,Token::new(token_string.clone(),TokenType::AccessToken)).await.expect("insertion failed");
+        assert_eq!(token_row.token_string,TokenHasher::global().unwrap().hash(&token_string));
+        // tokens are revoked through revoke only, which records when and why
+        let result = token_store.patch_row(&mut conn,token_row.id,serde_json::json!({"blacklisted": true})).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{column,reason}) if column == "blacklisted" && reason == "is written through revoke"));
+        let token_row = token_store.revoke(&mut *conn,&token_string,RevocationReason::Logout).await.expect("revoke failed");
+        assert!(token_row.blacklisted);
+        assert!(token_row.revoked_at.is_some());
+        token_store.delete(&mut *conn,token_row.id).await.expect("delete by id failed");
+        let result = token_store.patch_row(&mut conn,token_row.id,serde_json::json!({"expires_at": null})).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn store_error_test() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<StoreError>();
+
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+
+        let dummy_user = get_sample_user();
+        let user_json = serde_json::to_value(&dummy_user).expect("serialization failed");
+        let user_data = user_store.insert(&db_connection,user_json.clone()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+
+        // same username and email again
+        let error = user_store.insert(&db_connection,user_json).await.expect_err("duplicate insertion succeeded");
+        assert!(matches!(&error,StoreError::UniqueViolation{field,..} if field == "username"));
+        assert_eq!(error.code(),"unique_violation");
+        assert_eq!(error.http_status(),409);
+        assert_eq!(error.to_string(),"Store Error: username taken");
+        // the database error stays reachable as the source
+        let source = std::error::Error::source(&error).expect("missing source");
+        assert!(source.downcast_ref::<sqlx::Error>().is_some());
+
+        let other_user = get_sample_user();
+        let other_json = serde_json::to_value(&other_user).expect("serialization failed");
+        let other_data = user_store.insert(&db_connection,other_json).await.expect("insertion failed");
+        let other_row:UserRow = serde_json::from_value(other_data).expect("json conversion error");
+        let error = user_store.patch(&db_connection,other_row.id,serde_json::json!({"email": user_row.email})).await.expect_err("duplicate email accepted");
+        assert!(matches!(&error,StoreError::UniqueViolation{field,..} if field == "email"));
+
+        let error = user_store.patch(&db_connection,other_row.id,serde_json::json!({"username": null})).await.expect_err("null username accepted");
+        assert!(matches!(&error,StoreError::InvalidInput{field,..} if field == "username"));
+        assert!(std::error::Error::source(&error).is_some());
+        assert_eq!(error.code(),"invalid_input");
+        assert_eq!(error.http_status(),422);
+
+        let error = user_store.delete(&db_connection,Uuid::nil()).await.expect_err("missing user deleted");
+        assert_eq!(error.code(),"not_found");
+        assert_eq!(error.http_status(),404);
+
+        let error = StoreError::from(sqlx::Error::PoolTimedOut);
+        assert!(matches!(error,StoreError::Unavailable(_)));
+        assert_eq!(error.http_status(),503);
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    // Behaviour every backend of StoreTrait has to share.
+    async fn user_store_conformance<C, S>(user_store:&S, connection:C)
+    where
+        C: Copy + Send,
+        S: StoreTrait<C> + Sync,
+    {
+        let first_user = get_sample_user();
+        let second_user = get_sample_user();
+        let user_data = user_store.insert(connection,serde_json::to_value(&first_user).unwrap()).await.expect("insertion failed");
+        let first_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        assert_eq!(first_row.username,first_user.get_name());
+        assert_ne!(first_row.password_hash,first_user.get_password());
+        let user_data = user_store.insert(connection,serde_json::to_value(&second_user).unwrap()).await.expect("insertion failed");
+        let second_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+
+        // unique username and email
+        let same_name = User::new(first_user.get_name().to_string(),String::from("rillo"),format!("{}@gmail.com",get_random_string(10)),UserRoles::Normal);
+        let result = user_store.insert(connection,serde_json::to_value(&same_name).unwrap()).await;
+        assert!(matches!(result,Err(StoreError::UniqueViolation{field,..}) if field == "username"));
+        let same_email = User::new(get_random_string(10),String::from("rillo"),first_user.get_email().to_string(),UserRoles::Normal);
+        let result = user_store.insert(connection,serde_json::to_value(&same_email).unwrap()).await;
+        assert!(matches!(result,Err(StoreError::UniqueViolation{field,..}) if field == "email"));
+
+        // get
+        let user_data = user_store.get(connection,first_row.id).await.expect("unable to get user with id");
+        assert_eq!(user_data.len(),1);
+        assert_eq!(user_data[0]["username"],first_user.get_name());
+        let user_data = user_store.get(connection,Uuid::nil()).await.expect("unable to get user with id");
+        assert!(user_data.is_empty());
+        assert!(user_store.count(connection).await.expect("get count failed") >= 2);
+        let user_data = user_store.get_all_paginate(connection,1,0).await.expect("unable to get paginated user");
+        assert_eq!(user_data.len(),1);
+
+        // slug filters
+        let mut names = vec![first_user.get_name().to_string(),second_user.get_name().to_string()];
+        names.sort();
+        let json_slug = serde_json::json!({
+            "$or": [{"username": names[0]}, {"email": {"$ilike": format!("{}@GMAIL.com",names[1])}}],
+            "$order_by": "username"
+        });
+        let user_data = user_store.get_by_slug(connection,json_slug).await.expect("unable to get user with slug");
+        let found = user_data.iter().map(|user| user["username"].as_str().unwrap().to_string()).collect::<Vec<String>>();
+        assert_eq!(found,names);
+        let json_slug = serde_json::json!({"username": {"$in": names}, "$limit": 1});
+        let user_data = user_store.get_by_slug(connection,json_slug).await.expect("unable to get user with slug");
+        assert_eq!(user_data.len(),1);
+        let result = user_store.get_by_slug(connection,serde_json::json!({"password_hash": "x"})).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+
+        // cursor pagination
+        let request = PageRequest::new(1)
+            .filter(Filter::condition("username",Operator::In,serde_json::json!(names)))
+            .sort_by(vec![SortKey::desc("username")]);
+        let page = user_store.get_page(connection,request.clone()).await.expect("first page failed");
+        assert_eq!(page.items[0]["username"],names[1]);
+        let next_cursor = page.next_cursor.expect("missing next cursor");
+        let page = user_store.get_page(connection,request.clone().cursor(&next_cursor)).await.expect("second page failed");
+        assert_eq!(page.items[0]["username"],names[0]);
+        assert!(page.next_cursor.is_none());
+        let prev_cursor = page.prev_cursor.expect("missing prev cursor");
+        let page = user_store.get_page(connection,request.cursor(&prev_cursor)).await.expect("previous page failed");
+        assert_eq!(page.items[0]["username"],names[1]);
+
+        // patch
+        let new_name = get_random_string(10);
+        let user_data = user_store.patch(connection,first_row.id,serde_json::json!({"username": new_name, "confirmed": true})).await.expect("unable to patch user");
+        let patched_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        assert_eq!(patched_row.username,new_name);
+        assert!(patched_row.confirmed);
+        assert_eq!(patched_row.created_at,first_row.created_at);
+        assert_eq!(patched_row.version,first_row.version + 1);
+        let result = user_store.patch(connection,first_row.id,serde_json::json!({"id": Uuid::nil()})).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+        let result = user_store.patch(connection,first_row.id,serde_json::json!({"username": null})).await;
+        assert!(matches!(result,Err(StoreError::InvalidInput{field,..}) if field == "username"));
+        let result = user_store.patch(connection,first_row.id,serde_json::json!({"email": second_user.get_email()})).await;
+        assert!(matches!(result,Err(StoreError::UniqueViolation{field,..}) if field == "email"));
+        let result = user_store.patch(connection,Uuid::nil(),serde_json::json!({"confirmed": true})).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        // passwords are stored hashed whichever way they are written
+        let user_data = user_store.patch(connection,first_row.id,serde_json::json!({"password": "patched secret"})).await.expect("unable to patch user");
+        let patched_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        assert_ne!(patched_row.password_hash,"patched secret");
+        assert_ne!(patched_row.password_hash,first_row.password_hash);
+        let result = user_store.patch(connection,first_row.id,serde_json::json!({"password_hash": "plain secret"})).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{column,..}) if column == "password_hash"));
+
+        // update
+        let new_user = get_sample_user();
+        let user_data = user_store.update(connection,second_row.id,serde_json::to_value(&new_user).unwrap()).await.expect("user update failed");
+        assert_eq!(user_data["username"],new_user.get_name());
+        assert_eq!(user_data["id"],serde_json::json!(second_row.id));
+        assert_ne!(user_data["password_hash"],new_user.get_password());
+        assert_ne!(user_data["password_hash"],serde_json::json!(second_row.password_hash));
+        assert_eq!(user_data["version"],serde_json::json!(second_row.version + 1));
+        // a row read back and written again keeps its hash
+        let updated_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        let round_trip:User = updated_row.clone().into();
+        let user_data = user_store.update(connection,second_row.id,serde_json::to_value(&round_trip).unwrap()).await.expect("user update failed");
+        assert_eq!(user_data["password_hash"],serde_json::json!(updated_row.password_hash));
+        let result = user_store.update(connection,Uuid::nil(),serde_json::to_value(&new_user).unwrap()).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        // delete
+        user_store.delete(connection,first_row.id).await.expect("delete by id failed");
+        user_store.delete(connection,second_row.id).await.expect("delete by id failed");
+        let result = user_store.delete(connection,first_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let user_data = user_store.get(connection,first_row.id).await.expect("unable to get user with id");
+        assert!(user_data.is_empty());
+    }
+
+    async fn token_store_conformance<C, S>(token_store:&S, connection:C)
+    where
+        C: Copy + Send,
+        S: StoreTrait<C> + Sync,
+    {
+        let token_string = get_random_string(10);
+        let token_json = serde_json::to_value(&Token::new(token_string.clone(),TokenType::RefreshToken)).unwrap();
+        let token_data = token_store.insert(connection,token_json).await.expect("insertion failed");
+        let token_row:TokenRow = serde_json::from_value(token_data).expect("json conversion error");
+        assert_eq!(token_row.token_string,TokenHasher::global().unwrap().hash(&token_string));
+        assert!(!token_row.blacklisted);
+        assert!(token_row.expires_at.is_none());
+
+        // an expiry can be given next to the token fields
+        let expires_at = NaiveDateTime::parse_from_str("2100-01-01 00:00:00","%Y-%m-%d %H:%M:%S").unwrap();
+        let mut token_json = serde_json::to_value(&Token::new(get_random_string(10),TokenType::AccessToken)).unwrap();
+        token_json["expires_at"] = serde_json::json!(expires_at);
+        let token_data = token_store.insert(connection,token_json).await.expect("insertion failed");
+        assert_eq!(token_data["expires_at"],serde_json::json!(expires_at));
+        let expiring_row:TokenRow = serde_json::from_value(token_data).expect("json conversion error");
+        // and cleared again with a null
+        let token_data = token_store.patch(connection,expiring_row.id,serde_json::json!({"expires_at": null})).await.expect("unable to patch token");
+        assert!(token_data["expires_at"].is_null());
+        token_store.delete(connection,expiring_row.id).await.expect("delete by id failed");
+
+        let json_slug = serde_json::json!({"token_string": token_string, "token_type": TokenType::RefreshToken});
+        let token_data = token_store.get_by_slug(connection,json_slug).await.expect("unable to get token with slug");
+        assert_eq!(token_data.len(),1);
+        let request = PageRequest::new(5).filter(Filter::eq("token_string",serde_json::json!(token_string)));
+        let page = token_store.get_page(connection,request).await.expect("page failed");
+        assert_eq!(page.items.len(),1);
+        assert!(page.next_cursor.is_none());
+
+        // token strings are unique
+        let result = token_store.insert(connection,serde_json::to_value(&Token::new(token_string.clone(),TokenType::AccessToken)).unwrap()).await;
+        assert!(matches!(result,Err(StoreError::UniqueViolation{field,..}) if field == "token_string"));
+        let other_data = token_store.insert(connection,serde_json::to_value(&Token::new(get_random_string(10),TokenType::AccessToken)).unwrap()).await.expect("insertion failed");
+        let other_row:TokenRow = serde_json::from_value(other_data).expect("json conversion error");
+        let result = token_store.update(connection,other_row.id,serde_json::to_value(&Token::new(token_string.clone(),TokenType::AccessToken)).unwrap()).await;
+        assert!(matches!(result,Err(StoreError::UniqueViolation{field,..}) if field == "token_string"));
+        token_store.delete(connection,other_row.id).await.expect("delete by id failed");
+
+        let result = token_store.patch(connection,token_row.id,serde_json::json!({"blacklisted": true})).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{column,reason}) if column == "blacklisted" && reason == "is written through revoke"));
+        let result = token_store.patch(connection,token_row.id,serde_json::json!({"created_at": "2020-01-01T00:00:00"})).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+
+        let new_token = Token::new(get_random_string(10),TokenType::AccessToken);
+        let token_data = token_store.update(connection,token_row.id,serde_json::to_value(&new_token).unwrap()).await.expect("update failed");
+        assert_eq!(token_data["token_string"],TokenHasher::global().unwrap().hash(new_token.get_token()));
+        let token_data = token_store.get_by_slug(connection,serde_json::json!({"token_string": new_token.get_token()})).await.expect("unable to get token with slug");
+        assert_eq!(token_data.len(),1);
+        // a stored hash written as a token is hashed like any other token
+        let hash = token_data[0]["token_string"].as_str().unwrap().to_string();
+        let hash_as_token = Token::new(hash.clone(),TokenType::AccessToken);
+        let token_data = token_store.update(connection,token_row.id,serde_json::to_value(&hash_as_token).unwrap()).await.expect("update failed");
+        assert_eq!(token_data["token_string"],TokenHasher::global().unwrap().hash(&hash));
+
+        token_store.delete(connection,token_row.id).await.expect("delete by id failed");
+        let result = token_store.delete(connection,token_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let result = token_store.update(connection,token_row.id,serde_json::to_value(&new_token).unwrap()).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+    }
+
+    // tokens of a user go when the user is deleted
+    async fn token_owner_conformance<C, U, T>(user_store:&U, token_store:&T, connection:C)
+    where
+        C: Copy + Send,
+        U: StoreTrait<C> + Sync,
+        T: StoreTrait<C> + Sync,
+    {
+        let user_data = user_store.insert(connection,serde_json::to_value(&get_sample_user()).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        let mut token_json = serde_json::to_value(&Token::new(get_random_string(10),TokenType::RefreshToken)).unwrap();
+        token_json["user_id"] = serde_json::json!(user_row.id);
+        let token_data = token_store.insert(connection,token_json.clone()).await.expect("insertion failed");
+        let token_row:TokenRow = serde_json::from_value(token_data).expect("json conversion error");
+        assert_eq!(token_row.user_id,Some(user_row.id));
+        let token_data = token_store.get_by_slug(connection,serde_json::json!({"user_id": user_row.id})).await.expect("unable to get token with slug");
+        assert_eq!(token_data.len(),1);
+
+        let mut token_json = serde_json::to_value(&Token::new(get_random_string(10),TokenType::RefreshToken)).unwrap();
+        token_json["user_id"] = serde_json::json!(Uuid::nil());
+        let result = token_store.insert(connection,token_json).await;
+        assert!(matches!(result,Err(StoreError::ForeignKeyViolation{..})));
+
+        user_store.delete(connection,user_row.id).await.expect("delete by id failed");
+        let token_data = token_store.get(connection,token_row.id).await.expect("unable to get token with id");
+        assert!(token_data.is_empty());
+    }
+
+    // deletes are soft, the include_deleted stores still see the rows and
+    // restore_user and restore_token run the backend's restore
+    async fn soft_delete_conformance<C, U, T, RU, FU, RT, FT>(user_store:&U, all_users:&U, token_store:&T, all_tokens:&T, connection:C, restore_user:RU, restore_token:RT)
+    where
+        C: Copy + Send,
+        U: StoreTrait<C> + Sync,
+        T: StoreTrait<C> + Sync,
+        RU: Fn(Uuid) -> FU,
+        FU: std::future::Future<Output = Result<UserRow,StoreError>>,
+        RT: Fn(Uuid) -> FT,
+        FT: std::future::Future<Output = Result<TokenRow,StoreError>>,
+    {
+        let user = get_sample_user();
+        let user_data = user_store.insert(connection,serde_json::to_value(&user).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        let mut token_json = serde_json::to_value(&Token::new(get_random_string(10),TokenType::RefreshToken)).unwrap();
+        token_json["user_id"] = serde_json::json!(user_row.id);
+        let token_data = token_store.insert(connection,token_json).await.expect("insertion failed");
+        let token_row:TokenRow = serde_json::from_value(token_data).expect("json conversion error");
+        let count = user_store.count(connection).await.expect("count failed");
+        let all_count = all_users.count(connection).await.expect("count failed");
+
+        user_store.delete(connection,user_row.id).await.expect("delete by id failed");
+        assert!(user_store.get(connection,user_row.id).await.expect("get failed").is_empty());
+        let slug = serde_json::json!({"username": user.get_name()});
+        assert!(user_store.get_by_slug(connection,slug.clone()).await.expect("get by slug failed").is_empty());
+        let page = user_store.get_page(connection,PageRequest::new(10).filter(Filter::eq("id",serde_json::json!(user_row.id)))).await.expect("page failed");
+        assert!(page.items.is_empty());
+        assert_eq!(user_store.count(connection).await.expect("count failed"),count - 1);
+        assert_eq!(all_users.count(connection).await.expect("count failed"),all_count);
+        let result = user_store.patch(connection,user_row.id,serde_json::json!({"confirmed": true})).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let result = user_store.delete(connection,user_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let deleted = all_users.get_by_slug(connection,slug).await.expect("get by slug failed");
+        assert!(deleted[0]["deleted_at"].is_string());
+
+        // the user's tokens are soft deleted along with it
+        assert!(token_store.get(connection,token_row.id).await.expect("get failed").is_empty());
+        let deleted = all_tokens.get(connection,token_row.id).await.expect("get failed");
+        assert!(deleted[0]["deleted_at"].is_string());
+        let result = token_store.delete(connection,token_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        // restore brings back the user but not its tokens, as a write of its own
+        let restored = restore_user(user_row.id).await.expect("restore failed");
+        assert!(restored.deleted_at.is_none());
+        assert_eq!(restored.version,user_row.version + 1);
+        assert!(restored.updated_at >= user_row.updated_at);
+        assert!(!user_store.get(connection,user_row.id).await.expect("get failed").is_empty());
+        assert!(token_store.get(connection,token_row.id).await.expect("get failed").is_empty());
+        let result = restore_user(user_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let restored = restore_token(token_row.id).await.expect("restore failed");
+        assert!(restored.deleted_at.is_none());
+        assert_eq!(restored.version,token_row.version + 1);
+        assert!(restored.updated_at >= token_row.updated_at);
+        assert!(!token_store.get(connection,token_row.id).await.expect("get failed").is_empty());
+        let result = restore_token(token_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn pg_conformance_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        user_store_conformance(&UserPGStore::default(),&db_connection).await;
+        token_store_conformance(&TokenPGStore::default(),&db_connection).await;
+        token_owner_conformance(&UserPGStore::default(),&TokenPGStore::default(),&db_connection).await;
+        let (user_store,token_store) = (UserPGStore::default(),TokenPGStore::default());
+        soft_delete_conformance(&user_store,&UserPGStore::default().include_deleted(),&token_store,&TokenPGStore::default().include_deleted(),&db_connection,
+            |id| user_store.restore(&db_connection,id),|id| token_store.restore(&db_connection,id)).await;
+    }
+
+    #[cfg(feature = "memory")]
+    #[tokio::test]
+    async fn memory_conformance_test() {
+        use stores::memory_store::{InMemoryTokenStore, InMemoryUserStore, MemoryDatabase};
+        set_test_secrets();
+        let database = MemoryDatabase::default();
+        user_store_conformance(&InMemoryUserStore::default(),&database).await;
+        token_store_conformance(&InMemoryTokenStore::default(),&database).await;
+        token_owner_conformance(&InMemoryUserStore::default(),&InMemoryTokenStore::default(),&database).await;
+        let (user_store,token_store) = (InMemoryUserStore::default(),InMemoryTokenStore::default());
+        soft_delete_conformance(&user_store,&InMemoryUserStore::default().include_deleted(),&token_store,&InMemoryTokenStore::default().include_deleted(),&database,
+            |id| user_store.restore(&database,id),|id| token_store.restore(&database,id)).await;
+
+        // hard_delete removes the user and its tokens
+        let user_data = user_store.insert(&database,serde_json::to_value(&get_sample_user()).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        let mut token_json = serde_json::to_value(&Token::new(get_random_string(10),TokenType::RefreshToken)).unwrap();
+        token_json["user_id"] = serde_json::json!(user_row.id);
+        let token_data = token_store.insert(&database,token_json).await.expect("insertion failed");
+        let token_row:TokenRow = serde_json::from_value(token_data).expect("json conversion error");
+        user_store.hard_delete(&database,user_row.id).await.expect("hard delete failed");
+        assert!(InMemoryTokenStore::default().include_deleted().get(&database,token_row.id).await.expect("get failed").is_empty());
+        let result = user_store.hard_delete(&database,user_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn sqlite_conformance_test() {
+        use sqlx::sqlite::SqlitePoolOptions;
+        use stores::{token_sqlite_store::TokenSqliteStore, user_sqlite_store::UserSqliteStore};
+        set_test_secrets();
+        // every connection to :memory: opens a database of its own
+        let db_connection = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("could not open sqlite database");
+        migrations::run_sqlite_migrations(&db_connection).await.expect("sqlite migration failed");
+        user_store_conformance(&UserSqliteStore::default(),&db_connection).await;
+        token_store_conformance(&TokenSqliteStore::default(),&db_connection).await;
+        token_owner_conformance(&UserSqliteStore::default(),&TokenSqliteStore::default(),&db_connection).await;
+        // restore takes a connection, the pool hands one out per call
+        let (users,tokens,pool) = (&UserSqliteStore::default(),&TokenSqliteStore::default(),&db_connection);
+        soft_delete_conformance(users,&UserSqliteStore::default().include_deleted(),tokens,&TokenSqliteStore::default().include_deleted(),pool,
+            move |id| async move { users.restore(&mut *pool.acquire().await?,id).await },
+            move |id| async move { tokens.restore(&mut *pool.acquire().await?,id).await }).await;
+
+        // enums are text columns guarded by check constraints
+        let result = sqlx::query("insert into users(id,username,email,password_hash,user_role) values ($1,'name','mail','hash','owner')")
+            .bind(Uuid::nil())
+            .execute(&db_connection)
+            .await
+            .map_err(StoreError::from);
+        assert!(matches!(result,Err(StoreError::InvalidInput{field,..}) if field == "user_role"));
+
+        // hard deletes remove the user and its tokens for good
+        let user_store = UserSqliteStore::default();
+        let token_store = TokenSqliteStore::default();
+        let mut conn = db_connection.acquire().await.expect("could not acquire connection");
+        let user_row = user_store.insert_row(&mut conn,get_sample_user()).await.expect("insertion failed");
+        let token_row = token_store.insert_for_user(&mut conn,Some(user_row.id),Token::new(get_random_string(10),TokenType::RefreshToken),None).await.expect("insertion failed");
+        user_store.delete(&mut *conn,user_row.id).await.expect("delete by id failed");
+        user_store.hard_delete(&mut conn,user_row.id).await.expect("hard delete failed");
+        let all_tokens = TokenSqliteStore::default().include_deleted();
+        assert!(all_tokens.get_row(&mut conn,token_row.id).await.expect("get failed").is_none());
+        let result = user_store.hard_delete(&mut conn,user_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        drop(conn);
+
+        migrations::revert_sqlite_migrations(&db_connection,0).await.expect("sqlite revert failed");
+    }
+
+    #[tokio::test]
+    async fn token_expiry_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let token_store = TokenPGStore::default();
+
+        let token_string = get_random_string(10);
+        let token_row = token_store.insert_with_ttl(&db_connection,Token::new(token_string.clone(),TokenType::AccessToken),chrono::Duration::minutes(5)).await.expect("insertion failed");
+        assert!(token_row.expires_at.expect("missing expiry") > Utc::now().naive_utc());
+        let valid_row = token_store.get_valid(&db_connection,&token_string).await.expect("valid token rejected");
+        assert_eq!(valid_row.id,token_row.id);
+
+        let expired_string = get_random_string(10);
+        let past = Utc::now().naive_utc() - chrono::Duration::minutes(1);
+        let expired_row = token_store.insert_expiring(&db_connection,Token::new(expired_string.clone(),TokenType::RefreshToken),Some(past)).await.expect("insertion failed");
+        let result = token_store.get_valid(&db_connection,&expired_string).await;
+        assert!(matches!(result,Err(StoreError::TokenExpired)));
+
+        let blacklisted_string = get_random_string(10);
+        let blacklisted_row = token_store.insert_with_ttl(&db_connection,Token::new(blacklisted_string.clone(),TokenType::RefreshToken),chrono::Duration::minutes(5)).await.expect("insertion failed");
+        token_store.revoke(&db_connection,&blacklisted_string,RevocationReason::Admin).await.expect("revoke failed");
+        let result = token_store.get_valid(&db_connection,&blacklisted_string).await;
+        assert!(matches!(result,Err(StoreError::TokenBlacklisted)));
+        let result = token_store.get_valid(&db_connection,&get_random_string(10)).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        // purging removes expired tokens only, in batches of at least one
+        let result = token_store.purge_expired(&db_connection,0).await;
+        assert!(matches!(result,Err(StoreError::InvalidInput{field,..}) if field == "batch_size"));
+        let purged = token_store.purge_expired(&db_connection,1).await.expect("purge failed");
+        assert!(purged >= 1);
+        let result = token_store.get_valid(&db_connection,&expired_string).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        token_store.get_valid(&db_connection,&token_string).await.expect("valid token purged");
+        let history = AuditPGStore.history(&db_connection,"tokens",expired_row.id).await.expect("history failed");
+        assert_eq!(history.last().expect("purge not recorded").operation,AuditOperation::Delete);
+
+        // background purge
+        let expired_string = get_random_string(10);
+        token_store.insert_expiring(&db_connection,Token::new(expired_string.clone(),TokenType::AccessToken),Some(past)).await.expect("insertion failed");
+        let purge_task = TokenPGStore::spawn_purge_task(db_connection.clone(),std::time::Duration::from_millis(50),100);
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        purge_task.abort();
+        let result = token_store.get_valid(&db_connection,&expired_string).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn user_tokens_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+        let token_store = TokenPGStore::default();
+
+        let user_data = user_store.insert(&db_connection,serde_json::to_value(&get_sample_user()).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        let past = Utc::now().naive_utc() - chrono::Duration::minutes(1);
+        let first_string = get_random_string(10);
+        let first = token_store.insert_for_user(&db_connection,Some(user_row.id),Token::new(first_string.clone(),TokenType::AccessToken),None).await.expect("insertion failed");
+        let second = token_store.insert_for_user(&db_connection,Some(user_row.id),Token::new(get_random_string(10),TokenType::RefreshToken),None).await.expect("insertion failed");
+        token_store.insert_for_user(&db_connection,Some(user_row.id),Token::new(get_random_string(10),TokenType::RefreshToken),Some(past)).await.expect("insertion failed");
+        token_store.insert_expiring(&db_connection,Token::new(get_random_string(10),TokenType::AccessToken),None).await.expect("insertion failed");
+
+        let tokens = token_store.list_for_user(&db_connection,user_row.id).await.expect("list failed");
+        assert_eq!(tokens.len(),3);
+        assert_eq!(tokens[0].id,first.id);
+        assert_eq!(token_store.count_active_for_user(&db_connection,user_row.id).await.expect("count failed"),2);
+
+        token_store.revoke(&db_connection,&first_string,RevocationReason::Logout).await.expect("revoke failed");
+        assert_eq!(token_store.count_active_for_user(&db_connection,user_row.id).await.expect("count failed"),1);
+        assert_eq!(token_store.blacklist_all_for_user(&db_connection,user_row.id,RevocationReason::PasswordChange).await.expect("blacklist failed"),2);
+        assert_eq!(token_store.count_active_for_user(&db_connection,user_row.id).await.expect("count failed"),0);
+        let result = token_store.get_valid(&db_connection,&second.token_string).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        assert_eq!(token_store.delete_all_for_user(&db_connection,user_row.id).await.expect("delete failed"),3);
+        assert!(token_store.list_for_user(&db_connection,user_row.id).await.expect("list failed").is_empty());
+
+        // user deletes take the tokens along, a failed delete keeps them
+        token_store.insert_for_user(&db_connection,Some(user_row.id),Token::new(get_random_string(10),TokenType::AccessToken),None).await.expect("insertion failed");
+        let mut conn = db_connection.acquire().await.expect("could not acquire connection");
+        let result = user_store.delete(&mut *conn,Uuid::nil()).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        user_store.delete(&mut *conn,user_row.id).await.expect("delete by id failed");
+        assert!(token_store.list_for_user(&db_connection,user_row.id).await.expect("list failed").is_empty());
+    }
+
+    #[tokio::test]
+    async fn token_rotation_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let token_store = TokenPGStore::default();
+
+        let user_data = UserPGStore::default().insert(&db_connection,serde_json::to_value(&get_sample_user()).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        let expires_at = Utc::now().naive_utc() + chrono::Duration::days(1);
+        let first_string = get_random_string(10);
+        let first = token_store.insert_for_user(&db_connection,Some(user_row.id),Token::new(first_string.clone(),TokenType::RefreshToken),Some(expires_at)).await.expect("insertion failed");
+
+        let second_string = get_random_string(10);
+        let second = token_store.rotate_refresh_token(&db_connection,&first_string,Token::new(second_string.clone(),TokenType::RefreshToken)).await.expect("rotation failed");
+        assert_eq!(second.family_id,Some(first.id));
+        assert_eq!(second.user_id,Some(user_row.id));
+        assert_eq!(second.expires_at,first.expires_at);
+        let first = token_store.get_row(&mut *db_connection.acquire().await.unwrap(),first.id).await.expect("get failed").expect("missing token");
+        assert!(first.blacklisted);
+        assert_eq!(first.replaced_by,Some(second.id));
+        assert_eq!(first.family_id,Some(first.id));
+
+        let third_string = get_random_string(10);
+        let third = token_store.rotate_refresh_token(&db_connection,&second_string,Token::new(third_string.clone(),TokenType::RefreshToken)).await.expect("rotation failed");
+        assert_eq!(third.family_id,Some(first.id));
+        token_store.get_valid(&db_connection,&third_string).await.expect("rotated token rejected");
+
+        // replaying a rotated token revokes the family
+        let result = token_store.rotate_refresh_token(&db_connection,&first_string,Token::new(get_random_string(10),TokenType::RefreshToken)).await;
+        assert!(matches!(result,Err(StoreError::TokenReused)));
+        assert_eq!(StoreError::TokenReused.http_status(),401);
+        let result = token_store.get_valid(&db_connection,&third_string).await;
+        assert!(matches!(result,Err(StoreError::TokenBlacklisted)));
+        let third = token_store.get_row(&mut *db_connection.acquire().await.unwrap(),third.id).await.expect("get failed").expect("missing token");
+        assert_eq!(third.revocation_reason,Some(RevocationReason::ReuseDetected));
+        let result = token_store.rotate_refresh_token(&db_connection,&third_string,Token::new(get_random_string(10),TokenType::RefreshToken)).await;
+        assert!(matches!(result,Err(StoreError::TokenBlacklisted)));
+        assert_eq!(token_store.count_active_for_user(&db_connection,user_row.id).await.expect("count failed"),0);
+
+        let access_string = get_random_string(10);
+        token_store.insert_expiring(&db_connection,Token::new(access_string.clone(),TokenType::AccessToken),None).await.expect("insertion failed");
+        let result = token_store.rotate_refresh_token(&db_connection,&access_string,Token::new(get_random_string(10),TokenType::RefreshToken)).await;
+        assert!(matches!(result,Err(StoreError::InvalidInput{field,..}) if field == "token_type"));
+        let result = token_store.rotate_refresh_token(&db_connection,&get_random_string(10),Token::new(get_random_string(10),TokenType::RefreshToken)).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        UserPGStore::default().delete(&db_connection,user_row.id).await.expect("delete by id failed");
+    }
+
+    #[tokio::test]
+    async fn token_revocation_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let token_store = TokenPGStore::default();
+
+        let token_string = get_random_string(10);
+        token_store.insert_expiring(&db_connection,Token::new(token_string.clone(),TokenType::AccessToken),None).await.expect("insertion failed");
+        assert!(!token_store.is_revoked(&db_connection,&token_string).await.expect("revocation check failed"));
+        let token_row = token_store.revoke(&db_connection,&token_string,RevocationReason::Logout).await.expect("revoke failed");
+        assert!(token_row.blacklisted);
+        assert_eq!(token_row.revocation_reason,Some(RevocationReason::Logout));
+        let revoked_at = token_row.revoked_at.expect("missing revocation time");
+        assert!(token_store.is_revoked(&db_connection,&token_string).await.expect("revocation check failed"));
+        // the first revocation is kept
+        let token_row = token_store.revoke(&db_connection,&token_string,RevocationReason::Admin).await.expect("revoke failed");
+        assert_eq!(token_row.revocation_reason,Some(RevocationReason::Logout));
+        assert_eq!(token_row.revoked_at,Some(revoked_at));
+        let result = token_store.revoke(&db_connection,&get_random_string(10),RevocationReason::Admin).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        assert!(!token_store.is_revoked(&db_connection,&get_random_string(10)).await.expect("revocation check failed"));
+
+        let token_strings = vec![get_random_string(10),get_random_string(10),get_random_string(10)];
+        for token_string in token_strings.iter() {
+            token_store.insert_expiring(&db_connection,Token::new(token_string.clone(),TokenType::RefreshToken),None).await.expect("insertion failed");
+        }
+        let mut to_revoke = token_strings.clone();
+        to_revoke.push(token_string.clone());
+        to_revoke.push(get_random_string(10));
+        assert_eq!(token_store.revoke_many(&db_connection,&to_revoke,RevocationReason::Admin).await.expect("revoke failed"),3);
+        for token_string in token_strings.iter() {
+            assert!(token_store.is_revoked(&db_connection,token_string).await.expect("revocation check failed"));
+        }
+        let json_slug = serde_json::json!({"revocation_reason": "admin", "token_string": {"$in": token_strings}});
+        let token_data = token_store.get_by_slug(&db_connection,json_slug).await.expect("unable to get token with slug");
+        assert_eq!(token_data.len(),3);
+        assert!(token_data.iter().all(|token| token["revoked_at"].is_string()));
+    }
+
+    #[tokio::test]
+    async fn token_hash_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let token_store = TokenPGStore::default();
+
+        let hasher = TokenHasher::new(b"secret");
+        assert_eq!(hasher.hash("token"),TokenHasher::new(b"secret").hash("token"));
+        assert_ne!(hasher.hash("token"),TokenHasher::new(b"other secret").hash("token"));
+
+        // the table never sees the presented token
+        let token_string = get_random_string(10);
+        let token_row = token_store.insert_row(&mut *db_connection.acquire().await.unwrap(),Token::new(token_string.clone(),TokenType::AccessToken)).await.expect("insertion failed");
+        let stored: (String,) = sqlx::query_as("select token_string from tokens where id = $1").bind(token_row.id).fetch_one(&db_connection).await.expect("select failed");
+        assert_ne!(stored.0,token_string);
+        assert_eq!(stored.0,TokenHasher::global().unwrap().hash(&token_string));
+        let valid_row = token_store.get_valid(&db_connection,&token_string).await.expect("valid token rejected");
+        assert_eq!(valid_row.id,token_row.id);
+        // the stored hash is not a token itself
+        let result = token_store.get_valid(&db_connection,&stored.0).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        // a row read back and written again keeps its token
+        let mut conn = db_connection.acquire().await.expect("could not acquire connection");
+        let read_back = token_store.get_row(&mut conn,token_row.id).await.expect("get failed").expect("missing token");
+        let updated = token_store.update_stored_row(&mut conn,token_row.id,read_back.clone().into()).await.expect("update failed");
+        assert_eq!(updated.token_string,stored.0);
+        // while a row written back as a presented token gets hashed again
+        let updated = token_store.update_row(&mut conn,token_row.id,read_back.clone().into()).await.expect("update failed");
+        assert_ne!(updated.token_string,stored.0);
+        token_store.update_stored_row(&mut conn,token_row.id,read_back.into()).await.expect("update failed");
+        let valid_row = token_store.get_valid(&db_connection,&token_string).await.expect("round tripped token rejected");
+        assert_eq!(valid_row.id,token_row.id);
+        let result = token_store.get_by_slug(&db_connection,serde_json::json!({"token_string": {"$like": "%"}})).await;
+        assert!(matches!(result,Err(StoreError::InvalidFilter(_))));
+
+        // rows written before hashing are rewritten in place
+        let plaintext = get_random_string(10);
+        let legacy_id: (Uuid,) = sqlx::query_as("insert into tokens(token_string,token_type,blacklisted) values ($1,'access_token',false) returning id")
+            .bind(&plaintext).fetch_one(&db_connection).await.expect("insertion failed");
+        let result = token_store.hash_plaintext_tokens(&db_connection,0).await;
+        assert!(matches!(result,Err(StoreError::InvalidInput{field,..}) if field == "batch_size"));
+        let hashed = token_store.hash_plaintext_tokens(&db_connection,1).await.expect("rehash failed");
+        assert!(hashed >= 1);
+        let legacy_row = token_store.get_valid(&db_connection,&plaintext).await.expect("migrated token rejected");
+        assert_eq!(legacy_row.id,legacy_id.0);
+        // the rewrite is recorded without the plaintext it replaced
+        let history = AuditPGStore.history(&db_connection,"tokens",legacy_id.0).await.expect("history failed");
+        assert_eq!(history.last().expect("rewrite not recorded").operation,AuditOperation::Update);
+        assert_eq!(history.last().unwrap().changes["token_string"],serde_json::json!({"before": REDACTED, "after": REDACTED}));
+        assert_eq!(token_store.hash_plaintext_tokens(&db_connection,100).await.expect("rehash failed"),0);
+
+        token_store.delete_by_token(&db_connection,token_string.clone()).await.expect("delete by token failed");
+        token_store.delete_by_token(&db_connection,plaintext.clone()).await.expect("delete by token failed");
+        let result = token_store.get_valid(&db_connection,&token_string).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn authenticate_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+
+        let user = get_sample_user();
+        let user_data = user_store.insert(&db_connection,serde_json::to_value(&user).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        let by_name = user_store.authenticate(&db_connection,user.get_name(),user.get_password()).await.expect("authentication failed");
+        assert_eq!(by_name.id,user_row.id);
+        assert_eq!(by_name.password_hash,user_row.password_hash);
+        let by_email = user_store.authenticate(&db_connection,user.get_email(),user.get_password()).await.expect("authentication failed");
+        assert_eq!(by_email.id,user_row.id);
+
+        // wrong passwords and unknown users fail the same way
+        let result = user_store.authenticate(&db_connection,user.get_name(),"wrong password").await;
+        assert!(matches!(result,Err(AuthError::InvalidCredentials)));
+        let result = user_store.authenticate(&db_connection,&get_random_string(10),user.get_password()).await;
+        assert!(matches!(result,Err(AuthError::InvalidCredentials)));
+        let error = result.unwrap_err();
+        assert_eq!(error.code(),"invalid_credentials");
+        assert_eq!(error.http_status(),401);
+
+        // hashes with other parameters or in another format are rehashed
+        let current = "$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA";
+        assert!(!needs_rehash("$argon2id$v=19$m=19456,t=2,p=1$b3RoZXI$b3RoZXI",current));
+        assert!(needs_rehash("$argon2id$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA",current));
+        assert!(needs_rehash("$argon2i$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA",current));
+        assert!(needs_rehash("plain digest",current));
+        assert!(!needs_rehash("plain digest","other digest"));
+
+        user_store.delete(&db_connection,user_row.id).await.expect("delete by id failed");
+    }
+
+    #[tokio::test]
+    async fn lockout_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let policy = LockoutPolicy{ threshold: 2, base_lockout: chrono::Duration::minutes(1), max_lockout: chrono::Duration::minutes(4) };
+        assert_eq!(policy.lockout_for(1),None);
+        assert_eq!(policy.lockout_for(2),Some(chrono::Duration::minutes(1)));
+        assert_eq!(policy.lockout_for(3),Some(chrono::Duration::minutes(2)));
+        assert_eq!(policy.lockout_for(4),Some(chrono::Duration::minutes(4)));
+        assert_eq!(policy.lockout_for(40),Some(chrono::Duration::minutes(4)));
+        let user_store = UserPGStore::default().with_lockout_policy(policy);
+
+        let user = get_sample_user();
+        let user_data = user_store.insert(&db_connection,serde_json::to_value(&user).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        assert_eq!(user_row.failed_login_count,0);
+        assert!(user_row.last_login_at.is_none());
+
+        let result = user_store.authenticate(&db_connection,user.get_name(),"wrong password").await;
+        assert!(matches!(result,Err(AuthError::InvalidCredentials)));
+        let row = user_store.get_row(&mut *db_connection.acquire().await.unwrap(),user_row.id).await.expect("get failed").expect("missing user");
+        assert_eq!(row.failed_login_count,1);
+        assert!(row.locked_until.is_none());
+        let result = user_store.authenticate(&db_connection,user.get_name(),"wrong password").await;
+        assert!(matches!(result,Err(AuthError::InvalidCredentials)));
+
+        // the right password is refused while locked
+        let result = user_store.authenticate(&db_connection,user.get_email(),user.get_password()).await;
+        let Err(error) = result else { panic!("locked account authenticated") };
+        assert!(matches!(error,AuthError::Locked{until} if until > Utc::now().naive_utc()));
+        assert_eq!(error.code(),"account_locked");
+        assert_eq!(error.http_status(),423);
+        let row = user_store.record_login_failure(&db_connection,user_row.id).await.expect("record failure failed");
+        assert_eq!(row.failed_login_count,3);
+        let lock = row.locked_until.expect("missing lock") - Utc::now().naive_utc();
+        assert!(lock > chrono::Duration::seconds(90) && lock <= chrono::Duration::minutes(2));
+
+        // once the lock ran out a successful login resets the count
+        sqlx::query("update users set locked_until = $1 where id = $2")
+            .bind(Utc::now().naive_utc() - chrono::Duration::seconds(1))
+            .bind(user_row.id)
+            .execute(&db_connection)
+            .await
+            .expect("unlock failed");
+        let row = user_store.authenticate(&db_connection,user.get_name(),user.get_password()).await.expect("authentication failed");
+        assert_eq!(row.failed_login_count,0);
+        assert!(row.locked_until.is_none());
+        assert!(row.last_login_at.is_some());
+        let result = user_store.record_login_success(&db_connection,Uuid::nil()).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let result = user_store.record_login_failure(&db_connection,Uuid::nil()).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        // concurrent failures are all counted and lock on the final count
+        let user_store = std::sync::Arc::new(user_store);
+        let mut failures = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let (user_store,db_connection) = (user_store.clone(),db_connection.clone());
+            failures.spawn(async move { user_store.record_login_failure(&db_connection,user_row.id).await });
+        }
+        while let Some(result) = failures.join_next().await {
+            result.expect("task failed").expect("record failure failed");
+        }
+        let row = user_store.get_row(&mut *db_connection.acquire().await.unwrap(),user_row.id).await.expect("get failed").expect("missing user");
+        assert_eq!(row.failed_login_count,8);
+        let lock = row.locked_until.expect("missing lock") - Utc::now().naive_utc();
+        assert!(lock > chrono::Duration::minutes(3) && lock <= chrono::Duration::minutes(4));
+        user_store.delete(&db_connection,user_row.id).await.expect("delete by id failed");
+    }
+
+    #[tokio::test]
+    async fn email_confirmation_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let policy = ConfirmationPolicy{ ttl: chrono::Duration::hours(1), resend_interval: chrono::Duration::hours(1) };
+        let user_store = UserPGStore::default().with_confirmation_policy(policy);
+        let token_store = TokenPGStore::default();
+
+        let user_data = user_store.insert(&db_connection,serde_json::to_value(&get_sample_user()).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        assert!(!user_row.confirmed);
+        let first_token = user_store.issue_email_confirmation(&db_connection,user_row.id).await.expect("issue failed");
+        // confirmation tokens are no sessions
+        let result = token_store.get_valid(&db_connection,&first_token).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        assert_eq!(token_store.count_active_for_user(&db_connection,user_row.id).await.expect("count failed"),0);
+
+        let result = user_store.resend_email_confirmation(&db_connection,user_row.id).await;
+        let Err(error) = result else { panic!("resend was not rate limited") };
+        assert!(matches!(error,StoreError::RateLimited{until} if until > Utc::now().naive_utc()));
+        assert_eq!(error.code(),"rate_limited");
+        assert_eq!(error.http_status(),429);
+
+        // a new token replaces the old one, which works only once
+        let second_token = user_store.issue_email_confirmation(&db_connection,user_row.id).await.expect("issue failed");
+        let result = user_store.confirm_email(&db_connection,&first_token).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let row = user_store.confirm_email(&db_connection,&second_token).await.expect("confirmation failed");
+        assert!(row.confirmed);
+        let result = user_store.confirm_email(&db_connection,&second_token).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let result = user_store.issue_email_confirmation(&db_connection,user_row.id).await;
+        assert!(matches!(result,Err(StoreError::InvalidInput{field,..}) if field == "confirmed"));
+        let result = user_store.issue_email_confirmation(&db_connection,Uuid::nil()).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        // expired tokens confirm nothing
+        let policy = ConfirmationPolicy{ ttl: chrono::Duration::seconds(-1), resend_interval: chrono::Duration::zero() };
+        let expiring_store = UserPGStore::default().with_confirmation_policy(policy);
+        let user_data = user_store.insert(&db_connection,serde_json::to_value(&get_sample_user()).unwrap()).await.expect("insertion failed");
+        let other_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        expiring_store.issue_email_confirmation(&db_connection,other_row.id).await.expect("issue failed");
+        let expired_token = expiring_store.resend_email_confirmation(&db_connection,other_row.id).await.expect("resend failed");
+        let result = expiring_store.confirm_email(&db_connection,&expired_token).await;
+        assert!(matches!(result,Err(StoreError::TokenExpired)));
+        let other_row = user_store.get_row(&mut *db_connection.acquire().await.unwrap(),other_row.id).await.expect("get failed").expect("missing user");
+        assert!(!other_row.confirmed);
+
+        user_store.delete(&db_connection,user_row.id).await.expect("delete by id failed");
+        user_store.delete(&db_connection,other_row.id).await.expect("delete by id failed");
+    }
+
+    #[tokio::test]
+    async fn upsert_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+        let token_store = TokenPGStore::default();
+        let mut conn = db_connection.acquire().await.expect("could not acquire connection");
+
+        let user = get_sample_user();
+        let username = user.get_name().to_string();
+        let row = user_store.upsert(&mut conn,user,"username",&["email","user_role"]).await.expect("upsert failed");
+        assert_eq!(row.version,1);
+        let email = format!("{}@gmail.com",get_random_string(10));
+        let updated = user_store.upsert(&mut conn,User::new(username.clone(),String::from("other"),email.clone(),UserRoles::Admin),"username",&["email","user_role"]).await.expect("upsert failed");
+        assert_eq!(updated.id,row.id);
+        assert_eq!(updated.email,email);
+        assert_eq!(updated.user_role,UserRoles::Admin);
+        assert_eq!(updated.password_hash,row.password_hash);
+        assert_eq!(updated.version,2);
+        let unchanged = user_store.upsert(&mut conn,get_sample_user_named(&username),"username",&[]).await.expect("upsert failed");
+        assert_eq!(unchanged.email,email);
+        assert_eq!(unchanged.version,2);
+        let history = AuditPGStore.history(&db_connection,"users",row.id).await.expect("history failed");
+        let operations = history.iter().map(|entry| entry.operation).collect::<Vec<AuditOperation>>();
+        assert_eq!(operations,vec![AuditOperation::Insert,AuditOperation::Update]);
+
+        // the email is taken by the upserted user, the username by nobody
+        let result = user_store.upsert(&mut conn,User::new(get_random_string(10),String::from("rillo"),email.clone(),UserRoles::Normal),"username",&["email"]).await;
+        assert!(matches!(result,Err(StoreError::UniqueViolation{field,..}) if field == "email"));
+        let result = user_store.upsert(&mut conn,get_sample_user(),"confirmed",&[]).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+        let result = user_store.upsert(&mut conn,get_sample_user(),"email",&["failed_login_count"]).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+        user_store.delete(&mut *conn,row.id).await.expect("delete by id failed");
+        let result = user_store.upsert(&mut conn,get_sample_user_named(&username),"username",&["confirmed"]).await;
+        assert!(matches!(result,Err(StoreError::UniqueViolation{field,..}) if field == "username"));
+        let deleted = UserPGStore::default().include_deleted().upsert(&mut conn,get_sample_user_named(&username),"username",&["confirmed"]).await.expect("upsert failed");
+        assert_eq!(deleted.id,row.id);
+        assert!(deleted.deleted_at.is_some());
+
+        let token_string = get_random_string(10);
+        let token = NewToken::from(Token::new(token_string.clone(),TokenType::AccessToken));
+        let token_row = token_store.upsert(&mut conn,token,"token_string",&["blacklisted"]).await.expect("upsert failed");
+        let token = NewToken::from(Token::new_full(Uuid::nil(),token_string.clone(),TokenType::AccessToken,true));
+        let updated = token_store.upsert(&mut conn,token,"token_string",&["blacklisted"]).await.expect("upsert failed");
+        assert_eq!(updated.id,token_row.id);
+        assert!(updated.blacklisted);
+        assert_eq!(updated.version,2);
+        // the stored hash does not reach the row it belongs to
+        let token = NewToken::from(Token::new(token_row.token_string.clone(),TokenType::AccessToken));
+        let other = token_store.upsert(&mut conn,token,"token_string",&["blacklisted"]).await.expect("upsert failed");
+        assert_ne!(other.id,token_row.id);
+        token_store.hard_delete(&db_connection,other.id).await.expect("hard delete failed");
+        let token = NewToken::from(Token::new(token_string,TokenType::AccessToken));
+        let result = token_store.upsert(&mut conn,token,"user_id",&[]).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+        token_store.hard_delete(&db_connection,token_row.id).await.expect("hard delete failed");
+        user_store.hard_delete(&db_connection,row.id).await.expect("hard delete failed");
+    }
+
+    #[tokio::test]
+    async fn bulk_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+        let token_store = TokenPGStore::default();
+        let mut conn = db_connection.acquire().await.expect("could not acquire connection");
+
+        let existing = user_store.insert_row(&mut conn,get_sample_user()).await.expect("insertion failed");
+        let first = get_sample_user();
+        let taken_username = User::new(existing.username.clone(),String::from("rillo"),format!("{}@gmail.com",get_random_string(10)),UserRoles::Normal);
+        let taken_email = User::new(get_random_string(10),String::from("rillo"),first.get_email().to_string(),UserRoles::Normal);
+        let admin = User::new(get_random_string(10),String::from("rillo"),format!("{}@gmail.com",get_random_string(10)),UserRoles::Admin);
+        let results = user_store.insert_many(&mut conn,vec![first,taken_username,taken_email,admin]).await.expect("bulk insert failed");
+        assert_eq!(results.len(),4);
+        let first = results[0].as_ref().expect("first user not inserted").clone();
+        assert!(matches!(&results[1],Err(StoreError::UniqueViolation{field,..}) if field == "username"));
+        assert!(matches!(&results[2],Err(StoreError::UniqueViolation{field,..}) if field == "email"));
+        let admin = results[3].as_ref().expect("admin not inserted").clone();
+        assert_eq!(admin.user_role,UserRoles::Admin);
+        assert!(user_store.authenticate(&db_connection,&admin.username,"rillo").await.is_ok());
+
+        let filter = Filter::Or(vec![Filter::eq("id",serde_json::json!(first.id)),Filter::eq("id",serde_json::json!(admin.id))]);
+        let rows = user_store.patch_many(&mut conn,&filter,serde_json::json!({"confirmed": true})).await.expect("bulk patch failed");
+        assert_eq!(rows.len(),2);
+        assert!(rows.iter().all(|row| row.confirmed && row.version == 2));
+        let history = AuditPGStore.history(&db_connection,"users",first.id).await.expect("history failed");
+        let operations = history.iter().map(|entry| entry.operation).collect::<Vec<AuditOperation>>();
+        assert_eq!(operations,vec![AuditOperation::Insert,AuditOperation::Patch]);
+        let rows = user_store.delete_many(&mut conn,&filter).await.expect("bulk delete failed");
+        assert_eq!(rows.len(),2);
+        assert!(user_store.get_row(&mut conn,first.id).await.expect("get failed").is_none());
+        assert!(user_store.delete_many(&mut conn,&filter).await.expect("bulk delete failed").is_empty());
+
+        let duplicate = get_random_string(10);
+        let tokens = vec![
+            NewToken{token: Token::new(duplicate.clone(),TokenType::AccessToken),user_id: Some(existing.id),expires_at: None},
+            NewToken{token: Token::new(get_random_string(10),TokenType::RefreshToken),user_id: Some(Uuid::nil()),expires_at: None},
+            NewToken{token: Token::new(get_random_string(10),TokenType::RefreshToken),user_id: Some(existing.id),expires_at: None},
+            NewToken{token: Token::new(duplicate.clone(),TokenType::AccessToken),user_id: Some(existing.id),expires_at: None},
+        ];
+        let results = token_store.insert_many(&mut conn,tokens).await.expect("bulk insert failed");
+        assert_eq!(results[0].as_ref().expect("first token not inserted").user_id,Some(existing.id));
+        assert!(matches!(&results[1],Err(StoreError::ForeignKeyViolation{..})));
+        assert_eq!(results[2].as_ref().expect("third token not inserted").token_type,TokenType::RefreshToken);
+        assert!(matches!(&results[3],Err(StoreError::UniqueViolation{field,..}) if field == "token_string"));
+        // a token already stored is skipped the same way
+        let tokens = vec![NewToken{token: Token::new(duplicate,TokenType::AccessToken),user_id: Some(existing.id),expires_at: None}];
+        let results = token_store.insert_many(&mut conn,tokens).await.expect("bulk insert failed");
+        assert!(matches!(&results[0],Err(StoreError::UniqueViolation{field,..}) if field == "token_string"));
+        let filter = Filter::eq("user_id",serde_json::json!(existing.id));
+        let expires_at = NaiveDateTime::parse_from_str("2100-01-01 00:00:00","%Y-%m-%d %H:%M:%S").unwrap();
+        let rows = token_store.patch_many(&mut conn,&filter,serde_json::json!({"expires_at": expires_at})).await.expect("bulk patch failed");
+        assert_eq!(rows.len(),2);
+        assert!(rows.iter().all(|row| row.expires_at == Some(expires_at)));
+        let result = token_store.patch_many(&mut conn,&filter,serde_json::json!({"blacklisted": true})).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+        let rows = token_store.delete_many(&mut conn,&filter).await.expect("bulk delete failed");
+        assert_eq!(rows.len(),2);
+        assert_eq!(token_store.count_active_for_user(&db_connection,existing.id).await.expect("count failed"),0);
+
+        for id in [existing.id,first.id,admin.id] {
+            user_store.hard_delete(&db_connection,id).await.expect("hard delete failed");
+        }
+    }
+
+    #[tokio::test]
+    async fn row_version_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+        let token_store = TokenPGStore::default();
+        let mut conn = db_connection.acquire().await.expect("could not acquire connection");
+
+        let user_row = user_store.insert_row(&mut conn,get_sample_user()).await.expect("insertion failed");
+        assert_eq!(user_row.version,1);
+        // two writers read version 1, the second one is refused
+        let first = user_store.patch_if_version(&mut conn,user_row.id,1,serde_json::json!({"user_role": "Admin"})).await.expect("patch failed");
+        assert_eq!(first.version,2);
+        let result = user_store.patch_if_version(&mut conn,user_row.id,1,serde_json::json!({"confirmed": true})).await;
+        let Err(error) = result else { panic!("outdated patch was written") };
+        assert!(matches!(error,StoreError::Conflict{..}));
+        assert_eq!(error.http_status(),409);
+        let result = user_store.update_if_version(&mut conn,user_row.id,1,get_sample_user()).await;
+        assert!(matches!(result,Err(StoreError::Conflict{..})));
+        let row = user_store.get_row(&mut conn,user_row.id).await.expect("get failed").expect("missing user");
+        assert_eq!(row.version,2);
+        assert!(!row.confirmed);
+        let row = user_store.update_if_version(&mut conn,user_row.id,2,get_sample_user()).await.expect("update failed");
+        assert_eq!(row.version,3);
+        // writes outside update and patch move the version as well
+        let row = user_store.set_password(&db_connection,user_row.id,&get_random_string(12)).await.expect("set password failed");
+        assert_eq!(row.version,4);
+        // login bookkeeping does not, a failed login leaves an admin's patch standing
+        let failed = user_store.record_login_failure(&db_connection,user_row.id).await.expect("record failure failed");
+        assert_eq!((failed.version,failed.updated_at),(row.version,row.updated_at));
+        let succeeded = user_store.record_login_success(&mut conn,user_row.id).await.expect("record success failed");
+        assert_eq!((succeeded.version,succeeded.updated_at),(row.version,row.updated_at));
+        let row = user_store.patch_if_version(&mut conn,user_row.id,4,serde_json::json!({"confirmed": true})).await.expect("patch failed");
+        assert_eq!(row.version,5);
+        let result = user_store.patch_if_version(&mut conn,Uuid::nil(),1,serde_json::json!({"confirmed": true})).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        let token_string = get_random_string(10);
+        let token_row = token_store.insert_row(&mut conn,Token::new(token_string.clone(),TokenType::AccessToken)).await.expect("insertion failed");
+        let token_row = token_store.patch_if_version(&mut conn,token_row.id,token_row.version,serde_json::json!({"expires_at": null})).await.expect("patch failed");
+        token_store.revoke(&db_connection,&token_string,RevocationReason::Admin).await.expect("revoke failed");
+        let result = token_store.update_if_version(&mut conn,token_row.id,token_row.version,Token::new(token_string,TokenType::AccessToken)).await;
+        assert!(matches!(result,Err(StoreError::Conflict{..})));
+        user_store.delete(&mut *conn,user_row.id).await.expect("delete by id failed");
+    }
+
+    #[tokio::test]
+    async fn audit_log_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let audit_store = AuditPGStore::default();
+        let actor_data = UserPGStore::default().insert(&db_connection,serde_json::to_value(&get_sample_user()).unwrap()).await.expect("insertion failed");
+        let actor_id = serde_json::from_value::<UserRow>(actor_data).expect("json conversion error").id;
+        let user_store = UserPGStore::default().acting_as(actor_id);
+        let token_store = TokenPGStore::default().acting_as(actor_id);
+
+        let user_data = user_store.insert(&db_connection,serde_json::to_value(&get_sample_user()).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        user_store.patch(&db_connection,user_row.id,serde_json::json!({"user_role": "Admin"})).await.expect("unable to patch user");
+        user_store.patch(&db_connection,user_row.id,serde_json::json!({"password": get_random_string(12)})).await.expect("unable to patch user");
+        user_store.update(&db_connection,user_row.id,serde_json::to_value(&get_sample_user()).unwrap()).await.expect("user update failed");
+        user_store.delete(&db_connection,user_row.id).await.expect("delete by id failed");
+        user_store.restore(&db_connection,user_row.id).await.expect("restore failed");
+        user_store.set_password(&db_connection,user_row.id,&get_random_string(12)).await.expect("unable to set password");
+
+        let history = audit_store.history(&db_connection,"users",user_row.id).await.expect("history failed");
+        let operations = history.iter().map(|entry| entry.operation).collect::<Vec<AuditOperation>>();
+        assert_eq!(operations,vec![AuditOperation::Insert,AuditOperation::Patch,AuditOperation::Patch,AuditOperation::Update,AuditOperation::Delete,AuditOperation::Restore,AuditOperation::Update]);
+        assert!(history.iter().all(|entry| entry.actor_id == Some(actor_id)));
+        assert_eq!(history[0].changes["username"],serde_json::json!({"before": null, "after": user_row.username}));
+        assert_eq!(history[1].changes["user_role"],serde_json::json!({"before": "Normal", "after": "Admin"}));
+        assert!(history[1].changes.get("email").is_none());
+        // password hashes are never written to the log, only that they changed
+        assert_eq!(history[0].changes["password_hash"]["after"],serde_json::json!(REDACTED));
+        assert_eq!(history[2].changes["password_hash"],serde_json::json!({"before": REDACTED, "after": REDACTED}));
+        assert!(!history.iter().any(|entry| entry.changes.to_string().contains(&user_row.password_hash)));
+        assert!(history[4].changes["deleted_at"]["after"].is_string());
+        assert!(history[5].changes["deleted_at"]["after"].is_null());
+        assert_eq!(history[6].changes["password_hash"],serde_json::json!({"before": REDACTED, "after": REDACTED}));
+
+        // failed writes leave no trace and rolled back writes take their record along
+        let token_string = get_random_string(10);
+        let token_row = token_store.insert_row(&mut *db_connection.acquire().await.unwrap(),Token::new(token_string.clone(),TokenType::AccessToken)).await.expect("insertion failed");
+        let result = token_store.patch(&db_connection,token_row.id,serde_json::json!({"token_type": "no_token"})).await;
+        assert!(result.is_err());
+        let token_id = token_row.id;
+        let result = Store::transaction(&db_connection,|tx| Box::pin(async move {
+            TokenPGStore::default().patch(&mut **tx,token_id,serde_json::json!({"expires_at": "2100-01-01T00:00:00"})).await?;
+            Err::<(),StoreError>(StoreError::NotFound)
+        })).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        token_store.patch(&db_connection,token_row.id,serde_json::json!({"expires_at": "2100-01-01T00:00:00"})).await.expect("unable to patch token");
+        let history = audit_store.history(&db_connection,"tokens",token_row.id).await.expect("history failed");
+        assert_eq!(history.len(),2);
+        assert_eq!(history[1].changes["expires_at"],serde_json::json!({"before": null, "after": "2100-01-01T00:00:00"}));
+        token_store.revoke(&db_connection,&token_string,RevocationReason::Logout).await.expect("revoke failed");
+        let history = audit_store.history(&db_connection,"tokens",token_row.id).await.expect("history failed");
+        assert_eq!(history[2].operation,AuditOperation::Revoke);
+        assert_eq!(history[2].changes["revocation_reason"],serde_json::json!({"before": null, "after": "logout"}));
+        assert_eq!(history[2].changes["blacklisted"],serde_json::json!({"before": false, "after": true}));
+
+        let latest = audit_store.history_by_actor(&db_connection,actor_id,1).await.expect("history failed");
+        assert_eq!(latest[0].id,history[2].id);
+        assert_eq!(audit_store.history_by_actor(&db_connection,actor_id,100).await.expect("history failed").len(),10);
+    }
+
+    #[tokio::test]
+    async fn soft_delete_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+        let all_users = UserPGStore::default().include_deleted();
+        let token_store = TokenPGStore::default();
+
+        let user = get_sample_user();
+        let user_data = user_store.insert(&db_connection,serde_json::to_value(&user).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        let session = get_random_string(10);
+        let token_row = token_store.insert_for_user(&db_connection,Some(user_row.id),Token::new(session.clone(),TokenType::RefreshToken),None).await.expect("insertion failed");
+        let count = user_store.count(&db_connection).await.expect("count failed");
+        let all_count = all_users.count(&db_connection).await.expect("count failed");
+
+        // deleted users are hidden from every read and take their sessions along
+        user_store.delete(&db_connection,user_row.id).await.expect("delete by id failed");
+        assert!(user_store.get(&db_connection,user_row.id).await.expect("get failed").is_empty());
+        assert!(matches!(user_store.get_by_username(&db_connection,user.get_name()).await,Err(StoreError::NotFound)));
+        let slug = serde_json::json!({"username": user.get_name()});
+        assert!(user_store.get_by_slug(&db_connection,slug.clone()).await.expect("get by slug failed").is_empty());
+        let page = user_store.get_page(&db_connection,PageRequest::new(10).filter(Filter::eq("id",serde_json::json!(user_row.id)))).await.expect("page failed");
+        assert!(page.items.is_empty());
+        assert_eq!(user_store.count(&db_connection).await.expect("count failed"),count - 1);
+        assert_eq!(all_users.count(&db_connection).await.expect("count failed"),all_count);
+        let result = user_store.patch(&db_connection,user_row.id,serde_json::json!({"confirmed": true})).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let result = user_store.authenticate(&db_connection,user.get_name(),user.get_password()).await;
+        assert!(matches!(result,Err(AuthError::InvalidCredentials)));
+        assert!(matches!(token_store.get_valid(&db_connection,&session).await,Err(StoreError::NotFound)));
+        assert!(token_store.is_revoked(&db_connection,&session).await.expect("revocation check failed"));
+        let deleted:Vec<UserRow> = serde_json::from_value(serde_json::Value::Array(all_users.get_by_slug(&db_connection,slug).await.expect("get by slug failed"))).expect("json conversion error");
+        assert!(deleted[0].deleted_at.is_some());
+        let result = user_store.delete(&db_connection,user_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        // restoring brings back the user but not its sessions
+        let restored = user_store.restore(&db_connection,user_row.id).await.expect("restore failed");
+        assert!(restored.deleted_at.is_none());
+        user_store.authenticate(&db_connection,user.get_name(),user.get_password()).await.expect("restored user rejected");
+        assert!(matches!(token_store.get_valid(&db_connection,&session).await,Err(StoreError::NotFound)));
+        let result = user_store.restore(&db_connection,user_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let token_row = token_store.restore(&db_connection,token_row.id).await.expect("restore failed");
+        assert!(token_row.deleted_at.is_none());
+        token_store.get_valid(&db_connection,&session).await.expect("restored token rejected");
+
+        // hard deletes remove soft deleted rows as well
+        token_store.delete(&db_connection,token_row.id).await.expect("delete by id failed");
+        let result = token_store.get_row(&mut *db_connection.acquire().await.unwrap(),token_row.id).await.expect("get failed");
+        assert!(result.is_none());
+        let all_tokens = TokenPGStore::default().include_deleted();
+        assert_eq!(all_tokens.list_for_user(&db_connection,user_row.id).await.expect("list failed").len(),1);
+        token_store.hard_delete(&db_connection,token_row.id).await.expect("hard delete failed");
+        assert!(all_tokens.list_for_user(&db_connection,user_row.id).await.expect("list failed").is_empty());
+        user_store.delete(&db_connection,user_row.id).await.expect("delete by id failed");
+        user_store.hard_delete(&db_connection,user_row.id).await.expect("hard delete failed");
+        assert!(all_users.get(&db_connection,user_row.id).await.expect("get failed").is_empty());
+        let result = user_store.hard_delete(&db_connection,user_row.id).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn password_reset_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let policy = PasswordResetPolicy{ ttl: chrono::Duration::minutes(30), resend_interval: chrono::Duration::hours(1) };
+        let user_store = UserPGStore::default().with_password_reset_policy(policy);
+        let token_store = TokenPGStore::default();
+
+        let user = get_sample_user();
+        let user_data = user_store.insert(&db_connection,serde_json::to_value(&user).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        let session = get_random_string(10);
+        token_store.insert_for_user(&db_connection,Some(user_row.id),Token::new(session.clone(),TokenType::RefreshToken),None).await.expect("insertion failed");
+
+        let reset_token = user_store.issue_password_reset(&db_connection,user.get_email()).await.expect("issue failed");
+        let result = token_store.get_valid(&db_connection,&reset_token).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+        let result = user_store.issue_password_reset(&db_connection,user.get_email()).await;
+        assert!(matches!(result,Err(StoreError::RateLimited{..})));
+        let result = user_store.issue_password_reset(&db_connection,"nobody@example.com").await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        let new_password = get_random_string(12);
+        let row = user_store.reset_password(&db_connection,&reset_token,&new_password).await.expect("reset failed");
+        assert_ne!(row.password_hash,user_row.password_hash);
+        assert_ne!(row.password_hash,new_password);
+        user_store.authenticate(&db_connection,user.get_name(),&new_password).await.expect("new password rejected");
+        let result = user_store.authenticate(&db_connection,user.get_name(),user.get_password()).await;
+        assert!(matches!(result,Err(AuthError::InvalidCredentials)));
+        // every session is revoked and the token works only once
+        assert!(token_store.is_revoked(&db_connection,&session).await.expect("revocation check failed"));
+        assert_eq!(token_store.count_active_for_user(&db_connection,user_row.id).await.expect("count failed"),0);
+        let result = user_store.reset_password(&db_connection,&reset_token,&get_random_string(12)).await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        // an expired token changes nothing
+        let policy = PasswordResetPolicy{ ttl: chrono::Duration::seconds(-1), resend_interval: chrono::Duration::zero() };
+        let expiring_store = UserPGStore::default().with_password_reset_policy(policy);
+        let expired_token = expiring_store.issue_password_reset(&db_connection,user.get_email()).await.expect("issue failed");
+        let result = expiring_store.reset_password(&db_connection,&expired_token,&get_random_string(12)).await;
+        assert!(matches!(result,Err(StoreError::TokenExpired)));
+        user_store.authenticate(&db_connection,user.get_name(),&new_password).await.expect("password changed by expired token");
+
+        user_store.delete(&db_connection,user_row.id).await.expect("delete by id failed");
+    }
+
+    #[tokio::test]
+    async fn password_hashing_test() {
+        let db_url:String = String::from("postgres://postgres@localhost/test_db");
+        let db_connection:Pool<Postgres>  = get_connection(&db_url).await.expect("could not acquire connection");
+        let user_store = UserPGStore::default();
+
+        let user = get_sample_user();
+        let user_data = user_store.insert(&db_connection,serde_json::to_value(&user).unwrap()).await.expect("insertion failed");
+        let user_row:UserRow = serde_json::from_value(user_data).expect("json conversion error");
+        let mut plaintexts = vec![user.get_password().to_string()];
+
+        let new_password = get_random_string(12);
+        let row = user_store.set_password(&db_connection,user_row.id,&new_password).await.expect("set password failed");
+        assert_ne!(row.password_hash,user_row.password_hash);
+        user_store.authenticate(&db_connection,user.get_name(),&new_password).await.expect("new password rejected");
+        let result = user_store.authenticate(&db_connection,user.get_name(),user.get_password()).await;
+        assert!(matches!(result,Err(AuthError::InvalidCredentials)));
+        plaintexts.push(new_password);
+        let result = user_store.set_password(&db_connection,Uuid::nil(),"secret").await;
+        assert!(matches!(result,Err(StoreError::NotFound)));
+
+        let patched_password = get_random_string(12);
+        user_store.patch(&db_connection,user_row.id,serde_json::json!({"password": patched_password})).await.expect("unable to patch user");
+        user_store.authenticate(&db_connection,user.get_email(),&patched_password).await.expect("patched password rejected");
+        plaintexts.push(patched_password);
+
+        let updated = User::new(user.get_name().to_string(),get_random_string(12),user.get_email().to_string(),UserRoles::Normal);
+        user_store.update(&db_connection,user_row.id,serde_json::to_value(&updated).unwrap()).await.expect("update failed");
+        user_store.authenticate(&db_connection,user.get_name(),updated.get_password()).await.expect("updated password rejected");
+        plaintexts.push(updated.get_password().to_string());
+
+        // a user read back and written again keeps its password
+        let mut conn = db_connection.acquire().await.expect("could not acquire connection");
+        let read_back = user_store.get_row(&mut conn,user_row.id).await.expect("get failed").expect("missing user");
+        let row = user_store.update_row(&mut conn,user_row.id,read_back.clone().into()).await.expect("update failed");
+        assert_eq!(row.password_hash,read_back.password_hash);
+        user_store.authenticate(&db_connection,user.get_name(),updated.get_password()).await.expect("password lost on round trip");
+
+        let result = user_store.patch(&db_connection,user_row.id,serde_json::json!({"password_hash": "plain secret"})).await;
+        assert!(matches!(result,Err(StoreError::InvalidColumn{..})));
+        let result = user_store.patch(&db_connection,user_row.id,serde_json::json!({"password": 42})).await;
+        assert!(matches!(result,Err(StoreError::InvalidInput{field,..}) if field == "password"));
+
+        // none of the passwords reached the table as written
+        plaintexts.push(String::from("plain secret"));
+        let stored: (i64,) = sqlx::query_as("select count(*) from users where password_hash = any($1)")
+            .bind(&plaintexts)
+            .fetch_one(&db_connection)
+            .await
+            .expect("select failed");
+        assert_eq!(stored.0,0);
+        user_store.delete(&db_connection,user_row.id).await.expect("delete by id failed");
+    }
+
     #[tokio::test]
     async fn user_pg_test() {
 
@@ -190,7 +1674,7 @@ mod tests {
         let mtoken = token_data.last().unwrap().to_owned();
         let mtoken_row:TokenRow = serde_json::from_value(mtoken).expect("json conversion error");
         let returned_data:Token=mtoken_row.into();
-        assert_eq!(returned_data.get_token(),second_token_string);
+        assert_eq!(returned_data.get_token(),TokenHasher::global().unwrap().hash(&second_token_string));
 
 
         //selection
@@ -201,7 +1685,7 @@ mod tests {
             let token_row:TokenRow = serde_json::from_value(token_row.to_owned()).expect("Json conversion error");
             let mtoken:Token = token_row.into();
             println!("mtoken: {:?} second_token: {:?}",mtoken,second_new_token);
-            assert_eq!(mtoken.get_token(),second_new_token.get_token());
+            assert_eq!(mtoken.get_token(),TokenHasher::global().unwrap().hash(second_new_token.get_token()));
             assert_eq!(mtoken.get_type(),returned_data.get_type());
             m_id = mtoken.get_id();
         }
@@ -235,7 +1719,7 @@ mod tests {
         let returned_token =  retuned_token.first().unwrap();
         let returned_token:TokenRow = serde_json::from_value(returned_token.to_owned()).expect("json conversion error");
         let returned_token:Token = returned_token.into();
-        assert_eq!(dummy_token,returned_token.get_token());
+        assert_eq!(TokenHasher::global().unwrap().hash(&dummy_token),returned_token.get_token());
 
 
 
@@ -252,7 +1736,7 @@ mod tests {
 
         let token_row:TokenRow = serde_json::from_value(token_data.to_owned()).expect("json conversion error");
         let json_val = json_patch.get("token_string").and_then(serde_json::Value::as_str).unwrap();
-        assert_eq!(token_row.token_string.trim_matches('"').to_string(),json_val.to_owned());
+        assert_eq!(token_row.token_string,TokenHasher::global().unwrap().hash(json_val));
 
 
         //delete
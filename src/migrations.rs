@@ -0,0 +1,80 @@
+use crate::stores::store::StoreError;
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::{Pool, Postgres};
+#[cfg(feature = "sqlite")]
+use sqlx::Sqlite;
+
+// Versioned schema files in ./migrations, embedded at compile time.
+// Every migration ships with an `.up.sql` and a `.down.sql` file, applied
+// versions are recorded by sqlx in the `_sqlx_migrations` table.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+// Schema of the SQLite stores, versioned separately in ./migrations_sqlite.
+#[cfg(feature = "sqlite")]
+pub static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations_sqlite");
+
+/// Applies every pending migration, bringing a fresh database up to the
+/// schema expected by `UserPGStore` and `TokenPGStore`.
+pub async fn run_migrations(connection: &Pool<Postgres>) -> Result<(), StoreError> {
+    MIGRATOR
+        .run(connection)
+        .await
+        .map_err(StoreError::MigrationError)?;
+    log::info!("database migrated to version {}", latest_version());
+    Ok(())
+}
+
+/// Reverts applied migrations, newest first, until `target` is the latest
+/// applied version. A target of `0` reverts everything.
+pub async fn revert_migrations(connection: &Pool<Postgres>, target: i64) -> Result<(), StoreError> {
+    MIGRATOR
+        .undo(connection, target)
+        .await
+        .map_err(StoreError::MigrationError)?;
+    log::info!("database reverted to version {}", target);
+    Ok(())
+}
+
+/// Latest version recorded in the database, `None` when nothing was applied yet.
+pub async fn current_version(connection: &Pool<Postgres>) -> Result<Option<i64>, StoreError> {
+    let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+    conn.ensure_migrations_table()
+        .await
+        .map_err(StoreError::MigrationError)?;
+    let applied = conn
+        .list_applied_migrations()
+        .await
+        .map_err(StoreError::MigrationError)?;
+    Ok(applied.iter().map(|migration| migration.version).max())
+}
+
+/// Latest version embedded in this build of the library.
+pub fn latest_version() -> i64 {
+    MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Applies every pending SQLite migration, bringing a fresh database up to the
+/// schema expected by `UserSqliteStore` and `TokenSqliteStore`.
+#[cfg(feature = "sqlite")]
+pub async fn run_sqlite_migrations(connection: &Pool<Sqlite>) -> Result<(), StoreError> {
+    SQLITE_MIGRATOR
+        .run(connection)
+        .await
+        .map_err(StoreError::MigrationError)?;
+    Ok(())
+}
+
+/// Reverts applied SQLite migrations, newest first, until `target` is the latest
+/// applied version.
+#[cfg(feature = "sqlite")]
+pub async fn revert_sqlite_migrations(connection: &Pool<Sqlite>, target: i64) -> Result<(), StoreError> {
+    SQLITE_MIGRATOR
+        .undo(connection, target)
+        .await
+        .map_err(StoreError::MigrationError)?;
+    Ok(())
+}
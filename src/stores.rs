@@ -1,3 +1,15 @@
+pub mod audit;
+pub mod auth;
+pub mod columns;
+pub mod cursor;
+pub mod filter;
+#[cfg(feature = "memory")]
+pub mod memory_store;
 pub mod store;
+pub mod token_hash;
 pub mod user_store;
-pub mod token_store;
\ No newline at end of file
+pub mod token_store;
+#[cfg(feature = "sqlite")]
+pub mod user_sqlite_store;
+#[cfg(feature = "sqlite")]
+pub mod token_sqlite_store;
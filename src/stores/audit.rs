@@ -0,0 +1,258 @@
+use crate::stores::store::{StoreError, TransactionFuture};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, PgConnection, Postgres};
+use std::sync::Arc;
+use uuid::Uuid;
+
+// Columns whose values never make it into the audit log, a change to them
+// is still recorded. token_string is among them as rows written before
+// tokens were hashed hold the plaintext token.
+pub const REDACTED_COLUMNS: &[&str] = &["password_hash", "token_string"];
+pub const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[sqlx(type_name = "audit_operation", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    Insert,
+    Update,
+    Patch,
+    Delete,
+    // a token blacklisted, by revocation, rotation or a password change
+    Revoke,
+    // a soft deleted row brought back
+    Restore,
+}
+
+impl sqlx::postgres::PgHasArrayType for AuditOperation {
+    fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+        sqlx::postgres::PgTypeInfo::with_name("_audit_operation")
+    }
+}
+
+// A store write as handed to an AuditSink.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    // None for writes nobody was acting as, see acting_as on the stores
+    pub actor_id: Option<Uuid>,
+    // table of the written row
+    pub entity: String,
+    pub entity_id: Uuid,
+    pub operation: AuditOperation,
+    // see diff
+    pub changes: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize, sqlx::FromRow, Serialize, Clone)]
+pub struct AuditRow {
+    pub id: Uuid,
+    pub actor_id: Option<Uuid>,
+    pub entity: String,
+    pub entity_id: Uuid,
+    pub operation: AuditOperation,
+    pub changes: serde_json::Value,
+    pub created_at: NaiveDateTime,
+}
+
+// Receives every write of the Postgres stores, revocations and restores
+// included, on the connection the write runs on, inside its transaction, so
+// the write and its record are committed or rolled back together. Failing
+// fails the write. Login bookkeeping such as failed login counts is not
+// recorded.
+pub trait AuditSink: std::fmt::Debug + Send + Sync {
+    fn record<'c>(&'c self, connection: &'c mut PgConnection, entry: AuditEntry) -> TransactionFuture<'c, ()>;
+
+    // Records the entries of a bulk write, one by one unless the sink can
+    // take them at once.
+    fn record_many<'c>(&'c self, connection: &'c mut PgConnection, entries: Vec<AuditEntry>) -> TransactionFuture<'c, ()> {
+        Box::pin(async move {
+            for entry in entries {
+                self.record(&mut *connection, entry).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+// Maps every top level field that differs between the two serialized rows
+// to {"before": .., "after": ..}, a missing row counts as all nulls. Values
+// of REDACTED_COLUMNS are replaced by REDACTED.
+pub fn diff(before: Option<&serde_json::Value>, after: Option<&serde_json::Value>) -> serde_json::Value {
+    let empty = serde_json::Map::new();
+    let before = before.and_then(|row| row.as_object()).unwrap_or(&empty);
+    let after = after.and_then(|row| row.as_object()).unwrap_or(&empty);
+    let redact = |key: &str, value: Option<&serde_json::Value>| match value {
+        None | Some(serde_json::Value::Null) => serde_json::Value::Null,
+        Some(_) if REDACTED_COLUMNS.contains(&key) => serde_json::json!(REDACTED),
+        Some(value) => value.clone(),
+    };
+    let mut changes = serde_json::Map::new();
+    for key in before.keys().chain(after.keys().filter(|key| !before.contains_key(*key))) {
+        let (old, new) = (before.get(key), after.get(key));
+        if old.unwrap_or(&serde_json::Value::Null) == new.unwrap_or(&serde_json::Value::Null) {
+            continue;
+        }
+        changes.insert(
+            key.clone(),
+            serde_json::json!({"before": redact(key, old), "after": redact(key, new)}),
+        );
+    }
+    serde_json::Value::Object(changes)
+}
+
+// Keeps the history in the audit_log table, the sink the stores use unless
+// told otherwise, and reads it back.
+#[derive(Debug, Default)]
+pub struct AuditPGStore;
+
+impl AuditSink for AuditPGStore {
+    fn record<'c>(&'c self, connection: &'c mut PgConnection, entry: AuditEntry) -> TransactionFuture<'c, ()> {
+        Box::pin(async move {
+            sqlx::query!(
+                // language=PostgreSQL
+                r#"
+                    insert into audit_log(actor_id, entity, entity_id, operation, changes)
+                    values ($1, $2, $3, $4, $5)"#,
+                entry.actor_id,
+                entry.entity,
+                entry.entity_id,
+                entry.operation as AuditOperation,
+                entry.changes
+            )
+            .execute(connection)
+            .await
+            .map_err(StoreError::from)?;
+            Ok(())
+        })
+    }
+
+    fn record_many<'c>(&'c self, connection: &'c mut PgConnection, entries: Vec<AuditEntry>) -> TransactionFuture<'c, ()> {
+        Box::pin(async move {
+            if entries.is_empty() {
+                return Ok(());
+            }
+            let actor_ids: Vec<Option<Uuid>> = entries.iter().map(|entry| entry.actor_id).collect();
+            let entities: Vec<String> = entries.iter().map(|entry| entry.entity.clone()).collect();
+            let entity_ids: Vec<Uuid> = entries.iter().map(|entry| entry.entity_id).collect();
+            let operations: Vec<AuditOperation> = entries.iter().map(|entry| entry.operation).collect();
+            let changes: Vec<serde_json::Value> = entries.into_iter().map(|entry| entry.changes).collect();
+            sqlx::query(
+                // language=PostgreSQL
+                r#"
+                    insert into audit_log(actor_id, entity, entity_id, operation, changes)
+                    select actor_id, entity, entity_id, operation, changes
+                    from unnest($1::uuid[], $2::varchar[], $3::uuid[], $4::audit_operation[], $5::jsonb[])
+                        with ordinality as entry(actor_id, entity, entity_id, operation, changes, position)
+                    order by position"#,
+            )
+            .bind(actor_ids)
+            .bind(entities)
+            .bind(entity_ids)
+            .bind(operations)
+            .bind(changes)
+            .execute(connection)
+            .await
+            .map_err(StoreError::from)?;
+            Ok(())
+        })
+    }
+}
+
+impl AuditPGStore {
+    // Every recorded write of one row, oldest first.
+    pub async fn history<'e, E>(&self, connection: E, entity: &str, entity_id: Uuid) -> Result<Vec<AuditRow>, StoreError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let rows = sqlx::query_as!(
+            AuditRow,
+            r#"SELECT id, actor_id, entity, entity_id, operation AS "operation!: AuditOperation", changes, created_at FROM audit_log WHERE entity = $1 and entity_id = $2 order by created_at asc, id asc"#,
+            entity,
+            entity_id
+        )
+        .fetch_all(connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    // The latest writes made by an actor, newest first.
+    pub async fn history_by_actor<'e, E>(&self, connection: E, actor_id: Uuid, limit: i64) -> Result<Vec<AuditRow>, StoreError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let rows = sqlx::query_as!(
+            AuditRow,
+            r#"SELECT id, actor_id, entity, entity_id, operation AS "operation!: AuditOperation", changes, created_at FROM audit_log WHERE actor_id = $1 order by created_at desc, id desc limit $2"#,
+            actor_id,
+            limit
+        )
+        .fetch_all(connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+}
+
+// The sink and actor a store records its writes with.
+#[derive(Debug, Clone)]
+pub(crate) struct Auditor {
+    pub(crate) sink: Arc<dyn AuditSink>,
+    pub(crate) actor_id: Option<Uuid>,
+}
+
+impl Default for Auditor {
+    fn default() -> Self {
+        Auditor {
+            sink: Arc::new(AuditPGStore),
+            actor_id: None,
+        }
+    }
+}
+
+impl Auditor {
+    pub(crate) async fn record<T: Serialize>(
+        &self,
+        connection: &mut PgConnection,
+        entity: &str,
+        entity_id: Uuid,
+        operation: AuditOperation,
+        before: Option<&T>,
+        after: Option<&T>,
+    ) -> Result<(), StoreError> {
+        let before = before.map(serde_json::to_value).transpose().map_err(StoreError::JsonError)?;
+        let after = after.map(serde_json::to_value).transpose().map_err(StoreError::JsonError)?;
+        let entry = AuditEntry {
+            actor_id: self.actor_id,
+            entity: entity.to_string(),
+            entity_id,
+            operation,
+            changes: diff(before.as_ref(), after.as_ref()),
+        };
+        self.sink.record(connection, entry).await
+    }
+
+    // record for the rows of a bulk write, given as (id, before, after).
+    pub(crate) async fn record_many<T: Serialize>(
+        &self,
+        connection: &mut PgConnection,
+        entity: &str,
+        operation: AuditOperation,
+        rows: Vec<(Uuid, Option<&T>, Option<&T>)>,
+    ) -> Result<(), StoreError> {
+        let mut entries = Vec::with_capacity(rows.len());
+        for (entity_id, before, after) in rows {
+            let before = before.map(serde_json::to_value).transpose().map_err(StoreError::JsonError)?;
+            let after = after.map(serde_json::to_value).transpose().map_err(StoreError::JsonError)?;
+            entries.push(AuditEntry {
+                actor_id: self.actor_id,
+                entity: entity.to_string(),
+                entity_id,
+                operation,
+                changes: diff(before.as_ref(), after.as_ref()),
+            });
+        }
+        self.sink.record_many(connection, entries).await
+    }
+}
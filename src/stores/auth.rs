@@ -0,0 +1,248 @@
+use crate::stores::store::StoreError;
+use chrono::{Duration, NaiveDateTime};
+use crypto_lib::crypto::crypto::CryptoOp;
+use tokio::sync::OnceCell;
+
+static DECOY_HASH: OnceCell<String> = OnceCell::const_new();
+
+#[derive(Debug)]
+pub enum AuthError {
+    // unknown user or wrong password, deliberately not told apart
+    InvalidCredentials,
+    // too many failed logins, no password is checked before until
+    Locked {
+        until: NaiveDateTime,
+    },
+    StoreError(StoreError),
+}
+
+impl AuthError {
+    // Stable machine readable code, safe to hand out to API clients.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::Locked { .. } => "account_locked",
+            AuthError::StoreError(e) => e.code(),
+        }
+    }
+
+    // HTTP status a web layer should answer with.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            AuthError::InvalidCredentials => 401,
+            AuthError::Locked { .. } => 423,
+            AuthError::StoreError(e) => e.http_status(),
+        }
+    }
+}
+
+impl From<StoreError> for AuthError {
+    fn from(error: StoreError) -> Self {
+        AuthError::StoreError(error)
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthError::InvalidCredentials => write!(f, "Invalid Credentials"),
+            AuthError::Locked { until } => write!(f, "Account Locked until {}", until),
+            AuthError::StoreError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AuthError::StoreError(e) => Some(e),
+            AuthError::InvalidCredentials | AuthError::Locked { .. } => None,
+        }
+    }
+}
+
+// Locks an account once threshold logins in a row failed, for base_lockout
+// and twice as long with every further failure, up to max_lockout.
+#[derive(Debug, Clone)]
+pub struct LockoutPolicy {
+    pub threshold: i32,
+    pub base_lockout: Duration,
+    pub max_lockout: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            threshold: 5,
+            base_lockout: Duration::minutes(1),
+            max_lockout: Duration::hours(1),
+        }
+    }
+}
+
+impl LockoutPolicy {
+    // How long to lock an account after failed_login_count failures in a row,
+    // None while it is below the threshold.
+    pub fn lockout_for(&self, failed_login_count: i32) -> Option<Duration> {
+        if failed_login_count < self.threshold {
+            return None;
+        }
+        let mut lockout = self.base_lockout;
+        for _ in self.threshold..failed_login_count {
+            if lockout >= self.max_lockout {
+                break;
+            }
+            lockout = lockout * 2;
+        }
+        Some(lockout.min(self.max_lockout))
+    }
+}
+
+// Lifetime of email confirmation tokens and how long a user has to wait
+// before another one is sent.
+#[derive(Debug, Clone)]
+pub struct ConfirmationPolicy {
+    pub ttl: Duration,
+    pub resend_interval: Duration,
+}
+
+impl Default for ConfirmationPolicy {
+    fn default() -> Self {
+        ConfirmationPolicy {
+            ttl: Duration::hours(24),
+            resend_interval: Duration::minutes(1),
+        }
+    }
+}
+
+// Lifetime of password reset tokens and how long to wait before another one
+// is sent for the same account.
+#[derive(Debug, Clone)]
+pub struct PasswordResetPolicy {
+    pub ttl: Duration,
+    pub resend_interval: Duration,
+}
+
+impl Default for PasswordResetPolicy {
+    fn default() -> Self {
+        PasswordResetPolicy {
+            ttl: Duration::minutes(30),
+            resend_interval: Duration::minutes(1),
+        }
+    }
+}
+
+pub async fn hash_password(password: &str) -> Result<String, StoreError> {
+    CryptoOp::default()
+        .generate_hash(password.to_string())
+        .await
+        .map_err(|e| StoreError::other(&e))
+}
+
+// Password hash an update writes. The password of a User read back from a
+// row is the stored hash, which is kept as it is; anything else is a new
+// plaintext password and hashed.
+pub async fn update_password_hash(password: &str, stored: Option<&str>) -> Result<String, StoreError> {
+    match stored {
+        Some(stored) if stored == password => Ok(stored.to_string()),
+        _ => hash_password(password).await,
+    }
+}
+
+// Patches carry a plaintext password, which is hashed into password_hash.
+// Writing password_hash directly is refused so no plaintext can land there.
+pub async fn hash_password_patch(mut patch: serde_json::Value) -> Result<serde_json::Value, StoreError> {
+    let Some(map) = patch.as_object_mut() else {
+        return Ok(patch);
+    };
+    if map.contains_key("password_hash") {
+        return Err(StoreError::InvalidColumn {
+            column: String::from("password_hash"),
+            reason: "is written through password",
+        });
+    }
+    if let Some(password) = map.remove("password") {
+        let password = password.as_str().ok_or_else(|| StoreError::InvalidInput {
+            field: String::from("password"),
+            reason: String::from("password must be a string"),
+            source: None,
+        })?;
+        map.insert(String::from("password_hash"), serde_json::json!(hash_password(password).await?));
+    }
+    Ok(patch)
+}
+
+// Hash of a random password made with the current crypto-lib parameters.
+// Unknown users are verified against it so they cost as much as known ones,
+// and stored hashes with other parameters are due for a rehash.
+pub async fn decoy_hash() -> Result<&'static str, StoreError> {
+    let hash = DECOY_HASH
+        .get_or_try_init(|| async {
+            let charset = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+            hash_password(&random_string::generate(32, charset)).await
+        })
+        .await?;
+    Ok(hash)
+}
+
+// Whether stored was hashed with other parameters than current. Both are
+// PHC strings ($algorithm$version$params$salt$hash), everything before the
+// salt has to match; a stored hash in another format is always outdated.
+pub fn needs_rehash(stored: &str, current: &str) -> bool {
+    fn parameters(hash: &str) -> Option<&str> {
+        hash.rsplitn(3, '$').nth(2).filter(|parameters| !parameters.is_empty())
+    }
+    match (parameters(stored), parameters(current)) {
+        (Some(stored), Some(current)) => stored != current,
+        (None, Some(_)) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURRENT: &str = "$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ$aGFzaGhhc2g";
+
+    #[test]
+    fn needs_rehash_compares_parameters() {
+        assert!(!needs_rehash("$argon2id$v=19$m=19456,t=2,p=1$b3RoZXJzYWx0$b3RoZXJoYXNo", CURRENT));
+        assert!(needs_rehash("$argon2id$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$aGFzaGhhc2g", CURRENT));
+        assert!(needs_rehash("$argon2i$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ$aGFzaGhhc2g", CURRENT));
+    }
+
+    #[test]
+    fn needs_rehash_on_other_formats() {
+        assert!(needs_rehash("plaintext", CURRENT));
+        assert!(needs_rehash("", CURRENT));
+        assert!(needs_rehash("$salt$hash", CURRENT));
+        // without a PHC string to compare with nothing is rehashed
+        assert!(!needs_rehash("plaintext", "other plaintext"));
+        assert!(!needs_rehash(CURRENT, "plaintext"));
+    }
+
+    #[test]
+    fn lockout_doubles_from_the_threshold() {
+        let policy = LockoutPolicy::default();
+        assert_eq!(policy.lockout_for(0), None);
+        assert_eq!(policy.lockout_for(policy.threshold - 1), None);
+        assert_eq!(policy.lockout_for(policy.threshold), Some(Duration::minutes(1)));
+        assert_eq!(policy.lockout_for(policy.threshold + 1), Some(Duration::minutes(2)));
+        assert_eq!(policy.lockout_for(policy.threshold + 5), Some(Duration::minutes(32)));
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        let policy = LockoutPolicy::default();
+        assert_eq!(policy.lockout_for(policy.threshold + 6), Some(policy.max_lockout));
+        assert_eq!(policy.lockout_for(i32::MAX), Some(policy.max_lockout));
+        let policy = LockoutPolicy {
+            threshold: 1,
+            base_lockout: Duration::days(365),
+            max_lockout: Duration::days(30),
+        };
+        assert_eq!(policy.lockout_for(1), Some(Duration::days(30)));
+        assert_eq!(policy.lockout_for(i32::MAX), Some(Duration::days(30)));
+    }
+}
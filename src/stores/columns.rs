@@ -0,0 +1,292 @@
+use crate::stores::store::StoreError;
+use crate::stores::token_hash::TokenHasher;
+use crate::stores::token_store::{RevocationReason, TokenPurpose};
+use chrono::NaiveDateTime;
+use sqlx::{database::HasArguments, query::Query, Database, Encode, Postgres, Type};
+#[cfg(feature = "sqlite")]
+use sqlx::Sqlite;
+use token_lib::token::token::TokenType;
+use user_lib::user::user::UserRoles;
+use uuid::Uuid;
+
+// Postgres types of the columns the PG stores know how to bind from JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlType {
+    Uuid,
+    Text,
+    Bool,
+    BigInt,
+    Timestamp,
+    UserRole,
+    TokenType,
+    RevocationReason,
+    TokenPurpose,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ColumnDef {
+    pub name: &'static str,
+    pub sql_type: SqlType,
+    // may appear in get_by_slug conditions
+    pub filterable: bool,
+    // may be written by patch
+    pub patchable: bool,
+    // why patch refuses the column when it is not patchable
+    pub read_only_reason: &'static str,
+    // holds a keyed hash of the written value, see stored_value
+    pub hashed: bool,
+    // may hold NULL
+    pub nullable: bool,
+}
+
+impl ColumnDef {
+    pub const fn new(name: &'static str, sql_type: SqlType, filterable: bool, patchable: bool) -> Self {
+        ColumnDef {
+            name,
+            sql_type,
+            filterable,
+            patchable,
+            read_only_reason: "column is read-only",
+            hashed: false,
+            nullable: false,
+        }
+    }
+
+    pub const fn hashed(self) -> Self {
+        ColumnDef { hashed: true, ..self }
+    }
+
+    pub const fn nullable(self) -> Self {
+        ColumnDef { nullable: true, ..self }
+    }
+
+    // Refuses patches of a column that has a dedicated write, reason names it.
+    pub const fn written_through(self, reason: &'static str) -> Self {
+        ColumnDef {
+            patchable: false,
+            read_only_reason: reason,
+            ..self
+        }
+    }
+
+    // The value as it is kept in the column, hashed columns store the keyed
+    // hash of the text written to them, see TokenHasher.
+    pub fn stored_value(&self, value: &serde_json::Value) -> Result<serde_json::Value, StoreError> {
+        match value {
+            serde_json::Value::String(text) if self.hashed => {
+                Ok(serde_json::Value::String(TokenHasher::global()?.hash(text)))
+            }
+            _ => Ok(value.clone()),
+        }
+    }
+
+    pub fn quoted(&self) -> String {
+        quote_identifier(self.name)
+    }
+}
+
+// Whitelist of the columns of one table. Every identifier that ends up in a
+// dynamically built query has to be resolved through it first.
+#[derive(Debug)]
+pub struct ColumnRegistry {
+    table: &'static str,
+    columns: &'static [ColumnDef],
+}
+
+impl ColumnRegistry {
+    pub const fn new(table: &'static str, columns: &'static [ColumnDef]) -> Self {
+        ColumnRegistry { table, columns }
+    }
+
+    pub fn table(&self) -> String {
+        quote_identifier(self.table)
+    }
+
+    // The table name unquoted, as recorded in the audit log.
+    pub fn name(&self) -> &'static str {
+        self.table
+    }
+
+    pub fn columns(&self) -> &'static [ColumnDef] {
+        self.columns
+    }
+
+    pub fn column(&self, name: &str) -> Result<&'static ColumnDef, StoreError> {
+        self.columns
+            .iter()
+            .find(|column| column.name == name)
+            .ok_or_else(|| StoreError::InvalidColumn {
+                column: name.to_string(),
+                reason: "unknown column",
+            })
+    }
+
+    pub fn filterable(&self, name: &str) -> Result<&'static ColumnDef, StoreError> {
+        let column = self.column(name)?;
+        if !column.filterable {
+            return Err(StoreError::InvalidColumn {
+                column: name.to_string(),
+                reason: "column can not be filtered on",
+            });
+        }
+        Ok(column)
+    }
+
+    pub fn patchable(&self, name: &str) -> Result<&'static ColumnDef, StoreError> {
+        let column = self.column(name)?;
+        if !column.patchable {
+            return Err(StoreError::InvalidColumn {
+                column: name.to_string(),
+                reason: column.read_only_reason,
+            });
+        }
+        Ok(column)
+    }
+}
+
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// SQL flavour a dynamically built query is rendered for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    Postgres,
+    Sqlite,
+}
+
+// Placeholder values of a dynamically built query, bound in push order.
+#[derive(Debug, Default)]
+pub struct QueryParams {
+    dialect: Dialect,
+    values: Vec<(SqlType, serde_json::Value)>,
+}
+
+impl QueryParams {
+    pub fn new(dialect: Dialect) -> Self {
+        QueryParams {
+            dialect,
+            values: Vec::new(),
+        }
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    // Returns the placeholder to put into the query text.
+    pub fn push(&mut self, sql_type: SqlType, value: serde_json::Value) -> String {
+        self.values.push((sql_type, value));
+        format!("${}", self.values.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn bind<'a, DB: JsonBind<'a>>(
+        &self,
+        custom_query: Query<'a, DB, <DB as HasArguments<'a>>::Arguments>,
+    ) -> Result<Query<'a, DB, <DB as HasArguments<'a>>::Arguments>, StoreError> {
+        let mut custom_query = custom_query;
+        for (sql_type, value) in &self.values {
+            custom_query = DB::bind_json(custom_query, *sql_type, value)?;
+        }
+        Ok(custom_query)
+    }
+}
+
+// Databases able to bind a value of every SqlType, see bind_json_value.
+pub trait JsonBind<'a>: Database {
+    fn bind_json(
+        custom_query: Query<'a, Self, <Self as HasArguments<'a>>::Arguments>,
+        sql_type: SqlType,
+        value: &serde_json::Value,
+    ) -> Result<Query<'a, Self, <Self as HasArguments<'a>>::Arguments>, StoreError>;
+}
+
+impl<'a> JsonBind<'a> for Postgres {
+    fn bind_json(
+        custom_query: Query<'a, Self, <Self as HasArguments<'a>>::Arguments>,
+        sql_type: SqlType,
+        value: &serde_json::Value,
+    ) -> Result<Query<'a, Self, <Self as HasArguments<'a>>::Arguments>, StoreError> {
+        bind_json_value(custom_query, sql_type, value)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl<'a> JsonBind<'a> for Sqlite {
+    fn bind_json(
+        custom_query: Query<'a, Self, <Self as HasArguments<'a>>::Arguments>,
+        sql_type: SqlType,
+        value: &serde_json::Value,
+    ) -> Result<Query<'a, Self, <Self as HasArguments<'a>>::Arguments>, StoreError> {
+        bind_json_value(custom_query, sql_type, value)
+    }
+}
+
+// Binds a JSON value as the Rust type matching the column, JSON null binds SQL NULL.
+pub fn bind_json_value<'a, DB>(
+    custom_query: Query<'a, DB, <DB as HasArguments<'a>>::Arguments>,
+    sql_type: SqlType,
+    value: &serde_json::Value,
+) -> Result<Query<'a, DB, <DB as HasArguments<'a>>::Arguments>, StoreError>
+where
+    DB: Database,
+    Option<Uuid>: Encode<'a, DB> + Type<DB>,
+    Option<String>: Encode<'a, DB> + Type<DB>,
+    Option<bool>: Encode<'a, DB> + Type<DB>,
+    Option<i64>: Encode<'a, DB> + Type<DB>,
+    Option<NaiveDateTime>: Encode<'a, DB> + Type<DB>,
+    Option<UserRoles>: Encode<'a, DB> + Type<DB>,
+    Option<TokenType>: Encode<'a, DB> + Type<DB>,
+    Option<RevocationReason>: Encode<'a, DB> + Type<DB>,
+    Option<TokenPurpose>: Encode<'a, DB> + Type<DB>,
+{
+    let value = value.clone();
+    let custom_query = match sql_type {
+        SqlType::Uuid => {
+            let muid: Option<Uuid> = serde_json::from_value(value).map_err(StoreError::JsonError)?;
+            custom_query.bind(muid)
+        }
+        SqlType::Text => {
+            let text: Option<String> = serde_json::from_value(value).map_err(StoreError::JsonError)?;
+            custom_query.bind(text)
+        }
+        SqlType::Bool => {
+            let flag: Option<bool> = serde_json::from_value(value).map_err(StoreError::JsonError)?;
+            custom_query.bind(flag)
+        }
+        SqlType::BigInt => {
+            let number: Option<i64> = serde_json::from_value(value).map_err(StoreError::JsonError)?;
+            custom_query.bind(number)
+        }
+        SqlType::Timestamp => {
+            let time: Option<NaiveDateTime> = serde_json::from_value(value).map_err(StoreError::JsonError)?;
+            custom_query.bind(time)
+        }
+        SqlType::UserRole => {
+            let role: Option<UserRoles> = serde_json::from_value(value).map_err(StoreError::JsonError)?;
+            custom_query.bind(role)
+        }
+        SqlType::TokenType => {
+            let token_type: Option<TokenType> = serde_json::from_value(value).map_err(StoreError::JsonError)?;
+            custom_query.bind(token_type)
+        }
+        SqlType::RevocationReason => {
+            let reason: Option<RevocationReason> = serde_json::from_value(value).map_err(StoreError::JsonError)?;
+            custom_query.bind(reason)
+        }
+        SqlType::TokenPurpose => {
+            let purpose: Option<TokenPurpose> = serde_json::from_value(value).map_err(StoreError::JsonError)?;
+            custom_query.bind(purpose)
+        }
+    };
+    Ok(custom_query)
+}
@@ -0,0 +1,361 @@
+use crate::stores::columns::{ColumnRegistry, QueryParams, SqlType};
+use crate::stores::filter::{compare_rows, sort_rows, Filter, SortDirection, SortKey};
+use std::cmp::Ordering;
+use crate::stores::store::StoreError;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+type HmacSha256 = Hmac<Sha256>;
+
+static CURSOR_CODEC: OnceLock<CursorCodec> = OnceLock::new();
+
+// Largest page a PageRequest may ask for.
+pub const MAX_PAGE_LIMIT: i64 = 1000;
+
+// Signs and verifies the opaque cursor strings handed out by get_page, so a
+// client can not forge a position by editing the cursor.
+pub struct CursorCodec {
+    secret: Vec<u8>,
+}
+
+impl CursorCodec {
+    pub fn new(secret: &[u8]) -> Self {
+        CursorCodec {
+            secret: secret.to_vec(),
+        }
+    }
+
+    // Shared codec keyed by STORE_CURSOR_SECRET. Without it pages fail with
+    // Configuration, a random key would void every cursor on restart and
+    // between instances.
+    pub fn global() -> Result<&'static CursorCodec, StoreError> {
+        if let Some(codec) = CURSOR_CODEC.get() {
+            return Ok(codec);
+        }
+        let secret = dotenvy::var("STORE_CURSOR_SECRET")
+            .map_err(|_| StoreError::Configuration(String::from("STORE_CURSOR_SECRET is not set")))?;
+        Ok(CURSOR_CODEC.get_or_init(|| CursorCodec::new(secret.as_bytes())))
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("HMAC can take a key of any size")
+    }
+
+    fn encode(&self, payload: &CursorPayload) -> Result<String, StoreError> {
+        let payload = serde_json::to_vec(payload).map_err(StoreError::JsonError)?;
+        let mut mac = self.mac();
+        mac.update(&payload);
+        let signature = mac.finalize().into_bytes();
+        Ok(format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(&payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        ))
+    }
+
+    fn decode(&self, cursor: &str) -> Result<CursorPayload, StoreError> {
+        let (payload, signature) = cursor.split_once('.').ok_or(StoreError::InvalidCursor)?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| StoreError::InvalidCursor)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| StoreError::InvalidCursor)?;
+        let mut mac = self.mac();
+        mac.update(&payload);
+        mac.verify_slice(&signature)
+            .map_err(|_| StoreError::InvalidCursor)?;
+        serde_json::from_slice(&payload).map_err(|_| StoreError::InvalidCursor)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CursorPayload {
+    // sort keys the cursor was issued for, "-" prefixed when descending
+    keys: Vec<String>,
+    // sort key values of the row the cursor points at
+    values: Vec<serde_json::Value>,
+    // true to page towards the start of the listing
+    before: bool,
+    // digest of the filter the cursor was issued for
+    filter: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub prev_cursor: Option<String>,
+}
+
+// Keyset pagination request. Rows are ordered by the sort keys, `id` is
+// appended as a tie breaker when missing, so sort keys should be non null
+// columns.
+#[derive(Debug, Clone)]
+pub struct PageRequest {
+    pub filter: Filter,
+    pub sort: Vec<SortKey>,
+    pub limit: i64,
+    pub cursor: Option<String>,
+}
+
+impl PageRequest {
+    // First page ordered by creation time.
+    pub fn new(limit: i64) -> Self {
+        PageRequest {
+            filter: Filter::default(),
+            sort: vec![SortKey::asc("created_at"), SortKey::asc("id")],
+            limit,
+            cursor: None,
+        }
+    }
+
+    pub fn sort_by(mut self, sort: Vec<SortKey>) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // Continue from a next_cursor or prev_cursor of a previous page.
+    pub fn cursor(mut self, cursor: &str) -> Self {
+        self.cursor = Some(cursor.to_string());
+        self
+    }
+
+    // The decoded cursor, rejected when it was issued for another ordering
+    // or filter.
+    fn position(&self, keys: &[SortKey]) -> Result<Option<CursorPayload>, StoreError> {
+        let cursor = match &self.cursor {
+            Some(cursor) => cursor,
+            None => return Ok(None),
+        };
+        let payload = CursorCodec::global()?.decode(cursor)?;
+        let key_strings = keys.iter().map(|key| key.to_key()).collect::<Vec<String>>();
+        if payload.keys != key_strings || payload.values.len() != keys.len() || payload.filter != self.filter_digest()? {
+            return Err(StoreError::InvalidCursor);
+        }
+        Ok(Some(payload))
+    }
+
+    fn filter_digest(&self) -> Result<String, StoreError> {
+        let filter = serde_json::to_vec(&self.filter).map_err(StoreError::JsonError)?;
+        Ok(URL_SAFE_NO_PAD.encode(Sha256::digest(&filter)))
+    }
+
+    fn check_limit(&self) -> Result<(), StoreError> {
+        if self.limit < 1 {
+            return Err(StoreError::InvalidFilter(String::from("page limit must be positive")));
+        }
+        if self.limit > MAX_PAGE_LIMIT {
+            return Err(StoreError::InvalidFilter(format!("page limit must be at most {}", MAX_PAGE_LIMIT)));
+        }
+        Ok(())
+    }
+
+    fn sort_keys(&self) -> Vec<SortKey> {
+        let mut keys = self.sort.clone();
+        if !keys.iter().any(|key| key.column == "id") {
+            keys.push(SortKey::asc("id"));
+        }
+        keys
+    }
+
+    // Everything following `SELECT ... FROM table`, together with whether the
+    // rows come back in reverse order (when paging backwards).
+    pub fn to_sql(&self, columns: &ColumnRegistry, params: &mut QueryParams) -> Result<(String, bool), StoreError> {
+        self.check_limit()?;
+        let keys = self.sort_keys();
+        let key_columns = keys
+            .iter()
+            .map(|key| columns.filterable(&key.column))
+            .collect::<Result<Vec<_>, StoreError>>()?;
+        let mut custom_query = format!("WHERE {}", self.filter.to_sql(columns, params)?);
+
+        let mut backwards = false;
+        if let Some(payload) = self.position(&keys)? {
+            backwards = payload.before;
+            // (k1 > v1) OR (k1 = v1 AND k2 > v2) OR ...
+            let mut alternatives = Vec::new();
+            for (i, key) in keys.iter().enumerate() {
+                let mut parts = Vec::new();
+                for (column, value) in key_columns.iter().zip(&payload.values).take(i) {
+                    let placeholder = params.push(column.sql_type, value.clone());
+                    parts.push(format!("{} = {}", column.quoted(), placeholder));
+                }
+                let ascending = key.direction == SortDirection::Asc;
+                let operator = if ascending != backwards { ">" } else { "<" };
+                let placeholder = params.push(key_columns[i].sql_type, payload.values[i].clone());
+                parts.push(format!("{} {} {}", key_columns[i].quoted(), operator, placeholder));
+                alternatives.push(format!("({})", parts.join(" AND ")));
+            }
+            custom_query.push_str(&format!(" AND ({})", alternatives.join(" OR ")));
+        }
+
+        let order = keys
+            .iter()
+            .zip(key_columns.iter())
+            .map(|(key, column)| {
+                let ascending = key.direction == SortDirection::Asc;
+                format!("{} {}", column.quoted(), if ascending != backwards { "ASC" } else { "DESC" })
+            })
+            .collect::<Vec<String>>();
+        custom_query.push_str(&format!(" ORDER BY {}", order.join(", ")));
+        // one extra row tells whether there is another page
+        let limit = params.push(SqlType::BigInt, serde_json::json!(self.limit + 1));
+        custom_query.push_str(&format!(" LIMIT {}", limit));
+        Ok((custom_query, backwards))
+    }
+
+    // Pages through rows held in memory, the counterpart of to_sql and to_page.
+    pub fn apply<T: Serialize>(&self, columns: &ColumnRegistry, rows: Vec<T>) -> Result<Page<T>, StoreError> {
+        self.check_limit()?;
+        let keys = self.sort_keys();
+        let position = self.position(&keys)?;
+        let backwards = position.as_ref().map(|payload| payload.before).unwrap_or(false);
+        let position = position.map(|payload| {
+            let row = keys
+                .iter()
+                .map(|key| key.column.clone())
+                .zip(payload.values)
+                .collect::<serde_json::Map<String, serde_json::Value>>();
+            serde_json::Value::Object(row)
+        });
+        let mut selected = Vec::new();
+        for row in rows {
+            let json_row = serde_json::to_value(&row).map_err(StoreError::JsonError)?;
+            if !self.filter.matches(columns, &json_row)? {
+                continue;
+            }
+            if let Some(position) = &position {
+                let ordering = compare_rows(columns, &keys, &json_row, position)?;
+                let wanted = if backwards { Ordering::Less } else { Ordering::Greater };
+                if ordering != wanted {
+                    continue;
+                }
+            }
+            selected.push((json_row, row));
+        }
+        sort_rows(columns, &keys, &mut selected)?;
+        if backwards {
+            selected.reverse();
+        }
+        let rows = selected
+            .into_iter()
+            .take(self.limit as usize + 1)
+            .map(|(_, row)| row)
+            .collect();
+        self.to_page(rows, backwards)
+    }
+
+    // Builds the page out of the rows fetched with the query from to_sql.
+    pub fn to_page<T: Serialize>(&self, rows: Vec<T>, backwards: bool) -> Result<Page<T>, StoreError> {
+        let mut items = rows;
+        let has_more = items.len() as i64 > self.limit;
+        items.truncate(self.limit as usize);
+        if backwards {
+            items.reverse();
+        }
+        let (next_cursor, prev_cursor) = match (items.first(), items.last()) {
+            (Some(first), Some(last)) => {
+                let more_after = if backwards { true } else { has_more };
+                let more_before = if backwards { has_more } else { self.cursor.is_some() };
+                let next_cursor = if more_after { Some(self.cursor_for(last, false)?) } else { None };
+                let prev_cursor = if more_before { Some(self.cursor_for(first, true)?) } else { None };
+                (next_cursor, prev_cursor)
+            }
+            _ => (None, None),
+        };
+        Ok(Page {
+            items,
+            next_cursor,
+            prev_cursor,
+        })
+    }
+
+    fn cursor_for<T: Serialize>(&self, row: &T, before: bool) -> Result<String, StoreError> {
+        let row = serde_json::to_value(row).map_err(StoreError::JsonError)?;
+        let keys = self.sort_keys();
+        let values = keys
+            .iter()
+            .map(|key| row.get(&key.column).cloned().unwrap_or(serde_json::Value::Null))
+            .collect();
+        CursorCodec::global()?.encode(&CursorPayload {
+            keys: keys.iter().map(|key| key.to_key()).collect(),
+            values,
+            before,
+            filter: self.filter_digest()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(filter: String) -> CursorPayload {
+        CursorPayload {
+            keys: vec![String::from("created_at"), String::from("id")],
+            values: vec![
+                serde_json::json!("2024-01-01T00:00:00"),
+                serde_json::json!("00000000-0000-0000-0000-000000000000"),
+            ],
+            before: false,
+            filter,
+        }
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let codec = CursorCodec::new(b"secret");
+        let cursor = codec.encode(&payload(String::from("digest"))).unwrap();
+        let decoded = codec.decode(&cursor).unwrap();
+        assert_eq!(decoded.keys, vec!["created_at", "id"]);
+        assert_eq!(decoded.values[0], serde_json::json!("2024-01-01T00:00:00"));
+        assert!(!decoded.before);
+        assert_eq!(decoded.filter, "digest");
+    }
+
+    #[test]
+    fn tampered_cursor_is_rejected() {
+        let codec = CursorCodec::new(b"secret");
+        let cursor = codec.encode(&payload(String::from("digest"))).unwrap();
+        let (_, signature) = cursor.split_once('.').unwrap();
+        let mut forged = payload(String::from("digest"));
+        forged.before = true;
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap()), signature);
+        assert!(matches!(codec.decode(&forged), Err(StoreError::InvalidCursor)));
+        assert!(matches!(CursorCodec::new(b"other secret").decode(&cursor), Err(StoreError::InvalidCursor)));
+        assert!(matches!(codec.decode("no signature"), Err(StoreError::InvalidCursor)));
+        assert!(matches!(codec.decode("not base64!.not base64!"), Err(StoreError::InvalidCursor)));
+    }
+
+    #[test]
+    fn cursor_of_another_filter_is_rejected() {
+        // same secret as the store tests, the codec is shared by the process
+        std::env::set_var("STORE_CURSOR_SECRET", "test cursor secret");
+        let request = PageRequest::new(10).filter(Filter::eq("username", serde_json::json!("first")));
+        let keys = request.sort_keys();
+        let cursor = CursorCodec::global().unwrap().encode(&payload(request.filter_digest().unwrap())).unwrap();
+        assert!(request.clone().cursor(&cursor).position(&keys).unwrap().is_some());
+        let other = PageRequest::new(10).filter(Filter::eq("username", serde_json::json!("second"))).cursor(&cursor);
+        assert_ne!(other.filter_digest().unwrap(), request.filter_digest().unwrap());
+        assert!(matches!(other.position(&keys), Err(StoreError::InvalidCursor)));
+        let unfiltered = PageRequest::new(10).cursor(&cursor);
+        assert!(matches!(unfiltered.position(&keys), Err(StoreError::InvalidCursor)));
+    }
+
+    #[test]
+    fn page_limit_is_bounded() {
+        assert!(PageRequest::new(1).check_limit().is_ok());
+        assert!(PageRequest::new(MAX_PAGE_LIMIT).check_limit().is_ok());
+        assert!(matches!(PageRequest::new(0).check_limit(), Err(StoreError::InvalidFilter(_))));
+        assert!(matches!(PageRequest::new(MAX_PAGE_LIMIT + 1).check_limit(), Err(StoreError::InvalidFilter(_))));
+    }
+}
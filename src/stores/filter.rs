@@ -0,0 +1,650 @@
+use crate::stores::columns::{ColumnDef, ColumnRegistry, Dialect, QueryParams, SqlType};
+use crate::stores::store::StoreError;
+use crate::stores::token_store::{RevocationReason, TokenPurpose};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use std::cmp::Ordering;
+use token_lib::token::token::TokenType;
+use user_lib::user::user::UserRoles;
+use uuid::Uuid;
+
+// Filter expressions accepted by get_by_slug. The JSON form is a Mongo like
+// object, plain values compare for equality and everything else is an
+// operator object:
+//
+// {
+//     "user_role": "Admin",
+//     "created_at": {"$gte": "2024-01-01T00:00:00", "$lt": "2024-02-01T00:00:00"},
+//     "$or": [{"email": {"$ilike": "%@example.com"}}, {"confirmed": false}],
+//     "$order_by": ["-created_at", "id"],
+//     "$limit": 20
+// }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    In,
+    Like,
+    ILike,
+    // value is a bool, true for IS NULL and false for IS NOT NULL
+    IsNull,
+}
+
+impl Operator {
+    pub fn from_key(key: &str) -> Result<Operator, StoreError> {
+        match key {
+            "$eq" => Ok(Operator::Eq),
+            "$ne" => Ok(Operator::Ne),
+            "$lt" => Ok(Operator::Lt),
+            "$lte" => Ok(Operator::Lte),
+            "$gt" => Ok(Operator::Gt),
+            "$gte" => Ok(Operator::Gte),
+            "$in" => Ok(Operator::In),
+            "$like" => Ok(Operator::Like),
+            "$ilike" => Ok(Operator::ILike),
+            "$null" => Ok(Operator::IsNull),
+            _ => Err(StoreError::InvalidFilter(format!("unknown operator {}", key))),
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            Operator::Eq => "=",
+            Operator::Ne => "<>",
+            Operator::Lt => "<",
+            Operator::Lte => "<=",
+            Operator::Gt => ">",
+            Operator::Gte => ">=",
+            Operator::In => "IN",
+            Operator::Like => "LIKE",
+            Operator::ILike => "ILIKE",
+            Operator::IsNull => "IS NULL",
+        }
+    }
+}
+
+// Serialized only to fingerprint a filter, see PageRequest.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Filter {
+    Condition {
+        column: String,
+        operator: Operator,
+        value: serde_json::Value,
+    },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::And(Vec::new())
+    }
+}
+
+impl Filter {
+    pub fn condition(column: &str, operator: Operator, value: serde_json::Value) -> Filter {
+        Filter::Condition {
+            column: column.to_string(),
+            operator,
+            value,
+        }
+    }
+
+    pub fn eq(column: &str, value: serde_json::Value) -> Filter {
+        Filter::condition(column, Operator::Eq, value)
+    }
+
+    // Narrows the filter to rows that are not soft deleted.
+    pub fn not_deleted(self) -> Filter {
+        Filter::And(vec![self, Filter::condition("deleted_at", Operator::IsNull, serde_json::json!(true))])
+    }
+
+    pub fn from_json(json_filter: &serde_json::Value) -> Result<Filter, StoreError> {
+        let map = match json_filter {
+            serde_json::Value::Object(map) => map,
+            _ => return Err(StoreError::InvalidFilter(String::from("filter must be an object"))),
+        };
+        let mut filters = Vec::new();
+        for (key, value) in map {
+            match key.as_str() {
+                "$or" | "$and" => {
+                    let items = value.as_array().ok_or_else(|| {
+                        StoreError::InvalidFilter(format!("{} expects an array of filters", key))
+                    })?;
+                    let group = items
+                        .iter()
+                        .map(Filter::from_json)
+                        .collect::<Result<Vec<Filter>, StoreError>>()?;
+                    if key == "$or" {
+                        filters.push(Filter::Or(group));
+                    } else {
+                        filters.push(Filter::And(group));
+                    }
+                }
+                _ if key.starts_with('$') => {
+                    return Err(StoreError::InvalidFilter(format!("unexpected key {}", key)));
+                }
+                _ => match value {
+                    serde_json::Value::Object(operators) => {
+                        for (operator, operand) in operators {
+                            let operator = Operator::from_key(operator)?;
+                            filters.push(Filter::condition(key, operator, operand.clone()));
+                        }
+                    }
+                    _ => filters.push(Filter::eq(key, value.clone())),
+                },
+            }
+        }
+        if filters.len() == 1 {
+            return Ok(filters.remove(0));
+        }
+        Ok(Filter::And(filters))
+    }
+
+    // Renders the expression with one placeholder per value, the values are
+    // collected into params in placeholder order.
+    pub fn to_sql(&self, columns: &ColumnRegistry, params: &mut QueryParams) -> Result<String, StoreError> {
+        match self {
+            Filter::And(filters) | Filter::Or(filters) => {
+                if filters.is_empty() {
+                    // an empty AND matches everything, an empty OR nothing
+                    return Ok(String::from(if matches!(self, Filter::And(_)) { "TRUE" } else { "FALSE" }));
+                }
+                let joiner = if matches!(self, Filter::And(_)) { " AND " } else { " OR " };
+                let parts = filters
+                    .iter()
+                    .map(|filter| filter.to_sql(columns, params))
+                    .collect::<Result<Vec<String>, StoreError>>()?;
+                Ok(format!("({})", parts.join(joiner)))
+            }
+            Filter::Condition {
+                column,
+                operator,
+                value,
+            } => {
+                let column = columns.filterable(column)?;
+                let name = column.quoted();
+                let value = &stored_operand(column, *operator, value)?;
+                match operator {
+                    Operator::IsNull => {
+                        let is_null = value.as_bool().ok_or_else(|| {
+                            StoreError::InvalidFilter(String::from("$null expects a boolean"))
+                        })?;
+                        if is_null {
+                            Ok(format!("{} IS NULL", name))
+                        } else {
+                            Ok(format!("{} IS NOT NULL", name))
+                        }
+                    }
+                    Operator::Eq if value.is_null() => Ok(format!("{} IS NULL", name)),
+                    Operator::Ne if value.is_null() => Ok(format!("{} IS NOT NULL", name)),
+                    Operator::In => {
+                        let items = value.as_array().ok_or_else(|| {
+                            StoreError::InvalidFilter(String::from("$in expects an array"))
+                        })?;
+                        if items.is_empty() {
+                            return Ok(String::from("FALSE"));
+                        }
+                        let placeholders = items
+                            .iter()
+                            .map(|item| params.push(column.sql_type, item.clone()))
+                            .collect::<Vec<String>>();
+                        Ok(format!("{} IN ({})", name, placeholders.join(", ")))
+                    }
+                    Operator::Like | Operator::ILike if column.sql_type != SqlType::Text => {
+                        Err(StoreError::InvalidFilter(format!(
+                            "{} can not be matched with a pattern",
+                            column.name
+                        )))
+                    }
+                    // SQLite has no ILIKE and its LIKE already ignores ASCII case
+                    Operator::Like | Operator::ILike if params.dialect() == Dialect::Sqlite => {
+                        let placeholder = params.push(column.sql_type, value.clone());
+                        Ok(format!("{} LIKE {} ESCAPE '\\'", name, placeholder))
+                    }
+                    _ => {
+                        let placeholder = params.push(column.sql_type, value.clone());
+                        Ok(format!("{} {} {}", name, operator.sql(), placeholder))
+                    }
+                }
+            }
+        }
+    }
+
+    // Evaluates the expression against a serialized row, with the semantics
+    // of the SQL rendered by to_sql. Used by the stores without a database.
+    pub fn matches(&self, columns: &ColumnRegistry, row: &serde_json::Value) -> Result<bool, StoreError> {
+        match self {
+            Filter::And(filters) => {
+                for filter in filters {
+                    if !filter.matches(columns, row)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Filter::Or(filters) => {
+                for filter in filters {
+                    if filter.matches(columns, row)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Filter::Condition {
+                column,
+                operator,
+                value,
+            } => {
+                let column = columns.filterable(column)?;
+                let actual = row.get(column.name).unwrap_or(&serde_json::Value::Null);
+                let value = &stored_operand(column, *operator, value)?;
+                match operator {
+                    Operator::IsNull => {
+                        let is_null = value.as_bool().ok_or_else(|| {
+                            StoreError::InvalidFilter(String::from("$null expects a boolean"))
+                        })?;
+                        Ok(actual.is_null() == is_null)
+                    }
+                    Operator::Eq if value.is_null() => Ok(actual.is_null()),
+                    Operator::Ne if value.is_null() => Ok(!actual.is_null()),
+                    Operator::In => {
+                        let items = value.as_array().ok_or_else(|| {
+                            StoreError::InvalidFilter(String::from("$in expects an array"))
+                        })?;
+                        for item in items {
+                            if compare_json(column.sql_type, actual, item)? == Some(Ordering::Equal) {
+                                return Ok(true);
+                            }
+                        }
+                        Ok(false)
+                    }
+                    Operator::Like | Operator::ILike => {
+                        if column.sql_type != SqlType::Text {
+                            return Err(StoreError::InvalidFilter(format!(
+                                "{} can not be matched with a pattern",
+                                column.name
+                            )));
+                        }
+                        let pattern: Option<String> =
+                            serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
+                        match (actual.as_str(), pattern) {
+                            (Some(text), Some(pattern)) if *operator == Operator::ILike => {
+                                Ok(like_match(&pattern.to_lowercase(), &text.to_lowercase()))
+                            }
+                            (Some(text), Some(pattern)) => Ok(like_match(&pattern, text)),
+                            _ => Ok(false),
+                        }
+                    }
+                    _ => {
+                        // comparisons with NULL are never true
+                        let ordering = match compare_json(column.sql_type, actual, value)? {
+                            Some(ordering) => ordering,
+                            None => return Ok(false),
+                        };
+                        Ok(match operator {
+                            Operator::Eq => ordering == Ordering::Equal,
+                            Operator::Ne => ordering != Ordering::Equal,
+                            Operator::Lt => ordering == Ordering::Less,
+                            Operator::Lte => ordering != Ordering::Greater,
+                            Operator::Gt => ordering == Ordering::Greater,
+                            _ => ordering != Ordering::Less,
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Operands compared against a hashed column are hashed the same way, only
+// equality means anything for them.
+fn stored_operand(column: &ColumnDef, operator: Operator, value: &serde_json::Value) -> Result<serde_json::Value, StoreError> {
+    if !column.hashed {
+        return Ok(value.clone());
+    }
+    match (operator, value) {
+        (Operator::In, serde_json::Value::Array(items)) => {
+            Ok(serde_json::Value::Array(
+                items.iter().map(|item| column.stored_value(item)).collect::<Result<_, StoreError>>()?,
+            ))
+        }
+        (Operator::Eq | Operator::Ne | Operator::In | Operator::IsNull, _) => column.stored_value(value),
+        _ => Err(StoreError::InvalidFilter(format!(
+            "{} can only be compared for equality",
+            column.name
+        ))),
+    }
+}
+
+// Orders two JSON values as the database orders values of the column type,
+// None when either side is null.
+pub fn compare_json(
+    sql_type: SqlType,
+    left: &serde_json::Value,
+    right: &serde_json::Value,
+) -> Result<Option<Ordering>, StoreError> {
+    fn parse<T: serde::de::DeserializeOwned>(value: &serde_json::Value) -> Result<Option<T>, StoreError> {
+        serde_json::from_value(value.clone()).map_err(StoreError::JsonError)
+    }
+    let ordering = match sql_type {
+        SqlType::Uuid => parse::<Uuid>(left)?.zip(parse::<Uuid>(right)?).map(|(l, r)| l.cmp(&r)),
+        SqlType::Bool => parse::<bool>(left)?.zip(parse::<bool>(right)?).map(|(l, r)| l.cmp(&r)),
+        SqlType::BigInt => parse::<i64>(left)?.zip(parse::<i64>(right)?).map(|(l, r)| l.cmp(&r)),
+        SqlType::Timestamp => parse::<NaiveDateTime>(left)?
+            .zip(parse::<NaiveDateTime>(right)?)
+            .map(|(l, r)| l.cmp(&r)),
+        SqlType::Text => parse::<String>(left)?.zip(parse::<String>(right)?).map(|(l, r)| l.cmp(&r)),
+        // Postgres orders enum values as they were declared, the positions
+        // below follow the CREATE TYPE statements; the SQLite labels of
+        // user_role and token_type happen to sort the same way as text
+        SqlType::UserRole => parse::<UserRoles>(left)?
+            .zip(parse::<UserRoles>(right)?)
+            .map(|(l, r)| role_position(l).cmp(&role_position(r))),
+        SqlType::TokenType => parse::<TokenType>(left)?
+            .zip(parse::<TokenType>(right)?)
+            .map(|(l, r)| token_type_position(l).cmp(&token_type_position(r))),
+        SqlType::RevocationReason => parse::<RevocationReason>(left)?
+            .zip(parse::<RevocationReason>(right)?)
+            .map(|(l, r)| (l as u8).cmp(&(r as u8))),
+        SqlType::TokenPurpose => parse::<TokenPurpose>(left)?
+            .zip(parse::<TokenPurpose>(right)?)
+            .map(|(l, r)| (l as u8).cmp(&(r as u8))),
+    };
+    Ok(ordering)
+}
+
+fn role_position(role: UserRoles) -> u8 {
+    match role {
+        UserRoles::Admin => 0,
+        UserRoles::Normal => 1,
+    }
+}
+
+fn token_type_position(token_type: TokenType) -> u8 {
+    match token_type {
+        TokenType::AccessToken => 0,
+        TokenType::RefreshToken => 1,
+    }
+}
+
+// SQL LIKE: % matches any run of characters, _ a single one and \ escapes.
+fn like_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // matched[j] is true when the pattern so far matches text[..j]
+    let mut matched = vec![false; text.len() + 1];
+    matched[0] = true;
+    let mut i = 0;
+    while i < pattern.len() {
+        let (literal, c) = match pattern[i] {
+            '\\' if i + 1 < pattern.len() => {
+                i += 1;
+                (true, pattern[i])
+            }
+            c => (false, c),
+        };
+        let mut next = vec![false; text.len() + 1];
+        if !literal && c == '%' {
+            let mut any = false;
+            for j in 0..=text.len() {
+                any = any || matched[j];
+                next[j] = any;
+            }
+        } else {
+            for j in 0..text.len() {
+                next[j + 1] = matched[j] && ((!literal && c == '_') || text[j] == c);
+            }
+        }
+        matched = next;
+        i += 1;
+    }
+    matched[text.len()]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortDirection {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub column: String,
+    pub direction: SortDirection,
+}
+
+impl SortKey {
+    pub fn asc(column: &str) -> SortKey {
+        SortKey {
+            column: column.to_string(),
+            direction: SortDirection::Asc,
+        }
+    }
+
+    pub fn desc(column: &str) -> SortKey {
+        SortKey {
+            column: column.to_string(),
+            direction: SortDirection::Desc,
+        }
+    }
+
+    // "created_at" sorts ascending, "-created_at" descending
+    pub fn parse(key: &str) -> SortKey {
+        match key.strip_prefix('-') {
+            Some(column) => SortKey::desc(column),
+            None => SortKey::asc(key),
+        }
+    }
+
+    pub fn to_key(&self) -> String {
+        match self.direction {
+            SortDirection::Asc => self.column.clone(),
+            SortDirection::Desc => format!("-{}", self.column),
+        }
+    }
+}
+
+// Compares two serialized rows by the sort keys, nulls sort last like they do
+// in an ascending Postgres index.
+pub(crate) fn compare_rows(
+    columns: &ColumnRegistry,
+    keys: &[SortKey],
+    left: &serde_json::Value,
+    right: &serde_json::Value,
+) -> Result<Ordering, StoreError> {
+    for key in keys {
+        let column = columns.filterable(&key.column)?;
+        let null = serde_json::Value::Null;
+        let left = left.get(column.name).unwrap_or(&null);
+        let right = right.get(column.name).unwrap_or(&null);
+        let ordering = match compare_json(column.sql_type, left, right)? {
+            Some(ordering) => ordering,
+            None => left.is_null().cmp(&right.is_null()),
+        };
+        let ordering = match key.direction {
+            SortDirection::Asc => ordering,
+            SortDirection::Desc => ordering.reverse(),
+        };
+        if ordering != Ordering::Equal {
+            return Ok(ordering);
+        }
+    }
+    Ok(Ordering::Equal)
+}
+
+// Sorts rows by the keys, the first comparison error is returned.
+pub(crate) fn sort_rows<T>(
+    columns: &ColumnRegistry,
+    keys: &[SortKey],
+    rows: &mut [(serde_json::Value, T)],
+) -> Result<(), StoreError> {
+    for key in keys {
+        columns.filterable(&key.column)?;
+    }
+    let mut error = None;
+    rows.sort_by(|(left, _), (right, _)| {
+        compare_rows(columns, keys, left, right).unwrap_or_else(|e| {
+            error.get_or_insert(e);
+            Ordering::Equal
+        })
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// A filter together with the ordering and limit of the select it drives.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterQuery {
+    pub filter: Filter,
+    pub order_by: Vec<SortKey>,
+    pub limit: Option<i64>,
+}
+
+impl FilterQuery {
+    pub fn new(filter: Filter) -> Self {
+        FilterQuery {
+            filter,
+            ..Default::default()
+        }
+    }
+
+    pub fn order_by(mut self, key: SortKey) -> Self {
+        self.order_by.push(key);
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn from_json(json_filter: &serde_json::Value) -> Result<FilterQuery, StoreError> {
+        let mut map = match json_filter {
+            serde_json::Value::Object(map) => map.clone(),
+            _ => return Err(StoreError::InvalidFilter(String::from("filter must be an object"))),
+        };
+        let mut order_by = Vec::new();
+        match map.remove("$order_by") {
+            Some(serde_json::Value::String(key)) => order_by.push(SortKey::parse(&key)),
+            Some(serde_json::Value::Array(keys)) => {
+                for key in keys {
+                    let key = key.as_str().ok_or_else(|| {
+                        StoreError::InvalidFilter(String::from("$order_by expects column names"))
+                    })?;
+                    order_by.push(SortKey::parse(key));
+                }
+            }
+            Some(_) => {
+                return Err(StoreError::InvalidFilter(String::from("$order_by expects column names")));
+            }
+            None => {}
+        }
+        let limit = match map.remove("$limit") {
+            Some(limit) => Some(limit.as_i64().filter(|limit| *limit >= 0).ok_or_else(|| {
+                StoreError::InvalidFilter(String::from("$limit expects a positive integer"))
+            })?),
+            None => None,
+        };
+        Ok(FilterQuery {
+            filter: Filter::from_json(&serde_json::Value::Object(map))?,
+            order_by,
+            limit,
+        })
+    }
+
+    // Everything following `SELECT ... FROM table`.
+    pub fn to_sql(&self, columns: &ColumnRegistry, params: &mut QueryParams) -> Result<String, StoreError> {
+        let mut custom_query = format!("WHERE {}", self.filter.to_sql(columns, params)?);
+        if !self.order_by.is_empty() {
+            let keys = self
+                .order_by
+                .iter()
+                .map(|key| {
+                    let column = columns.filterable(&key.column)?;
+                    Ok(match key.direction {
+                        SortDirection::Asc => format!("{} ASC", column.quoted()),
+                        SortDirection::Desc => format!("{} DESC", column.quoted()),
+                    })
+                })
+                .collect::<Result<Vec<String>, StoreError>>()?;
+            custom_query.push_str(&format!(" ORDER BY {}", keys.join(", ")));
+        }
+        if let Some(limit) = self.limit {
+            custom_query.push_str(&format!(" LIMIT {}", params.push(SqlType::BigInt, serde_json::json!(limit))));
+        }
+        Ok(custom_query)
+    }
+
+    // Runs the query over rows held in memory.
+    pub fn apply<T: serde::Serialize>(&self, columns: &ColumnRegistry, rows: Vec<T>) -> Result<Vec<T>, StoreError> {
+        let mut selected = Vec::new();
+        for row in rows {
+            let json_row = serde_json::to_value(&row).map_err(StoreError::JsonError)?;
+            if self.filter.matches(columns, &json_row)? {
+                selected.push((json_row, row));
+            }
+        }
+        sort_rows(columns, &self.order_by, &mut selected)?;
+        let limit = self.limit.map(|limit| limit as usize).unwrap_or(selected.len());
+        Ok(selected.into_iter().take(limit).map(|(_, row)| row).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn like_match_wildcards() {
+        assert!(like_match("tr%", "true"));
+        assert!(like_match("%", ""));
+        assert!(like_match("%@example.com", "name@example.com"));
+        assert!(!like_match("%@example.com", "name@example.org"));
+        assert!(like_match("t_ue", "true"));
+        assert!(!like_match("t_e", "true"));
+        assert!(!like_match("_", ""));
+    }
+
+    #[test]
+    fn like_match_escapes() {
+        assert!(like_match("100\\%", "100%"));
+        assert!(!like_match("100\\%", "1000"));
+        assert!(like_match("a\\_b", "a_b"));
+        assert!(!like_match("a\\_b", "axb"));
+        assert!(like_match("a\\\\b", "a\\b"));
+        // a trailing backslash has nothing to escape and stands for itself
+        assert!(like_match("a\\", "a\\"));
+    }
+
+    #[test]
+    fn compare_json_orders_enums_by_declaration() {
+        let admin = serde_json::to_value(UserRoles::Admin).unwrap();
+        let normal = serde_json::to_value(UserRoles::Normal).unwrap();
+        assert_eq!(compare_json(SqlType::UserRole, &admin, &normal).unwrap(), Some(Ordering::Less));
+        let access = serde_json::to_value(TokenType::AccessToken).unwrap();
+        let refresh = serde_json::to_value(TokenType::RefreshToken).unwrap();
+        assert_eq!(compare_json(SqlType::TokenType, &refresh, &access).unwrap(), Some(Ordering::Greater));
+        let logout = serde_json::to_value(RevocationReason::Logout).unwrap();
+        let reuse = serde_json::to_value(RevocationReason::ReuseDetected).unwrap();
+        assert_eq!(compare_json(SqlType::RevocationReason, &logout, &reuse).unwrap(), Some(Ordering::Less));
+    }
+
+    #[test]
+    fn compare_json_nulls_and_mismatches() {
+        let null = serde_json::Value::Null;
+        assert_eq!(compare_json(SqlType::BigInt, &null, &serde_json::json!(1)).unwrap(), None);
+        assert_eq!(
+            compare_json(SqlType::Timestamp, &serde_json::json!("2024-01-02T00:00:00"), &serde_json::json!("2024-01-01T00:00:00")).unwrap(),
+            Some(Ordering::Greater)
+        );
+        assert!(compare_json(SqlType::BigInt, &serde_json::json!("one"), &serde_json::json!(1)).is_err());
+    }
+}
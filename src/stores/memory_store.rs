@@ -0,0 +1,518 @@
+use crate::stores::auth::{hash_password, hash_password_patch, update_password_hash};
+use crate::stores::columns::ColumnRegistry;
+use crate::stores::cursor::{Page, PageRequest};
+use crate::stores::filter::FilterQuery;
+use crate::stores::store::{StoreError, StoreTrait};
+use crate::stores::token_hash::TokenHasher;
+use crate::stores::token_store::{TokenPurpose, TokenRow, TOKEN_COLUMNS};
+use crate::stores::user_store::{UserRow, USER_COLUMNS};
+use chrono::{NaiveDateTime, Timelike, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use token_lib::token::token::Token;
+use user_lib::user::user::User;
+use uuid::Uuid;
+
+// Stand-in for the Postgres database, for unit tests and local development.
+// Like the PG stores the in-memory stores hold no state and take the database
+// as their connection, so one MemoryDatabase backs both of them.
+#[derive(Debug, Default)]
+pub struct MemoryDatabase {
+    users: RwLock<HashMap<Uuid, UserRow>>,
+    tokens: RwLock<HashMap<Uuid, TokenRow>>,
+}
+
+fn read<T>(table: &RwLock<T>) -> Result<RwLockReadGuard<'_, T>, StoreError> {
+    table.read().map_err(|e| StoreError::other(&e))
+}
+
+fn write<T>(table: &RwLock<T>) -> Result<RwLockWriteGuard<'_, T>, StoreError> {
+    table.write().map_err(|e| StoreError::other(&e))
+}
+
+fn now() -> NaiveDateTime {
+    // Postgres keeps timestamps to the microsecond
+    let now = Utc::now().naive_utc();
+    now.with_nanosecond(now.nanosecond() / 1_000 * 1_000).unwrap_or(now)
+}
+
+fn to_json<T: Serialize>(row: &T) -> Result<serde_json::Value, StoreError> {
+    serde_json::to_value(row).map_err(StoreError::JsonError)
+}
+
+fn to_json_vec<T: Serialize>(rows: &[T]) -> Result<Vec<serde_json::Value>, StoreError> {
+    rows.iter().map(to_json).collect()
+}
+
+fn to_json_page<T: Serialize>(page: Page<T>) -> Result<Page<serde_json::Value>, StoreError> {
+    Ok(Page {
+        items: to_json_vec(&page.items)?,
+        next_cursor: page.next_cursor,
+        prev_cursor: page.prev_cursor,
+    })
+}
+
+// Applies a patch the way the PG stores' patch_row does: only patchable
+// columns, NULL only in nullable ones, updated_at bumped.
+fn apply_patch<T: Serialize + DeserializeOwned>(
+    columns: &ColumnRegistry,
+    row: &T,
+    patch: &serde_json::Value,
+) -> Result<T, StoreError> {
+    let map = match patch {
+        serde_json::Value::Object(map) => map,
+        _ => return Err(StoreError::NotFound),
+    };
+    let mut json_row = to_json(row)?;
+    for (key, value) in map {
+        let column = columns.patchable(key)?;
+        if value.is_null() && !column.nullable {
+            return Err(StoreError::InvalidInput {
+                field: column.name.to_string(),
+                reason: String::from("null value violates not-null constraint"),
+                source: None,
+            });
+        }
+        json_row[column.name] = column.stored_value(value)?;
+    }
+    json_row["updated_at"] = serde_json::json!(now());
+    json_row["version"] = serde_json::json!(json_row["version"].as_i64().unwrap_or_default() + 1);
+    serde_json::from_value(json_row).map_err(StoreError::JsonError)
+}
+
+fn paginate<T>(mut rows: Vec<T>, key: impl Fn(&T) -> (NaiveDateTime, Uuid), limit: i64, offset: i64) -> Vec<T> {
+    rows.sort_by_key(key);
+    rows.into_iter()
+        .skip(offset.max(0) as usize)
+        .take(limit.max(0) as usize)
+        .collect()
+}
+
+// Deletes are soft as in UserPGStore, unique usernames and emails still
+// count soft deleted users.
+#[derive(Debug, Default)]
+pub struct InMemoryUserStore {
+    // whether reads and writes also see soft deleted users
+    include_deleted: bool,
+}
+
+impl InMemoryUserStore {
+    // Makes the store see soft deleted users as well.
+    pub fn include_deleted(mut self) -> Self {
+        self.include_deleted = true;
+        self
+    }
+
+    fn visible(&self, row: &UserRow) -> bool {
+        self.include_deleted || row.deleted_at.is_none()
+    }
+
+    // The users this store sees.
+    fn visible_rows(&self, users: &HashMap<Uuid, UserRow>) -> Vec<UserRow> {
+        users.values().filter(|row| self.visible(row)).cloned().collect()
+    }
+
+    // Mirrors the users_username_key and users_email_key constraints.
+    fn check_unique(users: &HashMap<Uuid, UserRow>, row: &UserRow) -> Result<(), StoreError> {
+        let others = users.values().filter(|other| other.id != row.id);
+        for other in others {
+            if other.username == row.username {
+                return Err(StoreError::UniqueViolation {
+                    field: String::from("username"),
+                    source: None,
+                });
+            }
+            if other.email == row.email {
+                return Err(StoreError::UniqueViolation {
+                    field: String::from("email"),
+                    source: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn get_by_username(
+        &self,
+        connection: &MemoryDatabase,
+        username: &str,
+    ) -> Result<serde_json::Value, StoreError> {
+        let users = read(&connection.users)?;
+        let row = users
+            .values()
+            .find(|row| row.username == username && self.visible(row))
+            .ok_or(StoreError::NotFound)?;
+        to_json(row)
+    }
+
+    // Brings back a soft deleted user, NotFound unless it is soft deleted.
+    // Its tokens stay deleted so it has to log in again.
+    pub async fn restore(&self, connection: &MemoryDatabase, id: Uuid) -> Result<UserRow, StoreError> {
+        let mut users = write(&connection.users)?;
+        let row = users
+            .get_mut(&id)
+            .filter(|row| row.deleted_at.is_some())
+            .ok_or(StoreError::NotFound)?;
+        row.deleted_at = None;
+        row.updated_at = now();
+        row.version += 1;
+        Ok(row.clone())
+    }
+
+    // Removes the user for good, soft deleted or not, and its tokens with it.
+    pub async fn hard_delete(&self, connection: &MemoryDatabase, id: Uuid) -> Result<(), StoreError> {
+        let mut users = write(&connection.users)?;
+        users.remove(&id).ok_or(StoreError::NotFound)?;
+        // tokens go with their user, as the foreign key cascade does
+        write(&connection.tokens)?.retain(|_, row| row.user_id != Some(id));
+        Ok(())
+    }
+}
+
+impl<'c> StoreTrait<&'c MemoryDatabase> for InMemoryUserStore {
+    async fn insert(
+        &self,
+        connection: &'c MemoryDatabase,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let user_obj: User = serde_json::from_value(item).map_err(StoreError::JsonError)?;
+        let password_hash = hash_password(user_obj.get_password()).await?;
+        let created_at = now();
+        let row = UserRow {
+            id: Uuid::new_v4(),
+            username: user_obj.get_name().to_string(),
+            email: user_obj.get_email().to_string(),
+            password_hash,
+            user_role: user_obj.get_role(),
+            confirmed: user_obj.get_confirmed_status(),
+            created_at,
+            updated_at: created_at,
+            failed_login_count: 0,
+            locked_until: None,
+            last_login_at: None,
+            deleted_at: None,
+            version: 1,
+        };
+        let mut users = write(&connection.users)?;
+        Self::check_unique(&users, &row)?;
+        users.insert(row.id, row.clone());
+        to_json(&row)
+    }
+
+    async fn get(
+        &self,
+        connection: &'c MemoryDatabase,
+        id: Uuid,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let users = read(&connection.users)?;
+        to_json_vec(&users.get(&id).filter(|row| self.visible(row)).into_iter().collect::<Vec<&UserRow>>())
+    }
+
+    async fn get_all_paginate(
+        &self,
+        connection: &'c MemoryDatabase,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let rows = self.visible_rows(&read(&connection.users)?);
+        let rows = paginate(rows, |row: &UserRow| (row.created_at, row.id), limit, offset);
+        to_json_vec(&rows)
+    }
+
+    async fn get_page(
+        &self,
+        connection: &'c MemoryDatabase,
+        request: PageRequest,
+    ) -> Result<Page<serde_json::Value>, StoreError> {
+        let rows = self.visible_rows(&read(&connection.users)?);
+        to_json_page(request.apply(&USER_COLUMNS, rows)?)
+    }
+
+    async fn count(&self, connection: &'c MemoryDatabase) -> Result<usize, StoreError> {
+        Ok(self.visible_rows(&read(&connection.users)?).len())
+    }
+
+    async fn get_by_slug(
+        &self,
+        connection: &'c MemoryDatabase,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let filter = FilterQuery::from_json(&json_slug)?;
+        let rows = self.visible_rows(&read(&connection.users)?);
+        to_json_vec(&filter.apply(&USER_COLUMNS, rows)?)
+    }
+
+    // Soft deletes the user together with its tokens, as UserPGStore does.
+    async fn delete(&self, connection: &'c MemoryDatabase, id: Uuid) -> Result<(), StoreError> {
+        let deleted_at = now();
+        let mut users = write(&connection.users)?;
+        let row = users
+            .get_mut(&id)
+            .filter(|row| row.deleted_at.is_none())
+            .ok_or(StoreError::NotFound)?;
+        row.deleted_at = Some(deleted_at);
+        let mut tokens = write(&connection.tokens)?;
+        for token in tokens.values_mut() {
+            if token.user_id == Some(id) && token.deleted_at.is_none() {
+                token.deleted_at = Some(deleted_at);
+            }
+        }
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        connection: &'c MemoryDatabase,
+        id: Uuid,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let user_data: User = serde_json::from_value(item).map_err(StoreError::JsonError)?;
+        let stored = read(&connection.users)?.get(&id).map(|row| row.password_hash.clone());
+        let password_hash = update_password_hash(user_data.get_password(), stored.as_deref()).await?;
+        let mut users = write(&connection.users)?;
+        let current = users.get(&id).filter(|row| self.visible(row)).ok_or(StoreError::NotFound)?;
+        let row = UserRow {
+            id,
+            username: user_data.get_name().to_string(),
+            email: user_data.get_email().to_string(),
+            password_hash,
+            user_role: user_data.get_role(),
+            confirmed: user_data.get_confirmed_status(),
+            created_at: current.created_at,
+            updated_at: now(),
+            failed_login_count: current.failed_login_count,
+            locked_until: current.locked_until,
+            last_login_at: current.last_login_at,
+            deleted_at: current.deleted_at,
+            version: current.version + 1,
+        };
+        Self::check_unique(&users, &row)?;
+        users.insert(id, row.clone());
+        to_json(&row)
+    }
+
+    async fn patch(
+        &self,
+        connection: &'c MemoryDatabase,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let patch = hash_password_patch(patch).await?;
+        let mut users = write(&connection.users)?;
+        let current = users.get(&id).filter(|row| self.visible(row)).ok_or(StoreError::NotFound)?;
+        let row = apply_patch(&USER_COLUMNS, current, &patch)?;
+        Self::check_unique(&users, &row)?;
+        users.insert(id, row.clone());
+        to_json(&row)
+    }
+}
+
+// Deletes are soft as in TokenPGStore.
+#[derive(Debug, Default)]
+pub struct InMemoryTokenStore {
+    // whether reads and writes also see soft deleted tokens
+    include_deleted: bool,
+}
+
+impl InMemoryTokenStore {
+    // Makes the store see soft deleted tokens as well.
+    pub fn include_deleted(mut self) -> Self {
+        self.include_deleted = true;
+        self
+    }
+
+    fn visible(&self, row: &TokenRow) -> bool {
+        self.include_deleted || row.deleted_at.is_none()
+    }
+
+    // The tokens this store sees.
+    fn visible_rows(&self, tokens: &HashMap<Uuid, TokenRow>) -> Vec<TokenRow> {
+        tokens.values().filter(|row| self.visible(row)).cloned().collect()
+    }
+
+    // Mirrors the tokens_token_string_key constraint.
+    fn check_unique(tokens: &HashMap<Uuid, TokenRow>, row: &TokenRow) -> Result<(), StoreError> {
+        if tokens.values().any(|other| other.id != row.id && other.token_string == row.token_string) {
+            return Err(StoreError::UniqueViolation {
+                field: String::from("token_string"),
+                source: None,
+            });
+        }
+        Ok(())
+    }
+
+    pub async fn delete_by_token(&self, connection: &MemoryDatabase, token_string: String) -> Result<(), StoreError> {
+        let token_string = TokenHasher::global()?.hash(&token_string);
+        let deleted_at = now();
+        for row in write(&connection.tokens)?.values_mut() {
+            if row.token_string == token_string && row.deleted_at.is_none() {
+                row.deleted_at = Some(deleted_at);
+            }
+        }
+        Ok(())
+    }
+
+    // Brings back a soft deleted token, NotFound unless it is soft deleted.
+    pub async fn restore(&self, connection: &MemoryDatabase, id: Uuid) -> Result<TokenRow, StoreError> {
+        let mut tokens = write(&connection.tokens)?;
+        let row = tokens
+            .get_mut(&id)
+            .filter(|row| row.deleted_at.is_some())
+            .ok_or(StoreError::NotFound)?;
+        row.deleted_at = None;
+        row.updated_at = now();
+        row.version += 1;
+        Ok(row.clone())
+    }
+
+    // Removes the token for good, soft deleted or not.
+    pub async fn hard_delete(&self, connection: &MemoryDatabase, id: Uuid) -> Result<(), StoreError> {
+        write(&connection.tokens)?
+            .remove(&id)
+            .map(|_| ())
+            .ok_or(StoreError::NotFound)
+    }
+}
+
+impl<'c> StoreTrait<&'c MemoryDatabase> for InMemoryTokenStore {
+    async fn insert(
+        &self,
+        connection: &'c MemoryDatabase,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let expires_at: Option<NaiveDateTime> =
+            serde_json::from_value(item.get("expires_at").cloned().unwrap_or_default()).map_err(StoreError::JsonError)?;
+        let user_id: Option<Uuid> =
+            serde_json::from_value(item.get("user_id").cloned().unwrap_or_default()).map_err(StoreError::JsonError)?;
+        let token_obj: Token = serde_json::from_value(item).map_err(StoreError::JsonError)?;
+        let users = read(&connection.users)?;
+        if user_id.is_some_and(|user_id| !users.contains_key(&user_id)) {
+            return Err(StoreError::ForeignKeyViolation {
+                constraint: String::from("tokens_user_id_fkey"),
+                source: None,
+            });
+        }
+        let created_at = now();
+        let row = TokenRow {
+            id: Uuid::new_v4(),
+            token_string: TokenHasher::global()?.hash(token_obj.get_token()),
+            token_type: token_obj.get_type(),
+            blacklisted: token_obj.get_blacklisted(),
+            created_at,
+            updated_at: created_at,
+            expires_at,
+            user_id,
+            family_id: None,
+            replaced_by: None,
+            revoked_at: None,
+            revocation_reason: None,
+            purpose: TokenPurpose::Session,
+            deleted_at: None,
+            version: 1,
+        };
+        let mut tokens = write(&connection.tokens)?;
+        Self::check_unique(&tokens, &row)?;
+        tokens.insert(row.id, row.clone());
+        to_json(&row)
+    }
+
+    async fn get(
+        &self,
+        connection: &'c MemoryDatabase,
+        id: Uuid,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let tokens = read(&connection.tokens)?;
+        to_json_vec(&tokens.get(&id).filter(|row| self.visible(row)).into_iter().collect::<Vec<&TokenRow>>())
+    }
+
+    async fn get_all_paginate(
+        &self,
+        connection: &'c MemoryDatabase,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let rows = self.visible_rows(&read(&connection.tokens)?);
+        let rows = paginate(rows, |row: &TokenRow| (row.created_at, row.id), limit, offset);
+        to_json_vec(&rows)
+    }
+
+    async fn get_page(
+        &self,
+        connection: &'c MemoryDatabase,
+        request: PageRequest,
+    ) -> Result<Page<serde_json::Value>, StoreError> {
+        let rows = self.visible_rows(&read(&connection.tokens)?);
+        to_json_page(request.apply(&TOKEN_COLUMNS, rows)?)
+    }
+
+    async fn count(&self, connection: &'c MemoryDatabase) -> Result<usize, StoreError> {
+        Ok(self.visible_rows(&read(&connection.tokens)?).len())
+    }
+
+    async fn get_by_slug(
+        &self,
+        connection: &'c MemoryDatabase,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let filter = FilterQuery::from_json(&json_slug)?;
+        let rows = self.visible_rows(&read(&connection.tokens)?);
+        to_json_vec(&filter.apply(&TOKEN_COLUMNS, rows)?)
+    }
+
+    // Soft deletes the token, see restore and hard_delete.
+    async fn delete(&self, connection: &'c MemoryDatabase, id: Uuid) -> Result<(), StoreError> {
+        let mut tokens = write(&connection.tokens)?;
+        let row = tokens
+            .get_mut(&id)
+            .filter(|row| row.deleted_at.is_none())
+            .ok_or(StoreError::NotFound)?;
+        row.deleted_at = Some(now());
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        connection: &'c MemoryDatabase,
+        id: Uuid,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let token_data: Token = serde_json::from_value(item).map_err(StoreError::JsonError)?;
+        let mut tokens = write(&connection.tokens)?;
+        let current = tokens.get(&id).filter(|row| self.visible(row)).ok_or(StoreError::NotFound)?;
+        let row = TokenRow {
+            id,
+            token_string: TokenHasher::global()?.hash(token_data.get_token()),
+            token_type: token_data.get_type(),
+            blacklisted: token_data.get_blacklisted(),
+            created_at: current.created_at,
+            updated_at: now(),
+            expires_at: current.expires_at,
+            user_id: current.user_id,
+            family_id: current.family_id,
+            replaced_by: current.replaced_by,
+            revoked_at: current.revoked_at,
+            revocation_reason: current.revocation_reason,
+            purpose: current.purpose,
+            deleted_at: current.deleted_at,
+            version: current.version + 1,
+        };
+        Self::check_unique(&tokens, &row)?;
+        tokens.insert(id, row.clone());
+        to_json(&row)
+    }
+
+    async fn patch(
+        &self,
+        connection: &'c MemoryDatabase,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut tokens = write(&connection.tokens)?;
+        let current = tokens.get(&id).filter(|row| self.visible(row)).ok_or(StoreError::NotFound)?;
+        let row = apply_patch(&TOKEN_COLUMNS, current, &patch)?;
+        Self::check_unique(&tokens, &row)?;
+        tokens.insert(id, row.clone());
+        to_json(&row)
+    }
+}
@@ -1,13 +1,18 @@
+use crate::stores::columns::{bind_json_value, ColumnRegistry};
+use crate::stores::cursor::{Page, PageRequest};
+use crate::stores::filter::FilterQuery;
 use crate::stores::token_store::TokenPGStore;
 use crate::stores::user_store::UserPGStore;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgRow;
+use sqlx::postgres::{PgDatabaseError, PgRow};
 use sqlx::Row;
+use sqlx::error::ErrorKind;
 use sqlx::{
     postgres::PgArguments,
     query::{self, Query, QueryAs},
-    Execute, Pool, Postgres,
+    Database, Execute, Pool, Postgres, Transaction,
 };
+use std::pin::Pin;
 use std::{error::Error, io};
 use uuid::Uuid;
 
@@ -17,7 +22,50 @@ pub enum StoreError {
     JsonError(serde_json::Error),
     UUIDError(uuid::Error),
     NotFound,
-    OtherError(Box<dyn std::error::Error>),
+    MigrationError(sqlx::migrate::MigrateError),
+    InvalidColumn {
+        column: String,
+        reason: &'static str,
+    },
+    InvalidFilter(String),
+    InvalidCursor,
+    // the token exists but may no longer be used
+    TokenExpired,
+    TokenBlacklisted,
+    // an already rotated refresh token was presented again, its family has
+    // been revoked and the user has to log in again
+    TokenReused,
+    // a unique constraint rejected the write, field is the column it guards;
+    // source is the database error for violations the database reported
+    UniqueViolation {
+        field: String,
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+    ForeignKeyViolation {
+        constraint: String,
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+    // the database refused a value (not null, check or malformed data)
+    InvalidInput {
+        field: String,
+        reason: String,
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+    // the write raced another transaction and may be retried
+    Conflict {
+        reason: String,
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+    // asked again too soon, may be retried once until has passed
+    RateLimited {
+        until: chrono::NaiveDateTime,
+    },
+    // the database could not be reached or is shutting down
+    Unavailable(sqlx::Error),
+    // a setting the stores need is missing, see TokenHasher::global and
+    // CursorCodec::global
+    Configuration(String),
+    OtherError(Box<dyn std::error::Error + Send + Sync>),
 }
 
 pub enum Store {
@@ -25,29 +73,250 @@ pub enum Store {
     TokenPostgersStore(TokenPGStore),
 }
 
-impl std::fmt::Display for StoreError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+pub type TransactionFuture<'c, T> =
+    Pin<Box<dyn std::future::Future<Output = Result<T, StoreError>> + Send + 'c>>;
+
+impl Store {
+    /// Runs `operation` inside a single transaction: it is committed when the
+    /// operation returns `Ok` and rolled back when it returns `Err`.
+    /// Values used by the operation have to be moved into it.
+    ///
+    /// ```ignore
+    /// Store::transaction(&pool, |tx| Box::pin(async move {
+    ///     UserPGStore::default().insert(&mut **tx, user_json).await?;
+    ///     TokenPGStore::default().insert(&mut **tx, token_json).await
+    /// })).await?;
+    /// ```
+    pub async fn transaction<DB, T, F>(connection: &Pool<DB>, operation: F) -> Result<T, StoreError>
+    where
+        DB: Database,
+        F: for<'c> FnOnce(&'c mut Transaction<'static, DB>) -> TransactionFuture<'c, T>,
+    {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        match operation(&mut transaction).await {
+            Ok(result) => {
+                transaction.commit().await.map_err(StoreError::from)?;
+                Ok(result)
+            }
+            Err(e) => {
+                // the operation's error is what the caller needs, a failed
+                // rollback is only logged, the connection drops the transaction
+                if let Err(rollback_error) = transaction.rollback().await {
+                    log::error!("rollback failed after {}: {}", e, rollback_error);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl StoreError {
+    // Stable machine readable code, safe to hand out to API clients.
+    pub fn code(&self) -> &'static str {
         match self {
-            StoreError::SqlxError(e) => match e {
-                sqlx::Error::Database(dbe) if dbe.constraint() == Some("users_username_key") => {
-                    write!(f, "Store Error: username taken",)
+            StoreError::SqlxError(_) => "database_error",
+            StoreError::JsonError(_) => "json_error",
+            StoreError::UUIDError(_) => "uuid_error",
+            StoreError::NotFound => "not_found",
+            StoreError::MigrationError(_) => "migration_error",
+            StoreError::InvalidColumn { .. } => "invalid_column",
+            StoreError::InvalidFilter(_) => "invalid_filter",
+            StoreError::InvalidCursor => "invalid_cursor",
+            StoreError::TokenExpired => "token_expired",
+            StoreError::TokenBlacklisted => "token_blacklisted",
+            StoreError::TokenReused => "token_reused",
+            StoreError::UniqueViolation { .. } => "unique_violation",
+            StoreError::ForeignKeyViolation { .. } => "foreign_key_violation",
+            StoreError::InvalidInput { .. } => "invalid_input",
+            StoreError::Conflict { .. } => "conflict",
+            StoreError::RateLimited { .. } => "rate_limited",
+            StoreError::Unavailable(_) => "unavailable",
+            StoreError::Configuration(_) => "configuration_error",
+            StoreError::OtherError(_) => "other_error",
+        }
+    }
+
+    // HTTP status a web layer should answer with.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            StoreError::NotFound => 404,
+            StoreError::JsonError(_)
+            | StoreError::UUIDError(_)
+            | StoreError::InvalidColumn { .. }
+            | StoreError::InvalidFilter(_)
+            | StoreError::InvalidCursor => 400,
+            StoreError::TokenExpired | StoreError::TokenBlacklisted | StoreError::TokenReused => 401,
+            StoreError::UniqueViolation { .. } | StoreError::Conflict { .. } => 409,
+            StoreError::ForeignKeyViolation { .. } | StoreError::InvalidInput { .. } => 422,
+            StoreError::RateLimited { .. } => 429,
+            StoreError::Unavailable(_) => 503,
+            StoreError::SqlxError(_)
+            | StoreError::MigrationError(_)
+            | StoreError::Configuration(_)
+            | StoreError::OtherError(_) => 500,
+        }
+    }
+
+    // Wraps errors that are not Send + Sync, keeping only their message.
+    pub fn other<E: std::fmt::Display + ?Sized>(error: &E) -> StoreError {
+        StoreError::OtherError(error.to_string().into())
+    }
+}
+
+// Classifies database errors by SQLSTATE so callers can match on variants
+// instead of messages, anything unknown stays a SqlxError.
+impl From<sqlx::Error> for StoreError {
+    fn from(error: sqlx::Error) -> Self {
+        let dbe = match &error {
+            sqlx::Error::RowNotFound => return StoreError::NotFound,
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => return StoreError::Unavailable(error),
+            sqlx::Error::Database(dbe) => dbe,
+            _ => return StoreError::SqlxError(error),
+        };
+        let code = dbe.code().map(|code| code.into_owned()).unwrap_or_default();
+        let constraint = dbe.constraint().unwrap_or_default().to_string();
+        let (kind, message) = (dbe.kind(), dbe.message().to_string());
+        // not null violations name the column rather than a constraint, SQLite
+        // names neither and only lists the table.column pairs in the message
+        let field = dbe
+            .try_downcast_ref::<PgDatabaseError>()
+            .and_then(|pge| pge.column())
+            .map(String::from)
+            .or_else(|| message_field(dbe.message()).filter(|_| constraint.is_empty()))
+            .unwrap_or_else(|| constraint_field(&constraint, dbe.table()));
+        // the classified errors keep the database error as their source
+        match kind {
+            ErrorKind::UniqueViolation => {
+                return StoreError::UniqueViolation {
+                    field,
+                    source: Some(Box::new(error)),
                 }
-                sqlx::Error::Database(dbe) if dbe.constraint() == Some("users_email_key") => {
-                    write!(f, "Store Error: email taken",)
+            }
+            ErrorKind::ForeignKeyViolation => {
+                return StoreError::ForeignKeyViolation {
+                    constraint,
+                    source: Some(Box::new(error)),
                 }
-                _ => {
-                    write!(f, "Store Error: {}", e)
+            }
+            ErrorKind::NotNullViolation | ErrorKind::CheckViolation => {
+                return StoreError::InvalidInput {
+                    field,
+                    reason: message,
+                    source: Some(Box::new(error)),
                 }
+            }
+            _ => {}
+        }
+        match code.as_str() {
+            // data exception class
+            _ if code.starts_with("22") => StoreError::InvalidInput {
+                field,
+                reason: message,
+                source: Some(Box::new(error)),
+            },
+            // serialization_failure, deadlock_detected
+            "40001" | "40P01" => StoreError::Conflict {
+                reason: message,
+                source: Some(Box::new(error)),
             },
+            // connection exceptions, insufficient resources, operator intervention
+            _ if code.starts_with("08") || code.starts_with("53") || code.starts_with("57P") => {
+                StoreError::Unavailable(error)
+            }
+            _ => StoreError::SqlxError(error),
+        }
+    }
+}
+
+// "UNIQUE constraint failed: users.username" names the username column,
+// a named check constraint is reported as is.
+fn message_field(message: &str) -> Option<String> {
+    let (_, failed) = message.split_once("constraint failed: ")?;
+    let first = failed.split(", ").next()?;
+    let field = first.rsplit('.').next()?;
+    Some(constraint_field(field, None))
+}
+
+// users_username_key on table users names the username column.
+fn constraint_field(constraint: &str, table: Option<&str>) -> String {
+    let field = match table {
+        Some(table) => constraint
+            .strip_prefix(table)
+            .and_then(|rest| rest.strip_prefix('_'))
+            .unwrap_or(constraint),
+        None => constraint,
+    };
+    ["_key", "_fkey", "_check", "_not_null"]
+        .iter()
+        .find_map(|suffix| field.strip_suffix(suffix))
+        .unwrap_or(field)
+        .to_string()
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StoreError::SqlxError(e) => write!(f, "Store Error: {}", e),
             StoreError::NotFound => write!(f, "NotFound"),
             StoreError::JsonError(e) => write!(f, "JSon Error: {}", e),
             StoreError::UUIDError(e) => write!(f, "UUID Error: {}", e),
+            StoreError::MigrationError(e) => write!(f, "Migration Error: {}", e),
+            StoreError::InvalidColumn { column, reason } => {
+                write!(f, "Invalid Column: {}, {}", column, reason)
+            }
+            StoreError::InvalidFilter(e) => write!(f, "Invalid Filter: {}", e),
+            StoreError::InvalidCursor => write!(f, "Invalid Cursor"),
+            StoreError::TokenExpired => write!(f, "Token Expired"),
+            StoreError::TokenBlacklisted => write!(f, "Token Blacklisted"),
+            StoreError::TokenReused => write!(f, "Token Reused"),
+            StoreError::UniqueViolation { field, .. } => write!(f, "Store Error: {} taken", field),
+            StoreError::ForeignKeyViolation { constraint, .. } => {
+                write!(f, "Store Error: foreign key {} violated", constraint)
+            }
+            StoreError::InvalidInput { field, reason, .. } => {
+                write!(f, "Invalid Input: {}, {}", field, reason)
+            }
+            StoreError::Conflict { reason, .. } => write!(f, "Conflict: {}", reason),
+            StoreError::RateLimited { until } => write!(f, "Rate Limited until {}", until),
+            StoreError::Unavailable(e) => write!(f, "Unavailable: {}", e),
+            StoreError::Configuration(e) => write!(f, "Configuration Error: {}", e),
             StoreError::OtherError(e) => write!(f, "Other Error: {}", e),
         }
     }
 }
 
-impl std::error::Error for StoreError {}
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::SqlxError(e) | StoreError::Unavailable(e) => Some(e),
+            StoreError::JsonError(e) => Some(e),
+            StoreError::UUIDError(e) => Some(e),
+            StoreError::MigrationError(e) => Some(e),
+            StoreError::OtherError(e) => Some(e.as_ref()),
+            StoreError::UniqueViolation { source, .. }
+            | StoreError::ForeignKeyViolation { source, .. }
+            | StoreError::InvalidInput { source, .. }
+            | StoreError::Conflict { source, .. } => source.as_deref().map(|e| e as &(dyn std::error::Error + 'static)),
+            _ => None,
+        }
+    }
+}
+
+// Fails with Conflict when a row read at expected has been written since and
+// is at current now. A missing row is left to the write to report.
+pub(crate) fn check_version(current: Option<i64>, expected: Option<i64>) -> Result<(), StoreError> {
+    match (current, expected) {
+        (Some(current), Some(expected)) if current != expected => Err(StoreError::Conflict {
+            reason: format!("expected version {}, the row is at version {}", expected, current),
+            source: None,
+        }),
+        _ => Ok(()),
+    }
+}
 
 // impl From<io::Error> for StoreError {
 //     fn from(error: io::Error) -> Self {
@@ -58,54 +327,132 @@ impl std::error::Error for StoreError {}
 //     }
 // }
 
-pub trait StoreTrait {
+// Implemented once per backend connection type. The Postgres stores accept a
+// `&Pool<Postgres>` or a `&mut PgConnection`, the latter also being how an open
+// transaction is passed in (`&mut *tx`), so several calls can share it.
+// insert, update and patch hand back the stored row; update, patch and delete
+// fail with NotFound when no row has the given id. Every backend soft deletes
+// and hides soft deleted rows unless the store is built with include_deleted.
+pub trait StoreTrait<C> {
     fn insert(
         &self,
-        connection: &Pool<Postgres>,
+        connection: C,
         item: serde_json::Value,
-    ) -> impl std::future::Future<Output = Result<(), StoreError>> + Send;
+    ) -> impl std::future::Future<Output = Result<serde_json::Value, StoreError>> + Send;
     fn get(
         &self,
-        connection: &Pool<Postgres>,
+        connection: C,
         id: Uuid,
     ) -> impl std::future::Future<Output = Result<Vec<serde_json::Value>, StoreError>> + Send;
     fn get_all_paginate(
         &self,
-        connection: &Pool<Postgres>,
+        connection: C,
         limit: i64,
         offset: i64,
     ) -> impl std::future::Future<Output = Result<Vec<serde_json::Value>, StoreError>> + Send;
+    fn get_page(
+        &self,
+        connection: C,
+        request: PageRequest,
+    ) -> impl std::future::Future<Output = Result<Page<serde_json::Value>, StoreError>> + Send;
     fn count(
         &self,
-        connection: &Pool<Postgres>,
+        connection: C,
     ) -> impl std::future::Future<Output = Result<usize, StoreError>> + Send;
     //async fn get_many_by_slug(&self,connection:&Pool<Postgres> ,json_slug:serde_json::Value)->Result<Vec<serde_json::Value>,StoreError>;
     fn get_by_slug(
         &self,
-        connection: &Pool<Postgres>,
+        connection: C,
         json_slug: serde_json::Value,
     ) -> impl std::future::Future<Output = Result<Vec<serde_json::Value>, StoreError>> + Send;
     fn delete(
         &self,
-        connection: &Pool<Postgres>,
+        connection: C,
         id: Uuid,
     ) -> impl std::future::Future<Output = Result<(), StoreError>> + Send;
     fn update(
         &self,
-        connection: &Pool<Postgres>,
+        connection: C,
         id: Uuid,
         item: serde_json::Value,
-    ) -> impl std::future::Future<Output = Result<(), StoreError>> + Send;
+    ) -> impl std::future::Future<Output = Result<serde_json::Value, StoreError>> + Send;
     fn patch(
         &self,
-        connection: &Pool<Postgres>,
+        connection: C,
         id: Uuid,
         patch: serde_json::Value,
-    ) -> impl std::future::Future<Output = Result<(), StoreError>> + Send;
+    ) -> impl std::future::Future<Output = Result<serde_json::Value, StoreError>> + Send;
+}
+
+// Typed counterpart of StoreTrait, trading the store's entity and row structs
+// instead of serde_json::Value. The JSON StoreTrait implementations of the SQL
+// stores are thin adapters on top of it; count and delete stay on StoreTrait.
+// DB is the database the store runs against, Postgres unless stated.
+pub trait TypedStore<Entity, Id, DB: Database = Postgres> {
+    type Row: Into<Entity> + Serialize;
+
+    fn insert_row(
+        &self,
+        connection: &mut DB::Connection,
+        item: Entity,
+    ) -> impl std::future::Future<Output = Result<Self::Row, StoreError>> + Send;
+    fn get_row(
+        &self,
+        connection: &mut DB::Connection,
+        id: Id,
+    ) -> impl std::future::Future<Output = Result<Option<Self::Row>, StoreError>> + Send;
+    fn get_rows_paginate(
+        &self,
+        connection: &mut DB::Connection,
+        limit: i64,
+        offset: i64,
+    ) -> impl std::future::Future<Output = Result<Vec<Self::Row>, StoreError>> + Send;
+    fn get_rows_page(
+        &self,
+        connection: &mut DB::Connection,
+        request: &PageRequest,
+    ) -> impl std::future::Future<Output = Result<Page<Self::Row>, StoreError>> + Send;
+    fn get_rows_by_slug(
+        &self,
+        connection: &mut DB::Connection,
+        json_slug: serde_json::Value,
+    ) -> impl std::future::Future<Output = Result<Vec<Self::Row>, StoreError>> + Send;
+    fn get_rows_by_filter(
+        &self,
+        connection: &mut DB::Connection,
+        filter: &FilterQuery,
+    ) -> impl std::future::Future<Output = Result<Vec<Self::Row>, StoreError>> + Send;
+    fn update_row(
+        &self,
+        connection: &mut DB::Connection,
+        id: Id,
+        item: Entity,
+    ) -> impl std::future::Future<Output = Result<Self::Row, StoreError>> + Send;
+    fn patch_row(
+        &self,
+        connection: &mut DB::Connection,
+        id: Id,
+        patch: serde_json::Value,
+    ) -> impl std::future::Future<Output = Result<Self::Row, StoreError>> + Send;
+}
+
+// Postgres specific helpers shared by the query building code of the PG stores.
+pub trait PgJsonTrait {
+    fn columns(&self) -> &'static ColumnRegistry;
+    // Binds the values of a JSON object in key order, each one as the type of its column.
     fn bind_values<'a>(
         &self,
         custom_query: Query<'a, Postgres, PgArguments>,
         json_value: &'a serde_json::Value,
-    ) -> Result<Query<'a, Postgres, PgArguments>, StoreError>;
+    ) -> Result<Query<'a, Postgres, PgArguments>, StoreError> {
+        let mut custom_query = custom_query;
+        if let serde_json::Value::Object(map) = json_value {
+            for (key, value) in map {
+                let column = self.columns().column(key)?;
+                custom_query = bind_json_value(custom_query, column.sql_type, &column.stored_value(value)?)?;
+            }
+        }
+        Ok(custom_query)
+    }
     fn row_to_json(&self, row: &PgRow) -> Result<serde_json::Value, sqlx::Error>;
 }
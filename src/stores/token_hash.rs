@@ -0,0 +1,53 @@
+use crate::stores::store::StoreError;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::OnceLock;
+
+type HmacSha256 = Hmac<Sha256>;
+
+static TOKEN_HASHER: OnceLock<TokenHasher> = OnceLock::new();
+
+// Marks a stored token_string as hashed, rows without it are plaintext left
+// from before hashing and are rewritten by TokenPGStore::hash_plaintext_tokens.
+pub const TOKEN_HASH_PREFIX: &str = "hmac-sha256:";
+
+// Keyed hash of the token strings kept in the tokens table, so a leaked
+// table does not leak usable tokens. Presented tokens are hashed the same
+// way to look them up.
+pub struct TokenHasher {
+    secret: Vec<u8>,
+}
+
+impl TokenHasher {
+    pub fn new(secret: &[u8]) -> Self {
+        TokenHasher {
+            secret: secret.to_vec(),
+        }
+    }
+
+    // Shared hasher keyed by STORE_TOKEN_SECRET. There is no fallback key, a
+    // random one would lose every stored token on restart and differ between
+    // instances, so token writes and lookups fail with Configuration instead.
+    pub fn global() -> Result<&'static TokenHasher, StoreError> {
+        if let Some(hasher) = TOKEN_HASHER.get() {
+            return Ok(hasher);
+        }
+        let secret = dotenvy::var("STORE_TOKEN_SECRET")
+            .map_err(|_| StoreError::Configuration(String::from("STORE_TOKEN_SECRET is not set")))?;
+        Ok(TOKEN_HASHER.get_or_init(|| TokenHasher::new(secret.as_bytes())))
+    }
+
+    pub fn hash(&self, token: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC can take a key of any size");
+        mac.update(token.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let hex = digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        format!("{}{}", TOKEN_HASH_PREFIX, hex)
+    }
+}
+
+// Random token for the single use tokens the store issues itself.
+pub fn random_token() -> String {
+    let charset = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    random_string::generate(43, charset)
+}
@@ -0,0 +1,480 @@
+use crate::stores::columns::{Dialect, QueryParams, SqlType};
+use crate::stores::cursor::{Page, PageRequest};
+use crate::stores::filter::{Filter, FilterQuery};
+use crate::stores::store::{StoreError, StoreTrait, TypedStore};
+use crate::stores::token_hash::TokenHasher;
+use crate::stores::token_store::{StoredToken, TokenRow, TOKEN_COLUMNS};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use token_lib::token::token::Token;
+use uuid::Uuid;
+
+// SQLite counterpart of TokenPGStore, on the schema in ./migrations_sqlite.
+// Ids are created here and token_type is stored as text. Deletes are soft
+// as in TokenPGStore.
+#[derive(Debug, Default)]
+pub struct TokenSqliteStore {
+    // whether reads and writes also see soft deleted tokens
+    include_deleted: bool,
+}
+
+impl TokenSqliteStore {
+    // Makes the store see soft deleted tokens as well.
+    pub fn include_deleted(mut self) -> Self {
+        self.include_deleted = true;
+        self
+    }
+
+    // Narrows a filter to the tokens this store sees.
+    fn visible(&self, filter: &Filter) -> Filter {
+        if self.include_deleted {
+            filter.clone()
+        } else {
+            filter.clone().not_deleted()
+        }
+    }
+
+    // Inserts a token that stops being valid once expires_at has passed, None
+    // for a token that never expires.
+    pub async fn insert_expiring(
+        &self,
+        connection: &mut SqliteConnection,
+        token_obj: Token,
+        expires_at: Option<NaiveDateTime>,
+    ) -> Result<TokenRow, StoreError> {
+        self.insert_for_user(connection, None, token_obj, expires_at).await
+    }
+
+    // Inserts a token owned by user_id, it is deleted along with the user.
+    pub async fn insert_for_user(
+        &self,
+        connection: &mut SqliteConnection,
+        user_id: Option<Uuid>,
+        token_obj: Token,
+        expires_at: Option<NaiveDateTime>,
+    ) -> Result<TokenRow, StoreError> {
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let row = sqlx::query_as::<_, TokenRow>(
+            r#"
+                    insert into "tokens"(id,token_string,token_type,blacklisted,created_at,updated_at,expires_at,user_id)
+                    values ($1, $2, $3, $4, $5, $5, $6, $7)
+                    returning *"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(TokenHasher::global()?.hash(token_obj.get_token()))
+        .bind(token_obj.get_type())
+        .bind(token_obj.get_blacklisted())
+        .bind(naive_now)
+        .bind(expires_at)
+        .bind(user_id)
+        .fetch_one(&mut *connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    pub async fn delete_by_token(&self, connection: &mut SqliteConnection, token_string: String) -> Result<(), StoreError> {
+        sqlx::query(r#"update tokens set deleted_at = $1 where token_string = $2 and deleted_at is null"#)
+            .bind(Utc::now().naive_utc())
+            .bind(TokenHasher::global()?.hash(&token_string))
+            .execute(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(())
+    }
+
+    // Writes back a token read from the table, its token_string kept as it
+    // is, where update_row would hash it as a presented token.
+    pub async fn update_stored_row(
+        &self,
+        connection: &mut SqliteConnection,
+        id: Uuid,
+        stored: StoredToken,
+    ) -> Result<TokenRow, StoreError> {
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let row = sqlx::query_as::<_, TokenRow>(
+            r#"
+                update tokens set token_string = $1, token_type = $2, blacklisted = $3, updated_at = $4, version = version + 1 where id=$5 and ($6 or deleted_at is null)
+                returning *"#,
+        )
+        .bind(stored.token_string)
+        .bind(stored.token_type)
+        .bind(stored.blacklisted)
+        .bind(naive_now)
+        .bind(id)
+        .bind(self.include_deleted)
+        .fetch_optional(&mut *connection)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        Ok(row)
+    }
+
+    // Brings back a soft deleted token, NotFound unless it is soft deleted.
+    pub async fn restore(&self, connection: &mut SqliteConnection, id: Uuid) -> Result<TokenRow, StoreError> {
+        let row = sqlx::query_as::<_, TokenRow>(
+            r#"
+                    update tokens set deleted_at = null, updated_at = $1, version = version + 1 where id = $2 and deleted_at is not null
+                    returning *"#,
+        )
+        .bind(Utc::now().naive_utc())
+        .bind(id)
+        .fetch_optional(&mut *connection)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        Ok(row)
+    }
+
+    // Removes the token for good, soft deleted or not.
+    pub async fn hard_delete(&self, connection: &mut SqliteConnection, id: Uuid) -> Result<(), StoreError> {
+        let result = sqlx::query(r#"delete from tokens where id=$1"#)
+            .bind(id)
+            .execute(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        if result.rows_affected() == 0 {
+            return Err(StoreError::NotFound);
+        }
+        Ok(())
+    }
+}
+
+impl TypedStore<Token, Uuid, Sqlite> for TokenSqliteStore {
+    type Row = TokenRow;
+
+    async fn insert_row(&self, connection: &mut SqliteConnection, token_obj: Token) -> Result<TokenRow, StoreError> {
+        self.insert_expiring(connection, token_obj, None).await
+    }
+
+    async fn get_row(&self, connection: &mut SqliteConnection, id: Uuid) -> Result<Option<TokenRow>, StoreError> {
+        let row = sqlx::query_as::<_, TokenRow>(r#"SELECT * FROM tokens WHERE id = $1 and ($2 or deleted_at is null)"#)
+            .bind(id)
+            .bind(self.include_deleted)
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    async fn update_row(
+        &self,
+        connection: &mut SqliteConnection,
+        id: Uuid,
+        token_data: Token,
+    ) -> Result<TokenRow, StoreError> {
+        self.update_stored_row(connection, id, StoredToken::hashed(&token_data)?).await
+    }
+
+    async fn get_rows_page(
+        &self,
+        connection: &mut SqliteConnection,
+        request: &PageRequest,
+    ) -> Result<Page<TokenRow>, StoreError> {
+        let mut params = QueryParams::new(Dialect::Sqlite);
+        let request = request.clone().filter(self.visible(&request.filter));
+        let (clause, backwards) = request.to_sql(&TOKEN_COLUMNS, &mut params)?;
+        let custom_query = format!("SELECT * FROM {} {}", TOKEN_COLUMNS.table(), clause);
+        log::debug!("final query is: {custom_query}");
+        let rows = params
+            .bind(sqlx::query(&custom_query))?
+            .try_map(|row| sqlx::FromRow::from_row(&row))
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        request.to_page(rows, backwards)
+    }
+
+    async fn get_rows_by_slug(
+        &self,
+        connection: &mut SqliteConnection,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<TokenRow>, StoreError> {
+        let filter = FilterQuery::from_json(&json_slug)?;
+        self.get_rows_by_filter(connection, &filter).await
+    }
+
+    async fn get_rows_by_filter(
+        &self,
+        connection: &mut SqliteConnection,
+        filter: &FilterQuery,
+    ) -> Result<Vec<TokenRow>, StoreError> {
+        let filter = FilterQuery {
+            filter: self.visible(&filter.filter),
+            ..filter.clone()
+        };
+        let mut params = QueryParams::new(Dialect::Sqlite);
+        let custom_query = format!(
+            "SELECT * FROM {} {}",
+            TOKEN_COLUMNS.table(),
+            filter.to_sql(&TOKEN_COLUMNS, &mut params)?
+        );
+        log::debug!("final query is: {custom_query}");
+        let rows = params
+            .bind(sqlx::query(&custom_query))?
+            .try_map(|row| sqlx::FromRow::from_row(&row))
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    async fn get_rows_paginate(
+        &self,
+        connection: &mut SqliteConnection,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<TokenRow>, StoreError> {
+        let rows = sqlx::query_as::<_, TokenRow>(r#"SELECT * FROM tokens where $3 or deleted_at is null order by created_at asc, id asc limit $1 offset $2"#)
+            .bind(limit)
+            .bind(offset)
+            .bind(self.include_deleted)
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    async fn patch_row(
+        &self,
+        connection: &mut SqliteConnection,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<TokenRow, StoreError> {
+        let mut params = QueryParams::new(Dialect::Sqlite);
+        let mut conditions = Vec::new();
+        if let serde_json::Value::Object(map) = &patch {
+            for (key, value) in map {
+                let column = TOKEN_COLUMNS.patchable(key)?;
+                let placeholder = params.push(column.sql_type, column.stored_value(value)?);
+                conditions.push(format!("{} = {}", column.quoted(), placeholder));
+            }
+        } else {
+            log::debug!("The JSON data is not an object");
+            return Err(StoreError::NotFound);
+        }
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let placeholder = params.push(SqlType::Timestamp, serde_json::json!(naive_now));
+        conditions.push(format!(r#""updated_at" = {}"#, placeholder));
+        conditions.push(String::from(r#""version" = "version" + 1"#));
+        let custom_query = format!(
+            "UPDATE {} SET {} WHERE id = {} AND ({} OR deleted_at IS NULL) RETURNING *",
+            TOKEN_COLUMNS.table(),
+            conditions.join(" , "),
+            params.push(SqlType::Uuid, serde_json::json!(id)),
+            params.push(SqlType::Bool, serde_json::json!(self.include_deleted))
+        );
+        log::debug!("final query is: {custom_query}");
+        let row = params
+            .bind(sqlx::query(&custom_query))?
+            .try_map(|row| sqlx::FromRow::from_row(&row))
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(StoreError::from)?
+            .ok_or(StoreError::NotFound)?;
+        Ok(row)
+    }
+}
+
+impl<'c> StoreTrait<&'c mut SqliteConnection> for TokenSqliteStore {
+    async fn insert(
+        &self,
+        connection: &'c mut SqliteConnection,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let expires_at: Option<NaiveDateTime> =
+            serde_json::from_value(item.get("expires_at").cloned().unwrap_or_default()).map_err(StoreError::JsonError)?;
+        let user_id: Option<Uuid> =
+            serde_json::from_value(item.get("user_id").cloned().unwrap_or_default()).map_err(StoreError::JsonError)?;
+        let token_obj: Token = serde_json::from_value(item).map_err(StoreError::JsonError)?;
+        let row = self.insert_for_user(connection, user_id, token_obj, expires_at).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
+    }
+
+    async fn get(
+        &self,
+        connection: &'c mut SqliteConnection,
+        id: Uuid,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let rows = self.get_row(connection, id).await?;
+        let token_datas = rows
+            .iter()
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
+            .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
+        Ok(token_datas)
+    }
+
+    // Soft deletes the token, see restore and hard_delete.
+    async fn delete(&self, connection: &'c mut SqliteConnection, id: Uuid) -> Result<(), StoreError> {
+        let result = sqlx::query(r#"update tokens set deleted_at = $1 where id = $2 and deleted_at is null"#)
+            .bind(Utc::now().naive_utc())
+            .bind(id)
+            .execute(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        if result.rows_affected() == 0 {
+            return Err(StoreError::NotFound);
+        }
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        connection: &'c mut SqliteConnection,
+        id: Uuid,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let token_data: Token = serde_json::from_value(item).map_err(StoreError::JsonError)?;
+        let row = self.update_row(connection, id, token_data).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
+    }
+
+    async fn get_by_slug(
+        &self,
+        connection: &'c mut SqliteConnection,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let rows = self.get_rows_by_slug(connection, json_slug).await?;
+        let token_datas = rows
+            .iter()
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
+            .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
+        Ok(token_datas)
+    }
+
+    async fn get_page(
+        &self,
+        connection: &'c mut SqliteConnection,
+        request: PageRequest,
+    ) -> Result<Page<serde_json::Value>, StoreError> {
+        let page = self.get_rows_page(connection, &request).await?;
+        let token_datas = page
+            .items
+            .iter()
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
+            .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
+        Ok(Page {
+            items: token_datas,
+            next_cursor: page.next_cursor,
+            prev_cursor: page.prev_cursor,
+        })
+    }
+
+    async fn count(&self, connection: &'c mut SqliteConnection) -> Result<usize, StoreError> {
+        let count: i64 = sqlx::query_scalar(r#"SELECT COUNT(id) FROM tokens WHERE $1 OR deleted_at IS NULL"#)
+            .bind(self.include_deleted)
+            .fetch_one(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(count as usize)
+    }
+
+    async fn get_all_paginate(
+        &self,
+        connection: &'c mut SqliteConnection,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let rows = self.get_rows_paginate(connection, limit, offset).await?;
+        let token_datas = rows
+            .iter()
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
+            .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
+        Ok(token_datas)
+    }
+
+    async fn patch(
+        &self,
+        connection: &'c mut SqliteConnection,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let row = self.patch_row(connection, id, patch).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
+    }
+}
+
+impl<'c> StoreTrait<&'c Pool<Sqlite>> for TokenSqliteStore {
+    async fn insert(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.insert(&mut *conn, item).await
+    }
+
+    async fn get(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        id: Uuid,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get(&mut *conn, id).await
+    }
+
+    async fn delete(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        id: Uuid,
+    ) -> Result<(), StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.delete(&mut *conn, id).await
+    }
+
+    async fn update(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        id: Uuid,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.update(&mut *conn, id, item).await
+    }
+
+    async fn get_by_slug(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_by_slug(&mut *conn, json_slug).await
+    }
+
+    async fn get_page(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        request: PageRequest,
+    ) -> Result<Page<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_page(&mut *conn, request).await
+    }
+
+    async fn count(
+        &self,
+        connection: &'c Pool<Sqlite>,
+    ) -> Result<usize, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.count(&mut *conn).await
+    }
+
+    async fn get_all_paginate(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_all_paginate(&mut *conn, limit, offset).await
+    }
+
+    async fn patch(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.patch(&mut *conn, id, patch).await
+    }
+}
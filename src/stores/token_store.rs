@@ -1,29 +1,120 @@
 use std::str::FromStr;
 
-use crate::stores::store::{StoreError, StoreTrait};
-use chrono::{NaiveDateTime, Utc};
+use crate::stores::audit::{AuditOperation, AuditSink, Auditor};
+use crate::stores::columns::{ColumnDef, ColumnRegistry, QueryParams, SqlType};
+use crate::stores::cursor::{Page, PageRequest};
+use crate::stores::filter::{Filter, FilterQuery};
+use crate::stores::store::{check_version, PgJsonTrait, StoreError, StoreTrait, TypedStore};
+use crate::stores::token_hash::{random_token, TokenHasher, TOKEN_HASH_PREFIX};
+use chrono::{Duration, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::{
-    postgres::PgArguments,
     query::{self, QueryAs},
-    Execute, Pool, Postgres,
+    Connection, Execute, Executor, PgConnection, Pool, Postgres,
 };
 use token_lib::token::token::{Token, TokenType};
+use tokio::task::JoinHandle;
 use user_lib::user::user::{User, UserRoles};
 use uuid::Uuid;
-use sqlx::query::Query;
 use sqlx::postgres::PgRow;
+use sqlx::FromRow;
 use sqlx::Row;
 use sqlx::Column;
 use sqlx::TypeInfo;
+use std::collections::HashMap;
+use std::sync::Arc;
 #[derive(Debug, Deserialize, sqlx::FromRow, Serialize, Clone)]
 pub struct TokenRow {
     pub id: Uuid,
+    // keyed hash of the token, see TokenHasher
     pub token_string: String,
     pub token_type: TokenType,
     pub blacklisted: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    // None for tokens that never expire
+    pub expires_at: Option<NaiveDateTime>,
+    // None for tokens that are not tied to a user
+    pub user_id: Option<Uuid>,
+    // rotation chain the token belongs to, None until it is first rotated
+    pub family_id: Option<Uuid>,
+    // successor of a rotated token
+    pub replaced_by: Option<Uuid>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub revocation_reason: Option<RevocationReason>,
+    pub purpose: TokenPurpose,
+    // set while the token is soft deleted, it is unusable until restored
+    pub deleted_at: Option<NaiveDateTime>,
+    // bumped by every write, see update_if_version
+    pub version: i64,
+}
+
+// What a token may be used for. Only sessions are accepted by get_valid and
+// rotation, the others are single use and consumed by their workflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[sqlx(type_name = "token_purpose", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPurpose {
+    Session,
+    EmailConfirmation,
+    PasswordReset,
+}
+
+// Why a token was revoked, stored next to revoked_at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[sqlx(type_name = "revocation_reason", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum RevocationReason {
+    Logout,
+    PasswordChange,
+    Admin,
+    ReuseDetected,
+}
+
+// A token for insert_many, owned by user_id and expiring at expires_at as
+// with insert_for_user.
+#[derive(Debug, Clone)]
+pub struct NewToken {
+    pub token: Token,
+    pub user_id: Option<Uuid>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl From<Token> for NewToken {
+    fn from(token: Token) -> Self {
+        NewToken {
+            token,
+            user_id: None,
+            expires_at: None,
+        }
+    }
+}
+
+// Unique columns upsert can detect an existing token by.
+const UPSERT_TARGETS: &[&str] = &["token_string"];
+// Columns upsert inserts, besides the conflict target, and so may overwrite.
+const UPSERT_COLUMNS: &[&str] = &["token_type", "blacklisted", "expires_at", "user_id"];
+
+// Batched maintenance loops stop on a short batch, which an empty batch never
+// is, so they need at least one row per batch.
+fn check_batch_size(batch_size: i64) -> Result<(), StoreError> {
+    if batch_size < 1 {
+        return Err(StoreError::InvalidInput {
+            field: String::from("batch_size"),
+            reason: String::from("batch_size must be at least 1"),
+            source: None,
+        });
+    }
+    Ok(())
+}
+
+// The token_type label of a type, bulk writes bind types as text arrays as
+// sqlx only binds arrays of enums declared in this crate.
+fn type_label(token_type: TokenType) -> &'static str {
+    match token_type {
+        TokenType::AccessToken => "access_token",
+        TokenType::RefreshToken => "refresh_token",
+    }
 }
 
 impl Into<Token> for TokenRow {
@@ -37,73 +128,1201 @@ impl Into<Token> for TokenRow {
     }
 }
 
+// A token as read back from the tokens table, its token_string already the
+// keyed hash. Only a TokenRow makes one, writes of a Token always hash it, so
+// writing a row back is kept apart from writing a presented token.
+#[derive(Debug, Clone)]
+pub struct StoredToken {
+    pub(crate) token_string: String,
+    pub(crate) token_type: TokenType,
+    pub(crate) blacklisted: bool,
+}
+
+impl StoredToken {
+    // The stored form of a presented token.
+    pub(crate) fn hashed(token: &Token) -> Result<StoredToken, StoreError> {
+        Ok(StoredToken {
+            token_string: TokenHasher::global()?.hash(token.get_token()),
+            token_type: token.get_type(),
+            blacklisted: token.get_blacklisted(),
+        })
+    }
+}
+
+impl From<TokenRow> for StoredToken {
+    fn from(row: TokenRow) -> Self {
+        StoredToken {
+            token_string: row.token_string,
+            token_type: row.token_type,
+            blacklisted: row.blacklisted,
+        }
+    }
+}
+
 #[derive(Debug, Default)]
-pub struct TokenPGStore;
+pub struct TokenPGStore {
+    // whether reads and writes also see soft deleted tokens
+    include_deleted: bool,
+    auditor: Auditor,
+}
+
+pub static TOKEN_COLUMNS: ColumnRegistry = ColumnRegistry::new(
+    "tokens",
+    &[
+        ColumnDef::new("id", SqlType::Uuid, true, false),
+        ColumnDef::new("token_string", SqlType::Text, true, true).hashed(),
+        ColumnDef::new("token_type", SqlType::TokenType, true, true),
+        // patching it would revoke a token without revoked_at and a reason
+        ColumnDef::new("blacklisted", SqlType::Bool, true, false).written_through("is written through revoke"),
+        ColumnDef::new("created_at", SqlType::Timestamp, true, false),
+        ColumnDef::new("updated_at", SqlType::Timestamp, true, false),
+        ColumnDef::new("expires_at", SqlType::Timestamp, true, true).nullable(),
+        ColumnDef::new("user_id", SqlType::Uuid, true, false).nullable(),
+        ColumnDef::new("family_id", SqlType::Uuid, true, false).nullable(),
+        ColumnDef::new("replaced_by", SqlType::Uuid, true, false).nullable(),
+        ColumnDef::new("revoked_at", SqlType::Timestamp, true, false).nullable(),
+        ColumnDef::new("revocation_reason", SqlType::RevocationReason, true, false).nullable(),
+        ColumnDef::new("purpose", SqlType::TokenPurpose, true, false),
+        ColumnDef::new("deleted_at", SqlType::Timestamp, true, false).nullable(),
+        ColumnDef::new("version", SqlType::BigInt, true, false),
+    ],
+);
+
 impl TokenPGStore{
-    pub async fn delete_by_token(&self, connection: &Pool<Postgres>, token_string: String) -> Result<(), StoreError> {
-        sqlx::query!(
+    // Makes the store see soft deleted tokens as well.
+    pub fn include_deleted(mut self) -> Self {
+        self.include_deleted = true;
+        self
+    }
+
+    // Records writes through sink instead of into the audit_log table.
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.auditor.sink = sink;
+        self
+    }
+
+    // Records writes as made by the user actor_id.
+    pub fn acting_as(mut self, actor_id: Uuid) -> Self {
+        self.auditor.actor_id = Some(actor_id);
+        self
+    }
+
+    // The row as it is before a write, locked until the write commits.
+    async fn locked_row(&self, connection: &mut PgConnection, id: Uuid) -> Result<Option<TokenRow>, StoreError> {
+        let row = sqlx::query_as!(TokenRow, r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE id = $1 for update"#, id)
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Records the write of rows, each matched up with its locked before row.
+    async fn record_rows(
+        &self,
+        connection: &mut PgConnection,
+        operation: AuditOperation,
+        before: Vec<TokenRow>,
+        rows: &[TokenRow],
+    ) -> Result<(), StoreError> {
+        let before: HashMap<Uuid, TokenRow> = before.into_iter().map(|row| (row.id, row)).collect();
+        self.auditor
+            .record_many(
+                connection,
+                TOKEN_COLUMNS.name(),
+                operation,
+                rows.iter().map(|row| (row.id, before.get(&row.id), Some(row))).collect(),
+            )
+            .await
+    }
+
+    // Records writes with the sink and actor of another store, so writes a
+    // user store makes to tokens are attributed like its own.
+    pub(crate) fn audited_by(mut self, auditor: &Auditor) -> Self {
+        self.auditor = auditor.clone();
+        self
+    }
+
+    // Replaces the row only while it is still at expected_version, the
+    // version read along with it, and fails with Conflict once another
+    // write got in between.
+    pub async fn update_if_version(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        expected_version: i64,
+        token_data: Token,
+    ) -> Result<TokenRow, StoreError> {
+        self.update_checked(connection, id, StoredToken::hashed(&token_data)?, Some(expected_version)).await
+    }
+
+    // Writes back a token read from the table, its token_string kept as it
+    // is, where update_row would hash it as a presented token.
+    pub async fn update_stored_row(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        stored: StoredToken,
+    ) -> Result<TokenRow, StoreError> {
+        self.update_checked(connection, id, stored, None).await
+    }
+
+    // As update_if_version, for a patch.
+    pub async fn patch_if_version(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        expected_version: i64,
+        patch: serde_json::Value,
+    ) -> Result<TokenRow, StoreError> {
+        self.patch_checked(connection, id, patch, Some(expected_version)).await
+    }
+
+    // update_row, failing with Conflict unless the row is at expected_version.
+    async fn update_checked(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        stored: StoredToken,
+        expected_version: Option<i64>,
+    ) -> Result<TokenRow, StoreError> {
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?;
+        check_version(before.as_ref().map(|row| row.version), expected_version)?;
+        let row = sqlx::query_as!(
+            TokenRow,
             // language=PostgreSQL
             r#"
-                    delete from  tokens where token_string=$1"#,
-            token_string
+                update tokens set token_string = $1, token_type = $2, blacklisted = $3, updated_at = $4, version = version + 1 where id=$5 and ($6 or deleted_at is null)
+                returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            stored.token_string,
+            stored.token_type as TokenType,
+            stored.blacklisted,
+            naive_now,
+            id,
+            self.include_deleted
         )
-        .execute(connection)
+        .fetch_optional(&mut *transaction)
         .await
-        .map_err(StoreError::SqlxError)?;
-        Ok(())
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), id, AuditOperation::Update, before.as_ref(), Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // patch_row, failing with Conflict unless the row is at expected_version.
+    async fn patch_checked(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        patch: serde_json::Value,
+        expected_version: Option<i64>,
+    ) -> Result<TokenRow, StoreError> {
+        let mut custom_query: String = format!("UPDATE {} SET ", TOKEN_COLUMNS.table());
+        let mut conditions = Vec::new();
+        // Check if the parsed value is an object
+        if let serde_json::Value::Object(map) = &patch {
+            // Iterate over the key-value pairs in the object
+
+            for key in map.keys() {
+                let column = TOKEN_COLUMNS.patchable(key)?;
+                conditions.push(format!("{} = ${}", column.quoted(), conditions.len() + 1));
+            }
+        } else {
+            log::debug!("The JSON data is not an object");
+            return Err(StoreError::NotFound);
+        }
+        conditions.push(format!(r#""updated_at" = ${}"#, conditions.len() + 1));
+        let max_variable = conditions.len() + 1;
+        custom_query.push_str(&conditions.join(" , "));
+        custom_query.push_str(r#" , "version" = "version" + 1"#);
+        custom_query.push_str(
+            format!(" WHERE id = ${} AND (${} OR deleted_at IS NULL) RETURNING *", max_variable, max_variable + 1).as_str(),
+        );
+        log::debug!("final query is: {custom_query}");
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let mut custom_query = sqlx::query(&custom_query);
+        custom_query = self.bind_values(custom_query, &patch)?;
+        custom_query = custom_query.bind(naive_now);
+        custom_query = custom_query.bind(id);
+        custom_query = custom_query.bind(self.include_deleted);
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?;
+        check_version(before.as_ref().map(|row| row.version), expected_version)?;
+        let row = custom_query
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?
+            .ok_or(StoreError::NotFound)?;
+        let row = TokenRow::from_row(&row).map_err(StoreError::from)?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), id, AuditOperation::Patch, before.as_ref(), Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Narrows a filter to the tokens this store sees.
+    fn visible(&self, filter: &Filter) -> Filter {
+        if self.include_deleted {
+            filter.clone()
+        } else {
+            filter.clone().not_deleted()
+        }
+    }
+
+    // Inserts the tokens with a single statement and returns one result per
+    // token, in order. A token for a user that does not exist gets the
+    // ForeignKeyViolation insert_for_user would fail with and is left out,
+    // as is a token whose token_string is already taken, by a stored row or
+    // an earlier token of the batch, which gets a UniqueViolation.
+    pub async fn insert_many(
+        &self,
+        connection: &mut PgConnection,
+        tokens: Vec<NewToken>,
+    ) -> Result<Vec<Result<TokenRow, StoreError>>, StoreError> {
+        let hasher = TokenHasher::global()?;
+        let token_strings: Vec<String> = tokens.iter().map(|new| hasher.hash(new.token.get_token())).collect();
+        let token_types: Vec<&str> = tokens.iter().map(|new| type_label(new.token.get_type())).collect();
+        let blacklisted: Vec<bool> = tokens.iter().map(|new| new.token.get_blacklisted()).collect();
+        let expires_at: Vec<Option<NaiveDateTime>> = tokens.iter().map(|new| new.expires_at).collect();
+        let user_ids: Vec<Option<Uuid>> = tokens.iter().map(|new| new.user_id).collect();
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        // the users are locked so none can go away before the insert, tokens
+        // skipped on conflict come back without an inserted row
+        let rows = sqlx::query(
+            // language=PostgreSQL
+            r#"
+                    with input as (
+                        select gen_random_uuid() as id, input.* from unnest($1::varchar[], $2::varchar[]::token_type[], $3::bool[], $4::timestamp[], $5::uuid[])
+                            with ordinality as input(token_string, token_type, blacklisted, expires_at, user_id, position)
+                        where user_id is null or user_id in (select id from users where id = any($5) for key share)
+                    ), inserted as (
+                        insert into tokens(id, token_string, token_type, blacklisted, expires_at, user_id)
+                        select id, token_string, token_type, blacklisted, expires_at, user_id from input order by position
+                        on conflict (token_string) do nothing
+                        returning *
+                    )
+                    select inserted.*, input.position from input left join inserted on inserted.id = input.id"#,
+        )
+        .bind(&token_strings)
+        .bind(&token_types)
+        .bind(&blacklisted)
+        .bind(&expires_at)
+        .bind(&user_ids)
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        let mut results: Vec<Result<TokenRow, StoreError>> = (0..tokens.len())
+            .map(|_| {
+                Err(StoreError::ForeignKeyViolation {
+                    constraint: String::from("tokens_user_id_fkey"),
+                    source: None,
+                })
+            })
+            .collect();
+        for row in &rows {
+            let position: i64 = row.try_get("position").map_err(StoreError::from)?;
+            let id: Option<Uuid> = row.try_get("id").map_err(StoreError::from)?;
+            results[position as usize - 1] = match id {
+                Some(_) => Ok(TokenRow::from_row(row).map_err(StoreError::from)?),
+                None => Err(StoreError::UniqueViolation {
+                    field: String::from("token_string"),
+                    source: None,
+                }),
+            };
+        }
+        self.auditor
+            .record_many(
+                &mut transaction,
+                TOKEN_COLUMNS.name(),
+                AuditOperation::Insert,
+                results.iter().flatten().map(|row| (row.id, None, Some(row))).collect(),
+            )
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(results)
+    }
+
+    // The rows the filter selects among the tokens this store sees, locked
+    // until the write commits.
+    async fn locked_rows(&self, connection: &mut PgConnection, filter: &Filter) -> Result<Vec<TokenRow>, StoreError> {
+        let mut params = QueryParams::default();
+        let custom_query = format!(
+            "SELECT * FROM {} WHERE {} FOR UPDATE",
+            TOKEN_COLUMNS.table(),
+            self.visible(filter).to_sql(&TOKEN_COLUMNS, &mut params)?
+        );
+        log::debug!("final query is: {custom_query}");
+        let rows = params
+            .bind(sqlx::query(&custom_query))?
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        rows.iter()
+            .map(|row| TokenRow::from_row(row).map_err(StoreError::from))
+            .collect()
+    }
+
+    // Applies one patch to every token the filter selects with a single
+    // statement and returns the patched rows.
+    pub async fn patch_many(
+        &self,
+        connection: &mut PgConnection,
+        filter: &Filter,
+        patch: serde_json::Value,
+    ) -> Result<Vec<TokenRow>, StoreError> {
+        let serde_json::Value::Object(map) = &patch else {
+            log::debug!("The JSON data is not an object");
+            return Err(StoreError::NotFound);
+        };
+        let mut params = QueryParams::default();
+        let mut conditions = Vec::new();
+        for (key, value) in map {
+            let column = TOKEN_COLUMNS.patchable(key)?;
+            conditions.push(format!("{} = {}", column.quoted(), params.push(column.sql_type, column.stored_value(value)?)));
+        }
+        let naive_now = serde_json::json!(Utc::now().naive_utc());
+        conditions.push(format!(r#""updated_at" = {}"#, params.push(SqlType::Timestamp, naive_now)));
+        let custom_query = format!(
+            r#"UPDATE {} SET {} , "version" = "version" + 1 WHERE id = ANY(${}) RETURNING *"#,
+            TOKEN_COLUMNS.table(),
+            conditions.join(" , "),
+            params.len() + 1
+        );
+        log::debug!("final query is: {custom_query}");
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_rows(&mut transaction, filter).await?;
+        let ids: Vec<Uuid> = before.iter().map(|row| row.id).collect();
+        let before: HashMap<Uuid, TokenRow> = before.into_iter().map(|row| (row.id, row)).collect();
+        let rows = params
+            .bind(sqlx::query(&custom_query))?
+            .bind(&ids)
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?
+            .iter()
+            .map(|row| TokenRow::from_row(row).map_err(StoreError::from))
+            .collect::<Result<Vec<TokenRow>, StoreError>>()?;
+        self.auditor
+            .record_many(
+                &mut transaction,
+                TOKEN_COLUMNS.name(),
+                AuditOperation::Patch,
+                rows.iter().map(|row| (row.id, before.get(&row.id), Some(row))).collect(),
+            )
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    // Soft deletes every token the filter selects and returns the deleted rows.
+    pub async fn delete_many(&self, connection: &mut PgConnection, filter: &Filter) -> Result<Vec<TokenRow>, StoreError> {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_rows(&mut transaction, filter).await?;
+        let ids: Vec<Uuid> = before.iter().map(|row| row.id).collect();
+        let before: HashMap<Uuid, TokenRow> = before.into_iter().map(|row| (row.id, row)).collect();
+        let rows = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    update tokens set deleted_at = $1 where id = any($2) and deleted_at is null
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            Utc::now().naive_utc(),
+            &ids
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        self.auditor
+            .record_many(
+                &mut transaction,
+                TOKEN_COLUMNS.name(),
+                AuditOperation::Delete,
+                rows.iter().map(|row| (row.id, before.get(&row.id), Some(row))).collect(),
+            )
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    // Inserts the token, or when conflict_target ("token_string") matches an
+    // existing one, overwrites its update_columns with the values of new
+    // instead, all in one statement. With no update_columns the existing
+    // token is returned as it is. A conflict with a soft deleted token is a
+    // UniqueViolation unless the store includes deleted tokens.
+    pub async fn upsert(
+        &self,
+        connection: &mut PgConnection,
+        new: NewToken,
+        conflict_target: &str,
+        update_columns: &[&str],
+    ) -> Result<TokenRow, StoreError> {
+        if !UPSERT_TARGETS.contains(&conflict_target) {
+            return Err(StoreError::InvalidColumn {
+                column: conflict_target.to_string(),
+                reason: "is not unique",
+            });
+        }
+        let target = TOKEN_COLUMNS.column(conflict_target)?.quoted();
+        let mut assignments = Vec::new();
+        for name in update_columns {
+            if !UPSERT_COLUMNS.contains(name) {
+                return Err(StoreError::InvalidColumn {
+                    column: name.to_string(),
+                    reason: "is not written by upsert",
+                });
+            }
+            let column = TOKEN_COLUMNS.column(name)?.quoted();
+            assignments.push(format!("{} = excluded.{}", column, column));
+        }
+        if assignments.is_empty() {
+            assignments.push(String::from(r#""version" = "tokens"."version""#));
+        } else {
+            assignments.push(String::from(r#""updated_at" = $6"#));
+            assignments.push(String::from(r#""version" = "tokens"."version" + 1"#));
+        }
+        let custom_query = format!(
+            r#"INSERT INTO {}(token_string, token_type, blacklisted, expires_at, user_id) VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT ({}) DO UPDATE SET {} WHERE $7 OR "tokens"."deleted_at" IS NULL
+            RETURNING *, (xmax = 0) AS inserted"#,
+            TOKEN_COLUMNS.table(),
+            target,
+            assignments.join(" , ")
+        );
+        log::debug!("final query is: {custom_query}");
+        let token_string = TokenHasher::global()?.hash(new.token.get_token());
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = sqlx::query(&format!("SELECT * FROM {} WHERE {} = $1 FOR UPDATE", TOKEN_COLUMNS.table(), target))
+            .bind(&token_string)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?
+            .map(|row| TokenRow::from_row(&row))
+            .transpose()
+            .map_err(StoreError::from)?;
+        let row = sqlx::query(&custom_query)
+            .bind(&token_string)
+            .bind(new.token.get_type())
+            .bind(new.token.get_blacklisted())
+            .bind(new.expires_at)
+            .bind(new.user_id)
+            .bind(Utc::now().naive_utc())
+            .bind(self.include_deleted)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?
+            .ok_or_else(|| StoreError::UniqueViolation {
+                field: conflict_target.to_string(),
+                source: None,
+            })?;
+        let inserted: bool = row.try_get("inserted").map_err(StoreError::from)?;
+        let row = TokenRow::from_row(&row).map_err(StoreError::from)?;
+        if inserted {
+            self.auditor
+                .record(&mut transaction, TOKEN_COLUMNS.name(), row.id, AuditOperation::Insert, None, Some(&row))
+                .await?;
+        } else if !update_columns.is_empty() {
+            self.auditor
+                .record(&mut transaction, TOKEN_COLUMNS.name(), row.id, AuditOperation::Update, before.as_ref(), Some(&row))
+                .await?;
+        }
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Inserts a token that stops being valid once expires_at has passed, None
+    // for a token that never expires.
+    pub async fn insert_expiring<'a, A>(
+        &self,
+        connection: A,
+        token_obj: Token,
+        expires_at: Option<NaiveDateTime>,
+    ) -> Result<TokenRow, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        self.insert_for_user(connection, None, token_obj, expires_at).await
+    }
+
+    // Inserts a token owned by user_id, it is deleted along with the user.
+    pub async fn insert_for_user<'a, A>(
+        &self,
+        connection: A,
+        user_id: Option<Uuid>,
+        token_obj: Token,
+        expires_at: Option<NaiveDateTime>,
+    ) -> Result<TokenRow, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let token = TokenHasher::global()?.hash(token_obj.get_token());
+        let token_type = token_obj.get_type();
+        let blacklisted = token_obj.get_blacklisted();
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let row = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    insert into "tokens"(token_string,token_type,blacklisted,expires_at,user_id)
+                    values ($1, $2, $3, $4, $5)
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            token,
+            token_type as TokenType,
+            blacklisted,
+            expires_at,
+            user_id
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), row.id, AuditOperation::Insert, None, Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
     }
-}
 
-impl StoreTrait for TokenPGStore {
-    fn bind_values<'a>(&self,
-        custom_query: Query<'a,Postgres, PgArguments>,
-        json_value: &'a serde_json::Value,
-    ) -> Result<Query<'a,Postgres, PgArguments>, StoreError> {
-        // Loop through the JSON object by key
-        let mut custom_query = custom_query;
-
-        if let serde_json::Value::Object(map) = json_value {
-            for (key, value) in map {
-                println!("Key: {}, Value: {}", key, value);
-                match key.as_str() {
-                    "id" => {
-                        let muid:Uuid = serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
-                        println!("uuid obtained is: {:?}", muid);
-                        custom_query = custom_query.bind(muid);
-                    },
-                    "token_type" =>{
-                        let token_type:TokenType = serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
-                        println!("token_type obtained is: {:?}", token_type);
-                        custom_query = custom_query.bind(token_type);
-                    }
-                    "created_at"|"updated_at"=> {
-                        let time:NaiveDateTime = serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
-                        println!("time obtained is: {:?}", time);
-                        custom_query = custom_query.bind(time);
-                    },
-                    "blacklisted" => {
-                        let blacklisted:bool = serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
-                        println!("blacklisted obtained is: {:?}", blacklisted);
-                        custom_query = custom_query.bind(blacklisted);
-                    },
-                    _ => {
-                        // Handle other keys here
-                        let text:String = serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
-                        println!("text obtained is: {:?}", text);
-                        custom_query = custom_query.bind(text);
-                    }
+    pub async fn insert_with_ttl<'a, A>(&self, connection: A, token_obj: Token, ttl: Duration) -> Result<TokenRow, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let expires_at = Utc::now().naive_utc() + ttl;
+        self.insert_expiring(connection, token_obj, Some(expires_at)).await
+    }
+
+    // Looks a token up for use, failing with TokenBlacklisted or TokenExpired
+    // when it exists but may no longer be used.
+    pub async fn get_valid<'e, E>(&self, connection: E, token_string: &str) -> Result<TokenRow, StoreError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let row = sqlx::query_as!(
+            TokenRow,
+            r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE token_string = $1 and purpose = 'session' and deleted_at is null"#,
+            TokenHasher::global()?.hash(token_string)
+        )
+        .fetch_optional(connection)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        if row.blacklisted {
+            return Err(StoreError::TokenBlacklisted);
+        }
+        if row.expires_at.is_some_and(|expires_at| expires_at <= Utc::now().naive_utc()) {
+            return Err(StoreError::TokenExpired);
+        }
+        Ok(row)
+    }
+
+    // Deletes expired tokens batch_size rows at a time, so a large backlog
+    // does not hold locks on the table for long. Returns the number deleted.
+    pub async fn purge_expired(&self, connection: &Pool<Postgres>, batch_size: i64) -> Result<u64, StoreError> {
+        check_batch_size(batch_size)?;
+        let mut purged = 0;
+        loop {
+            let naive_now: NaiveDateTime = Utc::now().naive_utc();
+            let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+            let rows = sqlx::query_as!(
+                TokenRow,
+                // language=PostgreSQL
+                r#"
+                    delete from tokens where id in (select id from tokens where expires_at <= $1 limit $2)
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+                naive_now,
+                batch_size
+            )
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?;
+            self.auditor
+                .record_many(
+                    &mut transaction,
+                    TOKEN_COLUMNS.name(),
+                    AuditOperation::Delete,
+                    rows.iter().map(|row| (row.id, Some(row), None)).collect(),
+                )
+                .await?;
+            transaction.commit().await.map_err(StoreError::from)?;
+            purged += rows.len() as u64;
+            if (rows.len() as i64) < batch_size {
+                break;
+            }
+        }
+        if purged > 0 {
+            log::info!("purged {} expired token(s)", purged);
+        }
+        Ok(purged)
+    }
+
+    // Runs purge_expired every period until the returned handle is aborted.
+    // Failures are logged and retried on the next tick.
+    pub fn spawn_purge_task(connection: Pool<Postgres>, period: std::time::Duration, batch_size: i64) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if let Err(e) = TokenPGStore::default().purge_expired(&connection, batch_size).await {
+                    log::error!("purging expired tokens failed: {}", e);
                 }
             }
+        })
+    }
+
+    // Hashes token strings stored before tokens were kept hashed, batch_size
+    // rows per transaction. Run once after upgrading, plaintext rows can not
+    // be looked up until then. Returns the number of rows rewritten.
+    pub async fn hash_plaintext_tokens(&self, connection: &Pool<Postgres>, batch_size: i64) -> Result<u64, StoreError> {
+        check_batch_size(batch_size)?;
+        let hashed_pattern = format!("{}%", TOKEN_HASH_PREFIX);
+        let mut rewritten = 0;
+        loop {
+            let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+            let before = sqlx::query_as!(
+                TokenRow,
+                // language=PostgreSQL
+                r#"
+                    select id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version from tokens where token_string not like $1 limit $2 for update"#,
+                hashed_pattern,
+                batch_size
+            )
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?;
+            let mut rows = Vec::with_capacity(before.len());
+            for row in before.iter() {
+                let row = sqlx::query_as!(
+                    TokenRow,
+                    // language=PostgreSQL
+                    r#"
+                        update tokens set token_string = $1 where id = $2
+                        returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+                    TokenHasher::global()?.hash(&row.token_string),
+                    row.id
+                )
+                .fetch_one(&mut *transaction)
+                .await
+                .map_err(StoreError::from)?;
+                rows.push(row);
+            }
+            self.record_rows(&mut transaction, AuditOperation::Update, before, &rows).await?;
+            transaction.commit().await.map_err(StoreError::from)?;
+            rewritten += rows.len() as u64;
+            if (rows.len() as i64) < batch_size {
+                break;
+            }
+        }
+        if rewritten > 0 {
+            log::info!("hashed {} plaintext token(s)", rewritten);
         }
+        Ok(rewritten)
+    }
 
-        Ok(custom_query)
+    // Swaps a refresh token for new_token in one transaction. The old token is
+    // blacklisted and points at its successor, the new one joins its family
+    // and keeps its owner and expiry. Presenting a token that was already
+    // rotated revokes the whole family and fails with TokenReused.
+    pub async fn rotate_refresh_token(
+        &self,
+        connection: &Pool<Postgres>,
+        old_token: &str,
+        new_token: Token,
+    ) -> Result<TokenRow, StoreError> {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let old = sqlx::query_as!(
+            TokenRow,
+            r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE token_string = $1 and purpose = 'session' and deleted_at is null for update"#,
+            TokenHasher::global()?.hash(old_token)
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        if old.replaced_by.is_some() {
+            let family_id = old.family_id.unwrap_or(old.id);
+            let before = sqlx::query_as!(
+                TokenRow,
+                r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE family_id = $1 and not blacklisted for update"#,
+                family_id
+            )
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?;
+            let ids: Vec<Uuid> = before.iter().map(|row| row.id).collect();
+            let revoked = sqlx::query_as!(
+                TokenRow,
+                // language=PostgreSQL
+                r#"
+                    update tokens set blacklisted = true, revoked_at = $1, revocation_reason = $2, updated_at = $1, version = version + 1
+                    where id = any($3)
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+                naive_now,
+                RevocationReason::ReuseDetected as RevocationReason,
+                &ids
+            )
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?;
+            self.record_rows(&mut transaction, AuditOperation::Revoke, before, &revoked).await?;
+            transaction.commit().await.map_err(StoreError::from)?;
+            log::warn!(
+                "rotated token {} was reused, revoked {} token(s) of family {}",
+                old.id,
+                revoked.len(),
+                family_id
+            );
+            return Err(StoreError::TokenReused);
+        }
+        if old.blacklisted {
+            return Err(StoreError::TokenBlacklisted);
+        }
+        if old.expires_at.is_some_and(|expires_at| expires_at <= naive_now) {
+            return Err(StoreError::TokenExpired);
+        }
+        if old.token_type != TokenType::RefreshToken || new_token.get_type() != TokenType::RefreshToken {
+            return Err(StoreError::InvalidInput {
+                field: String::from("token_type"),
+                reason: String::from("only refresh tokens can be rotated"),
+                source: None,
+            });
+        }
+        let family_id = old.family_id.unwrap_or(old.id);
+        let row = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    insert into "tokens"(token_string,token_type,blacklisted,expires_at,user_id,family_id)
+                    values ($1, $2, false, $3, $4, $5)
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            TokenHasher::global()?.hash(new_token.get_token()),
+            TokenType::RefreshToken as TokenType,
+            old.expires_at,
+            old.user_id,
+            family_id
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        let rotated = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    update tokens set blacklisted = true, family_id = $1, replaced_by = $2, revoked_at = $3, updated_at = $3, version = version + 1 where id = $4
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            family_id,
+            row.id,
+            naive_now,
+            old.id
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), row.id, AuditOperation::Insert, None, Some(&row))
+            .await?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), old.id, AuditOperation::Revoke, Some(&old), Some(&rotated))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Blacklists a token, recording when and why. A token that is already
+    // revoked keeps its first revocation.
+    pub async fn revoke<'a, A>(&self, connection: A, token_string: &str, reason: RevocationReason) -> Result<TokenRow, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = sqlx::query_as!(
+            TokenRow,
+            r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE token_string = $1 for update"#,
+            TokenHasher::global()?.hash(token_string)
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        let row = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    update tokens set blacklisted = true, revoked_at = coalesce(revoked_at, $1),
+                    revocation_reason = case when blacklisted then revocation_reason else $2 end, updated_at = $1, version = version + 1
+                    where id = $3
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            naive_now,
+            reason as RevocationReason,
+            before.id
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), row.id, AuditOperation::Revoke, Some(&before), Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Revokes the given tokens in one statement, unknown tokens are skipped.
+    // Returns the number of tokens that were still usable.
+    pub async fn revoke_many<'a, A>(&self, connection: A, token_strings: &[String], reason: RevocationReason) -> Result<u64, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let hasher = TokenHasher::global()?;
+        let hashes = token_strings
+            .iter()
+            .map(|token_string| hasher.hash(token_string))
+            .collect::<Vec<String>>();
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = sqlx::query_as!(
+            TokenRow,
+            r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE token_string = any($1) and not blacklisted for update"#,
+            &hashes
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        let rows = self.revoke_rows(&mut transaction, &before, reason).await?;
+        self.record_rows(&mut transaction, AuditOperation::Revoke, before, &rows).await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(rows.len() as u64)
+    }
+
+    // Blacklists the locked rows with one revocation time and reason.
+    async fn revoke_rows(
+        &self,
+        connection: &mut PgConnection,
+        rows: &[TokenRow],
+        reason: RevocationReason,
+    ) -> Result<Vec<TokenRow>, StoreError> {
+        let ids: Vec<Uuid> = rows.iter().map(|row| row.id).collect();
+        let rows = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    update tokens set blacklisted = true, revoked_at = $1, revocation_reason = $2, updated_at = $1, version = version + 1
+                    where id = any($3)
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            Utc::now().naive_utc(),
+            reason as RevocationReason,
+            &ids
+        )
+        .fetch_all(&mut *connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    // Single indexed lookup for auth middleware. Tokens the store does not
+    // know are not revoked, use get_valid where a token has to exist.
+    // Soft deleted tokens count as revoked.
+    pub async fn is_revoked<'e, E>(&self, connection: E, token_string: &str) -> Result<bool, StoreError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let revoked = sqlx::query_scalar!(
+            r#"SELECT exists(SELECT 1 FROM tokens WHERE token_string = $1 and (blacklisted or deleted_at is not null)) as "revoked!""#,
+            TokenHasher::global()?.hash(token_string)
+        )
+        .fetch_one(connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(revoked)
+    }
+
+    // Issues a single use token for the purpose, replacing any the user still
+    // holds for it. Returns the token to hand to the user, only its hash is
+    // kept.
+    pub async fn issue_for_purpose(
+        &self,
+        connection: &mut PgConnection,
+        user_id: Uuid,
+        purpose: TokenPurpose,
+        ttl: Duration,
+    ) -> Result<(String, TokenRow), StoreError> {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let replaced = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    delete from tokens where user_id = $1 and purpose = $2
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            user_id,
+            purpose as TokenPurpose
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        self.auditor
+            .record_many(
+                &mut transaction,
+                TOKEN_COLUMNS.name(),
+                AuditOperation::Delete,
+                replaced.iter().map(|row| (row.id, Some(row), None)).collect(),
+            )
+            .await?;
+        let token_string = random_token();
+        let row = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    insert into "tokens"(token_string,token_type,blacklisted,expires_at,user_id,purpose)
+                    values ($1, $2, false, $3, $4, $5)
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            TokenHasher::global()?.hash(&token_string),
+            TokenType::AccessToken as TokenType,
+            Utc::now().naive_utc() + ttl,
+            user_id,
+            purpose as TokenPurpose
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), row.id, AuditOperation::Insert, None, Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok((token_string, row))
+    }
+
+    // When the user was last issued a token for the purpose, None if they
+    // hold none.
+    pub async fn last_issued_at<'e, E>(
+        &self,
+        connection: E,
+        user_id: Uuid,
+        purpose: TokenPurpose,
+    ) -> Result<Option<NaiveDateTime>, StoreError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let issued_at = sqlx::query_scalar!(
+            r#"SELECT max(created_at) FROM tokens WHERE user_id = $1 and purpose = $2"#,
+            user_id,
+            purpose as TokenPurpose
+        )
+        .fetch_one(connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(issued_at)
+    }
+
+    // Deletes a single use token as it is used, so it works at most once.
+    // Fails like get_valid when the token may no longer be used.
+    pub async fn consume<'a, A>(&self, connection: A, token_string: &str, purpose: TokenPurpose) -> Result<TokenRow, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let row = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    delete from tokens where token_string = $1 and purpose = $2 and deleted_at is null
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            TokenHasher::global()?.hash(token_string),
+            purpose as TokenPurpose
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), row.id, AuditOperation::Delete, Some(&row), None)
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        if row.blacklisted {
+            return Err(StoreError::TokenBlacklisted);
+        }
+        if row.expires_at.is_some_and(|expires_at| expires_at <= Utc::now().naive_utc()) {
+            return Err(StoreError::TokenExpired);
+        }
+        Ok(row)
+    }
+
+    pub async fn list_for_user<'e, E>(&self, connection: E, user_id: Uuid) -> Result<Vec<TokenRow>, StoreError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let rows = sqlx::query_as!(
+            TokenRow,
+            r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE user_id = $1 and ($2 or deleted_at is null) order by created_at asc, id asc"#,
+            user_id,
+            self.include_deleted
+        )
+        .fetch_all(connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    // Blacklists every token of the user, e.g. after a password change.
+    // Returns the number of tokens that were still usable.
+    pub async fn blacklist_all_for_user<'a, A>(
+        &self,
+        connection: A,
+        user_id: Uuid,
+        reason: RevocationReason,
+    ) -> Result<u64, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = sqlx::query_as!(
+            TokenRow,
+            r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE user_id = $1 and not blacklisted for update"#,
+            user_id
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        let rows = self.revoke_rows(&mut transaction, &before, reason).await?;
+        self.record_rows(&mut transaction, AuditOperation::Revoke, before, &rows).await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(rows.len() as u64)
+    }
+
+    // Soft deletes every token of the user, returning how many there were.
+    pub async fn delete_all_for_user<'a, A>(&self, connection: A, user_id: Uuid) -> Result<u64, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = sqlx::query_as!(
+            TokenRow,
+            r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE user_id = $1 and deleted_at is null for update"#,
+            user_id
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        let rows = self.soft_delete_rows(&mut transaction, &before).await?;
+        self.record_rows(&mut transaction, AuditOperation::Delete, before, &rows).await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(rows.len() as u64)
+    }
+
+    // Soft deletes the locked rows.
+    async fn soft_delete_rows(&self, connection: &mut PgConnection, rows: &[TokenRow]) -> Result<Vec<TokenRow>, StoreError> {
+        let ids: Vec<Uuid> = rows.iter().map(|row| row.id).collect();
+        let rows = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    update tokens set deleted_at = $1 where id = any($2) and deleted_at is null
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            Utc::now().naive_utc(),
+            &ids
+        )
+        .fetch_all(&mut *connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    // Tokens of the user that get_valid would still accept.
+    pub async fn count_active_for_user<'e, E>(&self, connection: E, user_id: Uuid) -> Result<usize, StoreError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let count = sqlx::query_scalar!(
+            r#"SELECT count(*) as "count!" FROM tokens WHERE user_id = $1 and purpose = 'session' and not blacklisted and deleted_at is null and (expires_at is null or expires_at > $2)"#,
+            user_id,
+            naive_now
+        )
+        .fetch_one(connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(count as usize)
+    }
+
+    pub async fn delete_by_token<'a, A>(&self, connection: A, token_string: String) -> Result<(), StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = sqlx::query_as!(
+            TokenRow,
+            r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE token_string = $1 and deleted_at is null for update"#,
+            TokenHasher::global()?.hash(&token_string)
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        let rows = self.soft_delete_rows(&mut transaction, &before).await?;
+        self.record_rows(&mut transaction, AuditOperation::Delete, before, &rows).await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(())
+    }
+
+    // Brings back a soft deleted token, NotFound unless it is soft deleted.
+    pub async fn restore<'a, A>(&self, connection: A, id: Uuid) -> Result<TokenRow, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?;
+        let row = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    update tokens set deleted_at = null, updated_at = $1, version = version + 1 where id = $2 and deleted_at is not null
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            Utc::now().naive_utc(),
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), id, AuditOperation::Restore, before.as_ref(), Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Removes the token for good, soft deleted or not.
+    pub async fn hard_delete<'a, A>(&self, connection: A, id: Uuid) -> Result<(), StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?.ok_or(StoreError::NotFound)?;
+        sqlx::query!(
+            // language=PostgreSQL
+            r#"
+                    delete from tokens where id = $1"#,
+            id
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), id, AuditOperation::Delete, Some(&before), None)
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(())
+    }
+}
+
+impl PgJsonTrait for TokenPGStore {
+    fn columns(&self) -> &'static ColumnRegistry {
+        &TOKEN_COLUMNS
     }
     fn row_to_json(&self,row: &PgRow) -> Result<serde_json::Value, sqlx::Error> {
         let mut json_obj = serde_json::Map::new();
-        println!("trying to convert to json");
         for column in row.columns() {
             let column_name = column.name();
-            println!("column type is: {:?}",column.type_info().name());
             let column_value: serde_json::Value = match column.type_info().name() {
                 // Handle different types as needed
                 "UUID" => serde_json::json!(row.try_get::<uuid::Uuid, _>(column_name)?),
@@ -118,43 +1337,138 @@ impl StoreTrait for TokenPGStore {
     
         Ok(serde_json::Value::Object(json_obj))
     }
+}
+
+impl TypedStore<Token, Uuid> for TokenPGStore {
+    type Row = TokenRow;
+
+    async fn insert_row(&self, connection: &mut PgConnection, token_obj: Token) -> Result<TokenRow, StoreError> {
+        self.insert_for_user(connection, None, token_obj, None).await
+    }
+
+    async fn get_row(&self, connection: &mut PgConnection, id: Uuid) -> Result<Option<TokenRow>, StoreError> {
+        let row = sqlx::query_as!(TokenRow, r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens WHERE id = $1 and ($2 or deleted_at is null)"#, id, self.include_deleted)
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    async fn update_row(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        token_data: Token,
+    ) -> Result<TokenRow, StoreError> {
+        self.update_checked(connection, id, StoredToken::hashed(&token_data)?, None).await
+    }
+
+    async fn get_rows_page(
+        &self,
+        connection: &mut PgConnection,
+        request: &PageRequest,
+    ) -> Result<Page<TokenRow>, StoreError> {
+        let mut params = QueryParams::default();
+        let request = request.clone().filter(self.visible(&request.filter));
+        let (clause, backwards) = request.to_sql(&TOKEN_COLUMNS, &mut params)?;
+        let custom_query = format!("SELECT * FROM {} {}", TOKEN_COLUMNS.table(), clause);
+        log::debug!("final query is: {custom_query}");
+        let custom_query = params.bind(sqlx::query(&custom_query))?;
+        let rows = custom_query
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        let rows = rows
+            .iter()
+            .map(|row| TokenRow::from_row(row).map_err(StoreError::from))
+            .collect::<Result<Vec<TokenRow>, StoreError>>()?;
+        request.to_page(rows, backwards)
+    }
+
+    async fn get_rows_by_slug(
+        &self,
+        connection: &mut PgConnection,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<TokenRow>, StoreError> {
+        let filter = FilterQuery::from_json(&json_slug)?;
+        self.get_rows_by_filter(connection, &filter).await
+    }
+
+    async fn get_rows_by_filter(
+        &self,
+        connection: &mut PgConnection,
+        filter: &FilterQuery,
+    ) -> Result<Vec<TokenRow>, StoreError> {
+        let filter = FilterQuery {
+            filter: self.visible(&filter.filter),
+            ..filter.clone()
+        };
+        let mut params = QueryParams::default();
+        let custom_query = format!(
+            "SELECT * FROM {} {}",
+            TOKEN_COLUMNS.table(),
+            filter.to_sql(&TOKEN_COLUMNS, &mut params)?
+        );
+        log::debug!("final query is: {custom_query}");
+        let custom_query = params.bind(sqlx::query(&custom_query))?;
+        let rows = custom_query
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        let rows = rows
+            .iter()
+            .map(|row| TokenRow::from_row(row).map_err(StoreError::from))
+            .collect::<Result<Vec<TokenRow>, StoreError>>()?;
+        Ok(rows)
+    }
+
+    async fn get_rows_paginate(
+        &self,
+        connection: &mut PgConnection,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<TokenRow>, StoreError> {
+        let rows = sqlx::query_as!(
+            TokenRow,
+             r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version FROM tokens where $3 or deleted_at is null order by created_at asc, id asc limit $1 offset $2"#,
+             limit,offset,self.include_deleted)
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    async fn patch_row(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<TokenRow, StoreError> {
+        self.patch_checked(connection, id, patch, None).await
+    }
+}
+
+impl<'c> StoreTrait<&'c mut PgConnection> for TokenPGStore {
     async fn insert(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         item: serde_json::Value,
-    ) -> Result<(), StoreError> {
+    ) -> Result<serde_json::Value, StoreError> {
+        let expires_at: Option<NaiveDateTime> =
+            serde_json::from_value(item.get("expires_at").cloned().unwrap_or_default()).map_err(StoreError::JsonError)?;
+        let user_id: Option<Uuid> =
+            serde_json::from_value(item.get("user_id").cloned().unwrap_or_default()).map_err(StoreError::JsonError)?;
         let token_obj: Token = serde_json::from_value(item).map_err(StoreError::JsonError)?;
-
-        let token = token_obj.get_token().to_string();
-        println!("token to insert is: {}",token);
-        let token_type = token_obj.get_type();
-        let blacklisted = token_obj.get_blacklisted();
-        // TODO
-        // Store Hash instead of clear password, will be done after implementation of crypto library
-        sqlx::query!(
-            // language=PostgreSQL
-            r#"
-                    insert into "tokens"(token_string,token_type,blacklisted)
-                    values ($1, $2, $3)"#,
-            token,
-            token_type as TokenType,
-            blacklisted
-        )
-        .execute(connection)
-        .await
-        .map_err(StoreError::SqlxError)?;
-        Ok(())
+        let row = self.insert_for_user(connection, user_id, token_obj, expires_at).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
     }
 
     async fn get(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         id: Uuid,
     ) -> Result<Vec<serde_json::Value>, StoreError> {
-        let rows = sqlx::query_as!(TokenRow, r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted FROM tokens WHERE id = $1"#, id)
-            .fetch_all(connection)
-            .await
-            .map_err(StoreError::SqlxError)?;
+        let rows = self.get_row(connection, id).await?;
         let token_datas = rows
             .iter()
             .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
@@ -162,153 +1476,191 @@ impl StoreTrait for TokenPGStore {
         Ok(token_datas)
     }
 
-    async fn delete(&self, connection: &Pool<Postgres>, id: Uuid) -> Result<(), StoreError> {
-        sqlx::query!(
+    // Soft deletes the token, see restore and hard_delete.
+    async fn delete(&self, connection: &'c mut PgConnection, id: Uuid) -> Result<(), StoreError> {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?;
+        let row = sqlx::query_as!(
+            TokenRow,
             // language=PostgreSQL
             r#"
-                    delete from  tokens where id=$1"#,
+                    update tokens set deleted_at = $1 where id = $2 and deleted_at is null
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            Utc::now().naive_utc(),
             id
         )
-        .execute(connection)
+        .fetch_optional(&mut *transaction)
         .await
-        .map_err(StoreError::SqlxError)?;
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        self.auditor
+            .record(&mut transaction, TOKEN_COLUMNS.name(), id, AuditOperation::Delete, before.as_ref(), Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
         Ok(())
     }
 
     async fn update(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         id: Uuid,
         item: serde_json::Value,
-    ) -> Result<(), StoreError> {
+    ) -> Result<serde_json::Value, StoreError> {
         let token_data: Token = serde_json::from_value(item).map_err(StoreError::JsonError)?;
-        let naive_now: NaiveDateTime = Utc::now().naive_utc();
-        sqlx::query!(
-            // language=PostgreSQL
-            r#"
-                update tokens set token_string = $1, token_type = $2, blacklisted = $3, updated_at = $4 where id=$5"#,
-            token_data.get_token(),
-            token_data.get_type() as TokenType,
-            token_data.get_blacklisted(),
-            naive_now,
-            id
-        )
-        .execute(connection)
-        .await
-        .map_err(StoreError::SqlxError)?;
-        Ok(())
+        let row = self.update_row(connection, id, token_data).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
     }
 
     async fn get_by_slug(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         json_slug: serde_json::Value,
     ) -> Result<Vec<serde_json::Value>, StoreError> {
-        let mut custom_query: String = String::from(r#"SELECT * FROM tokens WHERE "#);
-        let mut values = Vec::new();
-        let mut keys = Vec::new();
-        let mut conditions = Vec::new();
-        // Check if the parsed value is an object
-        if let serde_json::Value::Object(map) = json_slug.clone() {
-            // Iterate over the key-value pairs in the object
+        let rows = self.get_rows_by_slug(connection, json_slug).await?;
+        let token_datas = rows
+            .iter()
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
+            .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
+        Ok(token_datas)
+    }
 
-            for (key, value) in map {
-                println!("Key: {}, Value: {}", key, value);
-                values.push(value.to_string().trim_matches('"').to_string());
-                keys.push(key.clone());
-                conditions.push(format!("{} = ${}", key, conditions.len() + 1));
-            }
-        } else {
-            log::debug!("The JSON data is not an object");
-            return Err(StoreError::NotFound);
-        }
-        custom_query.push_str(&conditions.join(" AND "));
-        println!("final query is: {custom_query}");
-        let mut custom_query = sqlx::query(&custom_query);
-        custom_query = self.bind_values(custom_query, &json_slug)?;
-        // for value in values{
-        //     custom_query = custom_query.bind(value);
-        // }
-        //print!("final query is: {:?}",custom_query);
-        let rows = custom_query
-            .fetch_all(connection)
-            .await
-            .map_err(StoreError::SqlxError)?;
-        println!("should have fetched");
-        let user_datas = rows
+    async fn get_page(
+        &self,
+        connection: &'c mut PgConnection,
+        request: PageRequest,
+    ) -> Result<Page<serde_json::Value>, StoreError> {
+        let page = self.get_rows_page(connection, &request).await?;
+        let token_datas = page
+            .items
             .iter()
-            .map(|row| self.row_to_json(row).map_err(StoreError::SqlxError))
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
             .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
-        Ok(user_datas)
+        Ok(Page {
+            items: token_datas,
+            next_cursor: page.next_cursor,
+            prev_cursor: page.prev_cursor,
+        })
     }
 
-    async fn count(&self, connection: &Pool<Postgres>) -> Result<usize, StoreError> {
+    async fn count(&self, connection: &'c mut PgConnection) -> Result<usize, StoreError> {
         let count: Option<i64> = sqlx::query_scalar(
             // language=PostgreSQL
             r#"
-                    SELECT COUNT(id) FROM tokens"#,
+                    SELECT COUNT(id) FROM tokens WHERE $1 OR deleted_at IS NULL"#,
         )
-        .fetch_one(connection)
+        .bind(self.include_deleted)
+        .fetch_one(&mut *connection)
         .await
-        .map_err(StoreError::SqlxError)?;
+        .map_err(StoreError::from)?;
 
         let count = if let Some(count) = count { count } else { 0 };
         Ok(count as usize)
     }
     async fn get_all_paginate(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         limit: i64,
         offset: i64,
     ) -> Result<Vec<serde_json::Value>, StoreError> {
-        let rows = sqlx::query_as!(
-            TokenRow,
-             r#"SELECT id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted FROM tokens order by id asc limit $1 offset $2"#,
-             limit,offset)
-            .fetch_all(connection)
-            .await
-            .map_err(StoreError::SqlxError)?;
-        let user_datas = rows
+        let rows = self.get_rows_paginate(connection, limit, offset).await?;
+        let token_datas = rows
             .iter()
             .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
             .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
-        Ok(user_datas)
+        Ok(token_datas)
     }
     async fn patch(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         id: Uuid,
         patch: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let row = self.patch_row(connection, id, patch).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
+    }
+}
+
+impl<'c> StoreTrait<&'c Pool<Postgres>> for TokenPGStore {
+    async fn insert(
+        &self,
+        connection: &'c Pool<Postgres>,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.insert(&mut *conn, item).await
+    }
+
+    async fn get(
+        &self,
+        connection: &'c Pool<Postgres>,
+        id: Uuid,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get(&mut *conn, id).await
+    }
+
+    async fn delete(
+        &self,
+        connection: &'c Pool<Postgres>,
+        id: Uuid,
     ) -> Result<(), StoreError> {
-        let mut custom_query: String = String::from(r#"update tokens set "#);
-        let mut conditions = Vec::new();
-        let mut max_variable = 0;
-        // Check if the parsed value is an object
-        if let serde_json::Value::Object(map) = patch.clone() {
-            // Iterate over the key-value pairs in the object
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.delete(&mut *conn, id).await
+    }
 
-            for (key, value) in map {
-                println!("Key: {}, Value: {}", key, value);
-                conditions.push(format!("{} = ${}", key, conditions.len() + 1));
-                max_variable = conditions.len() + 1;
-            }
-        } else {
-            log::debug!("The JSON data is not an object");
-            return Err(StoreError::NotFound);
-        }
-        custom_query.push_str(&conditions.join(" , "));
-        custom_query.push_str(format!(" WHERE id = ${}", max_variable).as_str());
-        println!("final query is: {custom_query}");
-        let mut custom_query = sqlx::query(&custom_query);
-        custom_query = self.bind_values(custom_query, &patch)?;
-        custom_query = custom_query.bind(id);
-        println!("id to patch {}", id.to_string());
-        let affected_rows = custom_query
-            .execute(connection)
-            .await
-            .map_err(StoreError::SqlxError)?;
+    async fn update(
+        &self,
+        connection: &'c Pool<Postgres>,
+        id: Uuid,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.update(&mut *conn, id, item).await
+    }
 
-        println!("Updated {} row(s)", affected_rows.rows_affected());
-        Ok(())
+    async fn get_by_slug(
+        &self,
+        connection: &'c Pool<Postgres>,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_by_slug(&mut *conn, json_slug).await
+    }
+
+    async fn get_page(
+        &self,
+        connection: &'c Pool<Postgres>,
+        request: PageRequest,
+    ) -> Result<Page<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_page(&mut *conn, request).await
+    }
+
+    async fn count(
+        &self,
+        connection: &'c Pool<Postgres>,
+    ) -> Result<usize, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.count(&mut *conn).await
+    }
+
+    async fn get_all_paginate(
+        &self,
+        connection: &'c Pool<Postgres>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_all_paginate(&mut *conn, limit, offset).await
+    }
+
+    async fn patch(
+        &self,
+        connection: &'c Pool<Postgres>,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.patch(&mut *conn, id, patch).await
     }
 }
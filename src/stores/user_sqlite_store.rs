@@ -0,0 +1,472 @@
+use crate::stores::auth::{hash_password, hash_password_patch, update_password_hash};
+use crate::stores::columns::{Dialect, QueryParams, SqlType};
+use crate::stores::cursor::{Page, PageRequest};
+use crate::stores::filter::{Filter, FilterQuery};
+use crate::stores::store::{StoreError, StoreTrait, TypedStore};
+use crate::stores::user_store::{UserRow, USER_COLUMNS};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::{Connection, Pool, Sqlite, SqliteConnection};
+use user_lib::user::user::User;
+use uuid::Uuid;
+
+// SQLite counterpart of UserPGStore, on the schema in ./migrations_sqlite.
+// SQLite can not generate ids or enum types, so ids are created here and
+// user_role is stored as text. Deletes are soft as in UserPGStore.
+#[derive(Debug, Default)]
+pub struct UserSqliteStore {
+    // whether reads and writes also see soft deleted users
+    include_deleted: bool,
+}
+
+impl UserSqliteStore {
+    // Makes the store see soft deleted users as well.
+    pub fn include_deleted(mut self) -> Self {
+        self.include_deleted = true;
+        self
+    }
+
+    // Narrows a filter to the users this store sees.
+    fn visible(&self, filter: &Filter) -> Filter {
+        if self.include_deleted {
+            filter.clone()
+        } else {
+            filter.clone().not_deleted()
+        }
+    }
+
+    pub async fn get_by_username(
+        &self,
+        connection: &mut SqliteConnection,
+        username: &str,
+    ) -> Result<serde_json::Value, StoreError> {
+        let row = sqlx::query_as::<_, UserRow>(r#"SELECT * FROM users WHERE username = $1 and ($2 or deleted_at is null)"#)
+            .bind(username)
+            .bind(self.include_deleted)
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(StoreError::from)?
+            .ok_or_else(|| StoreError::NotFound)?;
+        serde_json::to_value(&row).map_err(StoreError::JsonError)
+    }
+
+    // Brings back a soft deleted user, NotFound unless it is soft deleted.
+    // Its tokens stay deleted so it has to log in again.
+    pub async fn restore(&self, connection: &mut SqliteConnection, id: Uuid) -> Result<UserRow, StoreError> {
+        let row = sqlx::query_as::<_, UserRow>(
+            r#"
+                    update users set deleted_at = null, updated_at = $1, version = version + 1 where id = $2 and deleted_at is not null
+                    returning *"#,
+        )
+        .bind(Utc::now().naive_utc())
+        .bind(id)
+        .fetch_optional(&mut *connection)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        Ok(row)
+    }
+
+    // Removes the user for good, soft deleted or not, and its tokens with it.
+    pub async fn hard_delete(&self, connection: &mut SqliteConnection, id: Uuid) -> Result<(), StoreError> {
+        let result = sqlx::query(r#"delete from users where id=$1"#)
+            .bind(id)
+            .execute(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        if result.rows_affected() == 0 {
+            return Err(StoreError::NotFound);
+        }
+        Ok(())
+    }
+}
+
+impl TypedStore<User, Uuid, Sqlite> for UserSqliteStore {
+    type Row = UserRow;
+
+    async fn insert_row(&self, connection: &mut SqliteConnection, user_obj: User) -> Result<UserRow, StoreError> {
+        let password = hash_password(user_obj.get_password()).await?;
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let row = sqlx::query_as::<_, UserRow>(
+            r#"
+                    insert into "users"(id,username,email,password_hash,user_role,confirmed,created_at,updated_at)
+                    values ($1, $2, $3, $4, $5, $6, $7, $7)
+                    returning *"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_obj.get_name())
+        .bind(user_obj.get_email())
+        .bind(password)
+        .bind(user_obj.get_role())
+        .bind(user_obj.get_confirmed_status())
+        .bind(naive_now)
+        .fetch_one(&mut *connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    async fn get_row(&self, connection: &mut SqliteConnection, id: Uuid) -> Result<Option<UserRow>, StoreError> {
+        let row = sqlx::query_as::<_, UserRow>(r#"SELECT * FROM users WHERE id = $1 and ($2 or deleted_at is null)"#)
+            .bind(id)
+            .bind(self.include_deleted)
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    async fn update_row(
+        &self,
+        connection: &mut SqliteConnection,
+        id: Uuid,
+        user_data: User,
+    ) -> Result<UserRow, StoreError> {
+        let current = self.get_row(&mut *connection, id).await?;
+        let password = update_password_hash(
+            user_data.get_password(),
+            current.as_ref().map(|row| row.password_hash.as_str()),
+        )
+        .await?;
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let row = sqlx::query_as::<_, UserRow>(
+            r#"
+                update users set username = $1, email = $2, password_hash =$3, user_role = $4, confirmed = $5, updated_at = $6, version = version + 1 where id=$7 and ($8 or deleted_at is null)
+                returning *"#,
+        )
+        .bind(user_data.get_name())
+        .bind(user_data.get_email())
+        .bind(password)
+        .bind(user_data.get_role())
+        .bind(user_data.get_confirmed_status())
+        .bind(naive_now)
+        .bind(id)
+        .bind(self.include_deleted)
+        .fetch_optional(&mut *connection)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        Ok(row)
+    }
+
+    async fn get_rows_page(
+        &self,
+        connection: &mut SqliteConnection,
+        request: &PageRequest,
+    ) -> Result<Page<UserRow>, StoreError> {
+        let mut params = QueryParams::new(Dialect::Sqlite);
+        let request = request.clone().filter(self.visible(&request.filter));
+        let (clause, backwards) = request.to_sql(&USER_COLUMNS, &mut params)?;
+        let custom_query = format!("SELECT * FROM {} {}", USER_COLUMNS.table(), clause);
+        log::debug!("final query is: {custom_query}");
+        let rows = params
+            .bind(sqlx::query(&custom_query))?
+            .try_map(|row| sqlx::FromRow::from_row(&row))
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        request.to_page(rows, backwards)
+    }
+
+    async fn get_rows_by_slug(
+        &self,
+        connection: &mut SqliteConnection,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<UserRow>, StoreError> {
+        let filter = FilterQuery::from_json(&json_slug)?;
+        self.get_rows_by_filter(connection, &filter).await
+    }
+
+    async fn get_rows_by_filter(
+        &self,
+        connection: &mut SqliteConnection,
+        filter: &FilterQuery,
+    ) -> Result<Vec<UserRow>, StoreError> {
+        let filter = FilterQuery {
+            filter: self.visible(&filter.filter),
+            ..filter.clone()
+        };
+        let mut params = QueryParams::new(Dialect::Sqlite);
+        let custom_query = format!(
+            "SELECT * FROM {} {}",
+            USER_COLUMNS.table(),
+            filter.to_sql(&USER_COLUMNS, &mut params)?
+        );
+        log::debug!("final query is: {custom_query}");
+        let rows = params
+            .bind(sqlx::query(&custom_query))?
+            .try_map(|row| sqlx::FromRow::from_row(&row))
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    async fn get_rows_paginate(
+        &self,
+        connection: &mut SqliteConnection,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<UserRow>, StoreError> {
+        let rows = sqlx::query_as::<_, UserRow>(r#"SELECT * FROM users where $3 or deleted_at is null order by created_at asc, id asc limit $1 offset $2"#)
+            .bind(limit)
+            .bind(offset)
+            .bind(self.include_deleted)
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    async fn patch_row(
+        &self,
+        connection: &mut SqliteConnection,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<UserRow, StoreError> {
+        let patch = hash_password_patch(patch).await?;
+        let mut params = QueryParams::new(Dialect::Sqlite);
+        let mut conditions = Vec::new();
+        if let serde_json::Value::Object(map) = &patch {
+            for (key, value) in map {
+                let column = USER_COLUMNS.patchable(key)?;
+                let placeholder = params.push(column.sql_type, column.stored_value(value)?);
+                conditions.push(format!("{} = {}", column.quoted(), placeholder));
+            }
+        } else {
+            log::debug!("The JSON data is not an object");
+            return Err(StoreError::NotFound);
+        }
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let placeholder = params.push(SqlType::Timestamp, serde_json::json!(naive_now));
+        conditions.push(format!(r#""updated_at" = {}"#, placeholder));
+        conditions.push(String::from(r#""version" = "version" + 1"#));
+        let custom_query = format!(
+            "UPDATE {} SET {} WHERE id = {} AND ({} OR deleted_at IS NULL) RETURNING *",
+            USER_COLUMNS.table(),
+            conditions.join(" , "),
+            params.push(SqlType::Uuid, serde_json::json!(id)),
+            params.push(SqlType::Bool, serde_json::json!(self.include_deleted))
+        );
+        log::debug!("final query is: {custom_query}");
+        let row = params
+            .bind(sqlx::query(&custom_query))?
+            .try_map(|row| sqlx::FromRow::from_row(&row))
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(StoreError::from)?
+            .ok_or(StoreError::NotFound)?;
+        Ok(row)
+    }
+}
+
+impl<'c> StoreTrait<&'c mut SqliteConnection> for UserSqliteStore {
+    async fn insert(
+        &self,
+        connection: &'c mut SqliteConnection,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let user_obj: User = serde_json::from_value(item).map_err(StoreError::JsonError)?;
+        let row = self.insert_row(connection, user_obj).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
+    }
+
+    async fn get(
+        &self,
+        connection: &'c mut SqliteConnection,
+        id: Uuid,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let rows = self.get_row(connection, id).await?;
+        let user_datas = rows
+            .iter()
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
+            .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
+        Ok(user_datas)
+    }
+
+    // Soft deletes the user together with its tokens, as UserPGStore does.
+    async fn delete(&self, connection: &'c mut SqliteConnection, id: Uuid) -> Result<(), StoreError> {
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let result = sqlx::query(
+            r#"update users set deleted_at = $1 where id = $2 and deleted_at is null"#,
+        )
+        .bind(naive_now)
+        .bind(id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        if result.rows_affected() == 0 {
+            return Err(StoreError::NotFound);
+        }
+        sqlx::query(
+            r#"update tokens set deleted_at = $1 where user_id = $2 and deleted_at is null"#,
+        )
+        .bind(naive_now)
+        .bind(id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        connection: &'c mut SqliteConnection,
+        id: Uuid,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let user_data: User = serde_json::from_value(item).map_err(StoreError::JsonError)?;
+        let row = self.update_row(connection, id, user_data).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
+    }
+
+    async fn get_by_slug(
+        &self,
+        connection: &'c mut SqliteConnection,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let rows = self.get_rows_by_slug(connection, json_slug).await?;
+        let user_datas = rows
+            .iter()
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
+            .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
+        Ok(user_datas)
+    }
+
+    async fn get_page(
+        &self,
+        connection: &'c mut SqliteConnection,
+        request: PageRequest,
+    ) -> Result<Page<serde_json::Value>, StoreError> {
+        let page = self.get_rows_page(connection, &request).await?;
+        let user_datas = page
+            .items
+            .iter()
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
+            .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
+        Ok(Page {
+            items: user_datas,
+            next_cursor: page.next_cursor,
+            prev_cursor: page.prev_cursor,
+        })
+    }
+
+    async fn count(&self, connection: &'c mut SqliteConnection) -> Result<usize, StoreError> {
+        let count: i64 = sqlx::query_scalar(r#"SELECT COUNT(id) FROM users WHERE $1 OR deleted_at IS NULL"#)
+            .bind(self.include_deleted)
+            .fetch_one(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(count as usize)
+    }
+
+    async fn get_all_paginate(
+        &self,
+        connection: &'c mut SqliteConnection,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let rows = self.get_rows_paginate(connection, limit, offset).await?;
+        let user_datas = rows
+            .iter()
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
+            .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
+        Ok(user_datas)
+    }
+
+    async fn patch(
+        &self,
+        connection: &'c mut SqliteConnection,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let row = self.patch_row(connection, id, patch).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
+    }
+}
+
+impl<'c> StoreTrait<&'c Pool<Sqlite>> for UserSqliteStore {
+    async fn insert(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.insert(&mut *conn, item).await
+    }
+
+    async fn get(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        id: Uuid,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get(&mut *conn, id).await
+    }
+
+    async fn delete(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        id: Uuid,
+    ) -> Result<(), StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.delete(&mut *conn, id).await
+    }
+
+    async fn update(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        id: Uuid,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.update(&mut *conn, id, item).await
+    }
+
+    async fn get_by_slug(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_by_slug(&mut *conn, json_slug).await
+    }
+
+    async fn get_page(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        request: PageRequest,
+    ) -> Result<Page<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_page(&mut *conn, request).await
+    }
+
+    async fn count(
+        &self,
+        connection: &'c Pool<Sqlite>,
+    ) -> Result<usize, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.count(&mut *conn).await
+    }
+
+    async fn get_all_paginate(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_all_paginate(&mut *conn, limit, offset).await
+    }
+
+    async fn patch(
+        &self,
+        connection: &'c Pool<Sqlite>,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.patch(&mut *conn, id, patch).await
+    }
+}
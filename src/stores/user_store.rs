@@ -1,22 +1,33 @@
-use crate::stores::store::{StoreError, StoreTrait};
-use chrono::{NaiveDateTime, Utc};
+use crate::stores::audit::{AuditOperation, AuditSink, Auditor};
+use crate::stores::auth::{
+    decoy_hash, hash_password, hash_password_patch, needs_rehash, update_password_hash, AuthError, ConfirmationPolicy,
+    LockoutPolicy, PasswordResetPolicy,
+};
+use crate::stores::columns::{ColumnDef, ColumnRegistry, QueryParams, SqlType};
+use crate::stores::cursor::{Page, PageRequest};
+use crate::stores::filter::{Filter, FilterQuery};
+use crate::stores::store::{check_version, PgJsonTrait, StoreError, StoreTrait, TypedStore};
+use crate::stores::token_store::{RevocationReason, TokenPGStore, TokenPurpose, TokenRow, TOKEN_COLUMNS};
+use chrono::{Duration, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::{
-    postgres::PgArguments,
     query::{self, QueryAs},
-    Execute, Pool, Postgres,
+    Connection, Execute, Executor, PgConnection, Pool, Postgres,
 };
 use simple_logger::SimpleLogger;
 use std::{error::Error, io};
+use token_lib::token::token::TokenType;
 use user_lib::user::user::{User, UserRoles};
 use uuid::Uuid;
 use crypto_lib::crypto::{self, crypto::CryptoOp};
-use sqlx::query::Query;
 use sqlx::postgres::PgRow;
+use sqlx::FromRow;
 use sqlx::Row;
 use sqlx::Column;
 use sqlx::TypeInfo;
 use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 #[derive(Debug, Deserialize, sqlx::FromRow, Serialize, Clone)]
 pub struct UserRow {
     pub id: Uuid,
@@ -27,10 +38,76 @@ pub struct UserRow {
     pub confirmed: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    // failed logins since the last successful one
+    pub failed_login_count: i32,
+    pub locked_until: Option<NaiveDateTime>,
+    pub last_login_at: Option<NaiveDateTime>,
+    // set while the user is soft deleted
+    pub deleted_at: Option<NaiveDateTime>,
+    // bumped by every write, see update_if_version
+    pub version: i64,
 }
 
 #[derive(Debug, Default)]
-pub struct UserPGStore;
+pub struct UserPGStore {
+    lockout_policy: LockoutPolicy,
+    confirmation_policy: ConfirmationPolicy,
+    password_reset_policy: PasswordResetPolicy,
+    // whether reads and writes also see soft deleted users
+    include_deleted: bool,
+    auditor: Auditor,
+}
+
+// Fails with RateLimited while the last token of purpose issued to the user
+// is younger than resend_interval.
+async fn check_resend_interval(
+    connection: &mut PgConnection,
+    user_id: Uuid,
+    purpose: TokenPurpose,
+    resend_interval: Duration,
+) -> Result<(), StoreError> {
+    let issued_at = TokenPGStore::default()
+        .last_issued_at(&mut *connection, user_id, purpose)
+        .await?;
+    let until = issued_at.map(|issued_at| issued_at + resend_interval);
+    match until.filter(|until| *until > Utc::now().naive_utc()) {
+        Some(until) => Err(StoreError::RateLimited { until }),
+        None => Ok(()),
+    }
+}
+
+pub static USER_COLUMNS: ColumnRegistry = ColumnRegistry::new(
+    "users",
+    &[
+        ColumnDef::new("id", SqlType::Uuid, true, false),
+        ColumnDef::new("username", SqlType::Text, true, true),
+        ColumnDef::new("email", SqlType::Text, true, true),
+        ColumnDef::new("password_hash", SqlType::Text, false, true),
+        ColumnDef::new("user_role", SqlType::UserRole, true, true),
+        ColumnDef::new("confirmed", SqlType::Bool, true, true),
+        ColumnDef::new("created_at", SqlType::Timestamp, true, false),
+        ColumnDef::new("updated_at", SqlType::Timestamp, true, false),
+        ColumnDef::new("failed_login_count", SqlType::BigInt, true, false),
+        ColumnDef::new("locked_until", SqlType::Timestamp, true, false).nullable(),
+        ColumnDef::new("last_login_at", SqlType::Timestamp, true, false).nullable(),
+        ColumnDef::new("deleted_at", SqlType::Timestamp, true, false).nullable(),
+        ColumnDef::new("version", SqlType::BigInt, true, false),
+    ],
+);
+
+// Unique columns upsert can detect an existing user by.
+const UPSERT_TARGETS: &[&str] = &["username", "email"];
+// Columns upsert inserts and so may overwrite.
+const UPSERT_COLUMNS: &[&str] = &["username", "email", "password_hash", "user_role", "confirmed"];
+
+// The user_role label of a role, bulk writes bind roles as text arrays as
+// sqlx only binds arrays of enums declared in this crate.
+fn role_label(role: UserRoles) -> &'static str {
+    match role {
+        UserRoles::Admin => "admin",
+        UserRoles::Normal => "normal",
+    }
+}
 
 impl Into<User> for UserRow {
     fn into(self) -> User {
@@ -46,71 +123,908 @@ impl Into<User> for UserRow {
 }
 
 impl UserPGStore {
-    pub async fn get_by_username(
+    pub async fn get_by_username<'e, E>(
         &self,
-        connection: &Pool<Postgres>,
+        connection: E,
         username: &str,
-    ) -> Result<serde_json::Value, StoreError> {
-        let row = sqlx::query_as!(UserRow, r#"SELECT id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at FROM users WHERE username = $1"#, username)
+    ) -> Result<serde_json::Value, StoreError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let row = sqlx::query_as!(UserRow, r#"SELECT id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version FROM users WHERE username = $1 and ($2 or deleted_at is null)"#, username, self.include_deleted)
                 .fetch_optional(connection)
                 .await
-                .map_err(StoreError::SqlxError)?
+                .map_err(StoreError::from)?
                 .ok_or_else(|| StoreError::NotFound)?;
-        let user_data = serde_json::to_value(&row).expect("row conversion failed");
-        Ok(user_data)
+        serde_json::to_value(&row).map_err(StoreError::JsonError)
+    }
+
+    pub fn with_lockout_policy(mut self, lockout_policy: LockoutPolicy) -> Self {
+        self.lockout_policy = lockout_policy;
+        self
+    }
+
+    pub fn with_confirmation_policy(mut self, confirmation_policy: ConfirmationPolicy) -> Self {
+        self.confirmation_policy = confirmation_policy;
+        self
+    }
+
+    pub fn with_password_reset_policy(mut self, password_reset_policy: PasswordResetPolicy) -> Self {
+        self.password_reset_policy = password_reset_policy;
+        self
+    }
+
+    // Makes the store see soft deleted users as well, e.g. to look one up
+    // before restoring it.
+    pub fn include_deleted(mut self) -> Self {
+        self.include_deleted = true;
+        self
+    }
+
+    // Records writes through sink instead of into the audit_log table.
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.auditor.sink = sink;
+        self
+    }
+
+    // Records writes as made by the user actor_id.
+    pub fn acting_as(mut self, actor_id: Uuid) -> Self {
+        self.auditor.actor_id = Some(actor_id);
+        self
+    }
+
+    // The row as it is before a write, locked until the write commits.
+    async fn locked_row(&self, connection: &mut PgConnection, id: Uuid) -> Result<Option<UserRow>, StoreError> {
+        let row = sqlx::query_as!(UserRow, r#"SELECT id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version FROM users WHERE id = $1 for update"#, id)
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Replaces the row only while it is still at expected_version, the
+    // version read along with it, and fails with Conflict once another
+    // write got in between.
+    pub async fn update_if_version(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        expected_version: i64,
+        user_data: User,
+    ) -> Result<UserRow, StoreError> {
+        self.update_checked(connection, id, user_data, Some(expected_version)).await
+    }
+
+    // As update_if_version, for a patch.
+    pub async fn patch_if_version(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        expected_version: i64,
+        patch: serde_json::Value,
+    ) -> Result<UserRow, StoreError> {
+        self.patch_checked(connection, id, patch, Some(expected_version)).await
+    }
+
+    // update_row, failing with Conflict unless the row is at expected_version.
+    async fn update_checked(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        user_data: User,
+        expected_version: Option<i64>,
+    ) -> Result<UserRow, StoreError> {
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?;
+        check_version(before.as_ref().map(|row| row.version), expected_version)?;
+        let password = update_password_hash(
+            user_data.get_password(),
+            before.as_ref().map(|row| row.password_hash.as_str()),
+        )
+        .await?;
+        let row = sqlx::query_as!(
+            UserRow,
+            // language=PostgreSQL
+            r#"
+                update users set username = $1, email = $2, password_hash = $3, user_role = $4, confirmed = $5, updated_at = $6, version = version + 1 where id=$7 and ($8 or deleted_at is null)
+                returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
+            user_data.get_name(),
+            user_data.get_email(),
+            password,
+            user_data.get_role() as UserRoles,
+            user_data.get_confirmed_status(),
+            naive_now,
+            id,
+            self.include_deleted
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        self.auditor
+            .record(&mut transaction, USER_COLUMNS.name(), id, AuditOperation::Update, before.as_ref(), Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // patch_row, failing with Conflict unless the row is at expected_version.
+    async fn patch_checked(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        patch: serde_json::Value,
+        expected_version: Option<i64>,
+    ) -> Result<UserRow, StoreError> {
+        let patch = hash_password_patch(patch).await?;
+        let mut custom_query: String = format!("UPDATE {} SET ", USER_COLUMNS.table());
+        let mut conditions = Vec::new();
+        // Check if the parsed value is an object
+        if let serde_json::Value::Object(map) = &patch {
+            // Iterate over the key-value pairs in the object
+
+            for key in map.keys() {
+                let column = USER_COLUMNS.patchable(key)?;
+                conditions.push(format!("{} = ${}", column.quoted(), conditions.len() + 1));
+            }
+        } else {
+            log::debug!("The JSON data is not an object");
+            return Err(StoreError::NotFound);
+        }
+        conditions.push(format!(r#""updated_at" = ${}"#, conditions.len() + 1));
+        let max_variable = conditions.len() + 1;
+        custom_query.push_str(&conditions.join(" , "));
+        custom_query.push_str(r#" , "version" = "version" + 1"#);
+        custom_query.push_str(
+            format!(" WHERE id = ${} AND (${} OR deleted_at IS NULL) RETURNING *", max_variable, max_variable + 1).as_str(),
+        );
+        log::debug!("final query is: {custom_query}");
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let mut custom_query = sqlx::query(&custom_query);
+        custom_query = self.bind_values(custom_query, &patch)?;
+        custom_query = custom_query.bind(naive_now);
+        custom_query = custom_query.bind(id);
+        custom_query = custom_query.bind(self.include_deleted);
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?;
+        check_version(before.as_ref().map(|row| row.version), expected_version)?;
+        let row = custom_query
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?
+            .ok_or(StoreError::NotFound)?;
+        let row = UserRow::from_row(&row).map_err(StoreError::from)?;
+        self.auditor
+            .record(&mut transaction, USER_COLUMNS.name(), id, AuditOperation::Patch, before.as_ref(), Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Narrows a filter to the users this store sees.
+    fn visible(&self, filter: &Filter) -> Filter {
+        if self.include_deleted {
+            filter.clone()
+        } else {
+            filter.clone().not_deleted()
+        }
+    }
+
+    // Usernames and emails among the given ones that a user already has.
+    async fn taken_keys(
+        &self,
+        connection: &mut PgConnection,
+        usernames: &[String],
+        emails: &[String],
+    ) -> Result<(HashSet<String>, HashSet<String>), StoreError> {
+        let rows = sqlx::query!(
+            // language=PostgreSQL
+            r#"
+                    select username, email from users where username = any($1) or email = any($2)"#,
+            usernames,
+            emails
+        )
+        .fetch_all(&mut *connection)
+        .await
+        .map_err(StoreError::from)?;
+        Ok(rows.into_iter().map(|row| (row.username, row.email)).unzip())
+    }
+
+    // Inserts the users with a single statement and returns one result per
+    // user, in order. A user whose username or email is taken, by an existing
+    // user or an earlier one of the batch, gets the UniqueViolation insert_row
+    // would fail with and is left out, its password is not even hashed.
+    pub async fn insert_many(
+        &self,
+        connection: &mut PgConnection,
+        users: Vec<User>,
+    ) -> Result<Vec<Result<UserRow, StoreError>>, StoreError> {
+        let usernames: Vec<String> = users.iter().map(|user| user.get_name().to_string()).collect();
+        let emails: Vec<String> = users.iter().map(|user| user.get_email().to_string()).collect();
+        let (mut taken_usernames, mut taken_emails) = self.taken_keys(connection, &usernames, &emails).await?;
+        let mut results: Vec<Option<Result<UserRow, StoreError>>> = Vec::with_capacity(users.len());
+        let (mut batch_usernames, mut batch_emails, mut password_hashes, mut roles, mut confirmed) =
+            (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for user in &users {
+            let field = if taken_usernames.contains(user.get_name()) {
+                Some("username")
+            } else if taken_emails.contains(user.get_email()) {
+                Some("email")
+            } else {
+                None
+            };
+            if let Some(field) = field {
+                results.push(Some(Err(StoreError::UniqueViolation {
+                    field: field.to_string(),
+                    source: None,
+                })));
+                continue;
+            }
+            taken_usernames.insert(user.get_name().to_string());
+            taken_emails.insert(user.get_email().to_string());
+            batch_usernames.push(user.get_name().to_string());
+            batch_emails.push(user.get_email().to_string());
+            password_hashes.push(hash_password(user.get_password()).await?);
+            roles.push(role_label(user.get_role()));
+            confirmed.push(user.get_confirmed_status());
+            results.push(None);
+        }
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        // users inserted since taken_keys conflict as well, they are told
+        // apart below by the rows missing from the result
+        let rows = sqlx::query(
+            // language=PostgreSQL
+            r#"
+                    with input as (
+                        select * from unnest($1::varchar[], $2::varchar[], $3::varchar[], $4::varchar[]::user_role[], $5::bool[])
+                            with ordinality as input(username, email, password_hash, user_role, confirmed, position)
+                    ), inserted as (
+                        insert into users(username, email, password_hash, user_role, confirmed)
+                        select username, email, password_hash, user_role, confirmed from input order by position
+                        on conflict do nothing
+                        returning *
+                    )
+                    select inserted.*, input.position from inserted join input using (username)"#,
+        )
+        .bind(&batch_usernames)
+        .bind(&batch_emails)
+        .bind(&password_hashes)
+        .bind(&roles)
+        .bind(&confirmed)
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        let mut inserted: Vec<Option<UserRow>> = vec![None; batch_usernames.len()];
+        for row in &rows {
+            let position: i64 = row.try_get("position").map_err(StoreError::from)?;
+            inserted[position as usize - 1] = Some(UserRow::from_row(row).map_err(StoreError::from)?);
+        }
+        let skipped: Vec<String> = batch_usernames
+            .iter()
+            .zip(&inserted)
+            .filter(|(_, row)| row.is_none())
+            .map(|(username, _)| username.clone())
+            .collect();
+        let (raced_usernames, _) = self.taken_keys(&mut transaction, &skipped, &[]).await?;
+        let mut inserted = batch_usernames.iter().zip(inserted);
+        for result in results.iter_mut().filter(|result| result.is_none()) {
+            let Some((username, row)) = inserted.next() else {
+                break;
+            };
+            *result = Some(match row {
+                Some(row) => Ok(row),
+                None => Err(StoreError::UniqueViolation {
+                    field: String::from(if raced_usernames.contains(username) { "username" } else { "email" }),
+                    source: None,
+                }),
+            });
+        }
+        let rows: Vec<&UserRow> = results
+            .iter()
+            .filter_map(|result| result.as_ref().and_then(|result| result.as_ref().ok()))
+            .collect();
+        self.auditor
+            .record_many(
+                &mut transaction,
+                USER_COLUMNS.name(),
+                AuditOperation::Insert,
+                rows.into_iter().map(|row| (row.id, None, Some(row))).collect(),
+            )
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(results.into_iter().flatten().collect())
     }
-}
 
-impl StoreTrait for UserPGStore {
-    fn bind_values<'a>(&self,
-        custom_query: Query<'a,Postgres, PgArguments>,
-        json_value: &'a serde_json::Value,
-    ) -> Result<Query<'a,Postgres, PgArguments>, StoreError> {
-        // Loop through the JSON object by key
-        let mut custom_query = custom_query;
-
-        if let serde_json::Value::Object(map) = json_value {
-            for (key, value) in map {
-                println!("Key: {}, Value: {}", key, value);
-                match key.as_str() {
-                    "id" => {
-                        let muid:Uuid = serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
-                        println!("uuid obtained is: {:?}", muid);
-                        custom_query = custom_query.bind(muid);
-                    },
-                    "user_role" => {
-                        let role:UserRoles = serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
-                        println!("role obtained is: {:?}", role);
-                        custom_query = custom_query.bind(role);
-                    },
-                    "created_at"|"updated_at" => {
-                        let time:NaiveDateTime = serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
-                        println!("time obtained is: {:?}", time);
-                        custom_query = custom_query.bind(time);
-                    },
-                    "confirmed" => {
-                        let confirm:bool = serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
-                        println!("confirm obtained is: {:?}", confirm);
-                        custom_query = custom_query.bind(confirm);
-                    },
-                    _ => {
-                        // Handle other keys here
-                        let text:String = serde_json::from_value(value.clone()).map_err(StoreError::JsonError)?;
-                        println!("text obtained is: {:?}", text);
-                        custom_query = custom_query.bind(text);
-                    }
-                }
+    // The rows the filter selects among the users this store sees, locked
+    // until the write commits.
+    async fn locked_rows(&self, connection: &mut PgConnection, filter: &Filter) -> Result<Vec<UserRow>, StoreError> {
+        let mut params = QueryParams::default();
+        let custom_query = format!(
+            "SELECT * FROM {} WHERE {} FOR UPDATE",
+            USER_COLUMNS.table(),
+            self.visible(filter).to_sql(&USER_COLUMNS, &mut params)?
+        );
+        log::debug!("final query is: {custom_query}");
+        let rows = params
+            .bind(sqlx::query(&custom_query))?
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        rows.iter()
+            .map(|row| UserRow::from_row(row).map_err(StoreError::from))
+            .collect()
+    }
+
+    // Applies one patch to every user the filter selects with a single
+    // statement and returns the patched rows. Unlike insert_many it is all
+    // or nothing, a patch giving two users the same email fails as a whole.
+    pub async fn patch_many(
+        &self,
+        connection: &mut PgConnection,
+        filter: &Filter,
+        patch: serde_json::Value,
+    ) -> Result<Vec<UserRow>, StoreError> {
+        let patch = hash_password_patch(patch).await?;
+        let serde_json::Value::Object(map) = &patch else {
+            log::debug!("The JSON data is not an object");
+            return Err(StoreError::NotFound);
+        };
+        let mut params = QueryParams::default();
+        let mut conditions = Vec::new();
+        for (key, value) in map {
+            let column = USER_COLUMNS.patchable(key)?;
+            conditions.push(format!("{} = {}", column.quoted(), params.push(column.sql_type, column.stored_value(value)?)));
+        }
+        let naive_now = serde_json::json!(Utc::now().naive_utc());
+        conditions.push(format!(r#""updated_at" = {}"#, params.push(SqlType::Timestamp, naive_now)));
+        let custom_query = format!(
+            r#"UPDATE {} SET {} , "version" = "version" + 1 WHERE id = ANY(${}) RETURNING *"#,
+            USER_COLUMNS.table(),
+            conditions.join(" , "),
+            params.len() + 1
+        );
+        log::debug!("final query is: {custom_query}");
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_rows(&mut transaction, filter).await?;
+        let ids: Vec<Uuid> = before.iter().map(|row| row.id).collect();
+        let before: HashMap<Uuid, UserRow> = before.into_iter().map(|row| (row.id, row)).collect();
+        let rows = params
+            .bind(sqlx::query(&custom_query))?
+            .bind(&ids)
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?
+            .iter()
+            .map(|row| UserRow::from_row(row).map_err(StoreError::from))
+            .collect::<Result<Vec<UserRow>, StoreError>>()?;
+        self.auditor
+            .record_many(
+                &mut transaction,
+                USER_COLUMNS.name(),
+                AuditOperation::Patch,
+                rows.iter()
+                    .map(|row| (row.id, before.get(&row.id), Some(row)))
+                    .collect(),
+            )
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    // Soft deletes every user the filter selects along with their tokens,
+    // as delete does for one, and returns the deleted rows.
+    pub async fn delete_many(&self, connection: &mut PgConnection, filter: &Filter) -> Result<Vec<UserRow>, StoreError> {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_rows(&mut transaction, filter).await?;
+        let ids: Vec<Uuid> = before.iter().map(|row| row.id).collect();
+        let before: HashMap<Uuid, UserRow> = before.into_iter().map(|row| (row.id, row)).collect();
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        let rows = sqlx::query_as!(
+            UserRow,
+            // language=PostgreSQL
+            r#"
+                    update users set deleted_at = $1 where id = any($2) and deleted_at is null
+                    returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
+            naive_now,
+            &ids
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        let ids: Vec<Uuid> = rows.iter().map(|row| row.id).collect();
+        let tokens = sqlx::query!(
+            // language=PostgreSQL
+            r#"
+                    update tokens set deleted_at = $1 where user_id = any($2) and deleted_at is null"#,
+            naive_now,
+            &ids
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        self.auditor
+            .record_many(
+                &mut transaction,
+                USER_COLUMNS.name(),
+                AuditOperation::Delete,
+                rows.iter()
+                    .map(|row| (row.id, before.get(&row.id), Some(row)))
+                    .collect(),
+            )
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        log::debug!("deleted {} user(s) and {} token(s)", rows.len(), tokens.rows_affected());
+        Ok(rows)
+    }
+
+    // Inserts the user, or when conflict_target ("username" or "email")
+    // matches an existing one, overwrites its update_columns with the values
+    // of user instead, all in one statement. password_hash takes the hashed
+    // password of user. With no update_columns the existing user is returned
+    // as it is. A conflict with a soft deleted user is a UniqueViolation
+    // unless the store includes deleted users.
+    pub async fn upsert(
+        &self,
+        connection: &mut PgConnection,
+        user: User,
+        conflict_target: &str,
+        update_columns: &[&str],
+    ) -> Result<UserRow, StoreError> {
+        if !UPSERT_TARGETS.contains(&conflict_target) {
+            return Err(StoreError::InvalidColumn {
+                column: conflict_target.to_string(),
+                reason: "is not unique",
+            });
+        }
+        let target = USER_COLUMNS.column(conflict_target)?.quoted();
+        let mut assignments = Vec::new();
+        for name in update_columns {
+            if !UPSERT_COLUMNS.contains(name) {
+                return Err(StoreError::InvalidColumn {
+                    column: name.to_string(),
+                    reason: "is not written by upsert",
+                });
             }
+            let column = USER_COLUMNS.column(name)?.quoted();
+            assignments.push(format!("{} = excluded.{}", column, column));
+        }
+        if assignments.is_empty() {
+            assignments.push(String::from(r#""version" = "users"."version""#));
+        } else {
+            assignments.push(String::from(r#""updated_at" = $6"#));
+            assignments.push(String::from(r#""version" = "users"."version" + 1"#));
+        }
+        let custom_query = format!(
+            r#"INSERT INTO {}(username, email, password_hash, user_role, confirmed) VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT ({}) DO UPDATE SET {} WHERE $7 OR "users"."deleted_at" IS NULL
+            RETURNING *, (xmax = 0) AS inserted"#,
+            USER_COLUMNS.table(),
+            target,
+            assignments.join(" , ")
+        );
+        log::debug!("final query is: {custom_query}");
+        let password = hash_password(user.get_password()).await?;
+        let key = if conflict_target == "username" { user.get_name() } else { user.get_email() };
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = sqlx::query(&format!("SELECT * FROM {} WHERE {} = $1 FOR UPDATE", USER_COLUMNS.table(), target))
+            .bind(key)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?
+            .map(|row| UserRow::from_row(&row))
+            .transpose()
+            .map_err(StoreError::from)?;
+        let row = sqlx::query(&custom_query)
+            .bind(user.get_name())
+            .bind(user.get_email())
+            .bind(password)
+            .bind(user.get_role())
+            .bind(user.get_confirmed_status())
+            .bind(Utc::now().naive_utc())
+            .bind(self.include_deleted)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?
+            .ok_or_else(|| StoreError::UniqueViolation {
+                field: conflict_target.to_string(),
+                source: None,
+            })?;
+        let inserted: bool = row.try_get("inserted").map_err(StoreError::from)?;
+        let row = UserRow::from_row(&row).map_err(StoreError::from)?;
+        if inserted {
+            self.auditor
+                .record(&mut transaction, USER_COLUMNS.name(), row.id, AuditOperation::Insert, None, Some(&row))
+                .await?;
+        } else if !update_columns.is_empty() {
+            self.auditor
+                .record(&mut transaction, USER_COLUMNS.name(), row.id, AuditOperation::Update, before.as_ref(), Some(&row))
+                .await?;
+        }
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Issues the token a user confirms their email with, replacing any
+    // earlier one. Returns the token to mail, only its hash is stored.
+    pub async fn issue_email_confirmation(&self, connection: &Pool<Postgres>, id: Uuid) -> Result<String, StoreError> {
+        self.issue_confirmation(connection, id, false).await
+    }
+
+    // As issue_email_confirmation, but fails with RateLimited until the
+    // resend interval has passed since the last token was issued.
+    pub async fn resend_email_confirmation(&self, connection: &Pool<Postgres>, id: Uuid) -> Result<String, StoreError> {
+        self.issue_confirmation(connection, id, true).await
+    }
+
+    async fn issue_confirmation(&self, connection: &Pool<Postgres>, id: Uuid, rate_limited: bool) -> Result<String, StoreError> {
+        let token_store = TokenPGStore::default().audited_by(&self.auditor);
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        // the row lock keeps concurrent resends from both passing the check
+        let confirmed = sqlx::query_scalar!(r#"SELECT confirmed FROM users WHERE id = $1 and deleted_at is null for update"#, id)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?
+            .ok_or(StoreError::NotFound)?;
+        if confirmed {
+            return Err(StoreError::InvalidInput {
+                field: String::from("confirmed"),
+                reason: String::from("email is already confirmed"),
+                source: None,
+            });
         }
+        if rate_limited {
+            check_resend_interval(
+                &mut transaction,
+                id,
+                TokenPurpose::EmailConfirmation,
+                self.confirmation_policy.resend_interval,
+            )
+            .await?;
+        }
+        let (token_string, _) = token_store
+            .issue_for_purpose(&mut transaction, id, TokenPurpose::EmailConfirmation, self.confirmation_policy.ttl)
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(token_string)
+    }
+
+    // Marks the email of the token's user as confirmed and uses the token
+    // up, both or neither.
+    pub async fn confirm_email(&self, connection: &Pool<Postgres>, token_string: &str) -> Result<UserRow, StoreError> {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let token_row = TokenPGStore::default()
+            .audited_by(&self.auditor)
+            .consume(&mut *transaction, token_string, TokenPurpose::EmailConfirmation)
+            .await?;
+        let user_id = token_row.user_id.ok_or(StoreError::NotFound)?;
+        let before = self.locked_row(&mut transaction, user_id).await?;
+        let row = sqlx::query_as!(
+            UserRow,
+            // language=PostgreSQL
+            r#"
+                update users set confirmed = true, updated_at = $1, version = version + 1 where id = $2
+                returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
+            Utc::now().naive_utc(),
+            user_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        self.auditor
+            .record(&mut transaction, USER_COLUMNS.name(), user_id, AuditOperation::Update, before.as_ref(), Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
 
-        Ok(custom_query)
+    // Issues a password reset token for the user with email, replacing any
+    // earlier one, and fails with RateLimited until the resend interval has
+    // passed. Returns the token to mail, only its hash is stored. Callers
+    // should answer an unknown email (NotFound) like a known one.
+    pub async fn issue_password_reset(&self, connection: &Pool<Postgres>, email: &str) -> Result<String, StoreError> {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        // the row lock keeps concurrent requests from both passing the check
+        let id = sqlx::query_scalar!(r#"SELECT id FROM users WHERE email = $1 and deleted_at is null for update"#, email)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?
+            .ok_or(StoreError::NotFound)?;
+        check_resend_interval(
+            &mut transaction,
+            id,
+            TokenPurpose::PasswordReset,
+            self.password_reset_policy.resend_interval,
+        )
+        .await?;
+        let (token_string, _) = TokenPGStore::default()
+            .audited_by(&self.auditor)
+            .issue_for_purpose(&mut transaction, id, TokenPurpose::PasswordReset, self.password_reset_policy.ttl)
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(token_string)
+    }
+
+    // Sets a new password for the token's user, uses the token up and revokes
+    // every session of the user, all or nothing. A reset also lifts a lockout.
+    pub async fn reset_password(
+        &self,
+        connection: &Pool<Postgres>,
+        token_string: &str,
+        password: &str,
+    ) -> Result<UserRow, StoreError> {
+        let password_hash = hash_password(password).await?;
+        let token_store = TokenPGStore::default().audited_by(&self.auditor);
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let token_row = token_store
+            .consume(&mut *transaction, token_string, TokenPurpose::PasswordReset)
+            .await?;
+        let user_id = token_row.user_id.ok_or(StoreError::NotFound)?;
+        let before = self.locked_row(&mut transaction, user_id).await?;
+        let row = sqlx::query_as!(
+            UserRow,
+            // language=PostgreSQL
+            r#"
+                update users set password_hash = $1, failed_login_count = 0, locked_until = null, updated_at = $2, version = version + 1 where id = $3
+                returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
+            password_hash,
+            Utc::now().naive_utc(),
+            user_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        self.auditor
+            .record(&mut transaction, USER_COLUMNS.name(), user_id, AuditOperation::Update, before.as_ref(), Some(&row))
+            .await?;
+        token_store
+            .blacklist_all_for_user(&mut *transaction, user_id, RevocationReason::PasswordChange)
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Brings back a soft deleted user, NotFound unless it is soft deleted.
+    pub async fn restore<'a, A>(&self, connection: A, id: Uuid) -> Result<UserRow, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?;
+        let row = sqlx::query_as!(
+            UserRow,
+            // language=PostgreSQL
+            r#"
+                update users set deleted_at = null, updated_at = $1, version = version + 1 where id = $2 and deleted_at is not null
+                returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
+            Utc::now().naive_utc(),
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        self.auditor
+            .record(&mut transaction, USER_COLUMNS.name(), id, AuditOperation::Restore, before.as_ref(), Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Removes the user for good, soft deleted or not, and its tokens with it.
+    // Meant for purges such as GDPR erasure, the tokens are recorded as
+    // deleted along with the user.
+    pub async fn hard_delete<'a, A>(&self, connection: A, id: Uuid) -> Result<(), StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?.ok_or(StoreError::NotFound)?;
+        let tokens = sqlx::query_as!(
+            TokenRow,
+            // language=PostgreSQL
+            r#"
+                    delete from tokens where user_id = $1
+                    returning id, token_string, created_at, updated_at, token_type AS "token_type!: TokenType", blacklisted, expires_at, user_id, family_id, replaced_by, revoked_at, revocation_reason AS "revocation_reason: RevocationReason", purpose AS "purpose!: TokenPurpose", deleted_at, version"#,
+            id
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        sqlx::query!(
+            // language=PostgreSQL
+            r#"
+                    delete from users where id = $1"#,
+            id
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        self.auditor
+            .record_many(
+                &mut transaction,
+                TOKEN_COLUMNS.name(),
+                AuditOperation::Delete,
+                tokens.iter().map(|row| (row.id, Some(row), None)).collect(),
+            )
+            .await?;
+        self.auditor
+            .record(&mut transaction, USER_COLUMNS.name(), id, AuditOperation::Delete, Some(&before), None)
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(())
+    }
+
+    // Counts a failed login and locks the account as the lockout policy says.
+    // A lock is only ever extended, never shortened by a concurrent failure.
+    // Login bookkeeping leaves updated_at and version alone, so a failed
+    // login does not make a concurrent update_if_version fail with Conflict.
+    pub async fn record_login_failure<'e, E>(&self, connection: E, id: Uuid) -> Result<UserRow, StoreError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let policy = &self.lockout_policy;
+        let naive_now: NaiveDateTime = Utc::now().naive_utc();
+        // counts the failure and locks the account in one statement, so
+        // concurrent failures neither get lost nor lock on a stale count; the
+        // lockout is LockoutPolicy::lockout_for, the exponent capped to stay
+        // within float8
+        let row = sqlx::query_as!(
+            UserRow,
+            // language=PostgreSQL
+            r#"
+                update users set
+                    failed_login_count = failed_login_count + 1,
+                    locked_until = case when failed_login_count + 1 >= $2
+                        then greatest(locked_until, $3::timestamp + make_interval(secs => least($4::float8 * power(2::float8, least(failed_login_count + 1 - $2, 62)), $5::float8)))
+                        else locked_until end
+                where id = $1
+                returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
+            id,
+            policy.threshold,
+            naive_now,
+            policy.base_lockout.num_milliseconds() as f64 / 1000.0,
+            policy.max_lockout.num_milliseconds() as f64 / 1000.0
+        )
+        .fetch_optional(connection)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        if let (Some(_), Some(locked_until)) = (policy.lockout_for(row.failed_login_count), row.locked_until) {
+            log::warn!("user {} locked until {} after {} failed logins", id, locked_until, row.failed_login_count);
+        }
+        Ok(row)
+    }
+
+    // Clears the failure count and lock, bookkeeping as record_login_failure.
+    pub async fn record_login_success<'e, E>(&self, connection: E, id: Uuid) -> Result<UserRow, StoreError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let row = sqlx::query_as!(
+            UserRow,
+            // language=PostgreSQL
+            r#"
+                update users set failed_login_count = 0, locked_until = null, last_login_at = $1 where id = $2
+                returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
+            Utc::now().naive_utc(),
+            id
+        )
+        .fetch_optional(connection)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        Ok(row)
+    }
+
+    // Replaces the password of a user, storing only its hash.
+    pub async fn set_password<'a, A>(&self, connection: A, id: Uuid, new_password: &str) -> Result<UserRow, StoreError>
+    where
+        A: sqlx::Acquire<'a, Database = Postgres>,
+    {
+        let password_hash = hash_password(new_password).await?;
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?;
+        let row = sqlx::query_as!(
+            UserRow,
+            // language=PostgreSQL
+            r#"
+                update users set password_hash = $1, updated_at = $2, version = version + 1 where id = $3
+                returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
+            password_hash,
+            Utc::now().naive_utc(),
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        self.auditor
+            .record(&mut transaction, USER_COLUMNS.name(), id, AuditOperation::Update, before.as_ref(), Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    // Checks a password against the user with that username, or else that
+    // email. Unknown users go through the same hash verification so timing
+    // does not tell them apart, and a hash made with outdated parameters is
+    // replaced by a fresh one while the password is at hand. Locked accounts
+    // are refused before the password is checked and every outcome is
+    // recorded for the lockout policy. The row stays locked from the lock
+    // check to the recorded outcome, so the rehash and the login bookkeeping
+    // commit together and concurrent logins of one user go one after another.
+    pub async fn authenticate(
+        &self,
+        connection: &Pool<Postgres>,
+        username_or_email: &str,
+        password: &str,
+    ) -> Result<UserRow, AuthError> {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let row = sqlx::query_as!(
+            UserRow,
+            r#"SELECT id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version FROM users WHERE (username = $1 or email = $1) and deleted_at is null order by username = $1 desc limit 1 for update"#,
+            username_or_email
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(StoreError::from)?;
+        let locked_until = row.as_ref().and_then(|row| row.locked_until);
+        if let Some(until) = locked_until.filter(|until| *until > Utc::now().naive_utc()) {
+            return Err(AuthError::Locked { until });
+        }
+        let crypto_op = CryptoOp::default();
+        let current_hash = decoy_hash().await?;
+        let stored_hash = row.as_ref().map_or(current_hash, |row| row.password_hash.as_str()).to_string();
+        let verified = crypto_op
+            .verify_password(password.to_string(), stored_hash)
+            .await
+            .map_err(|e| StoreError::other(&e))?;
+        let row = match row {
+            Some(row) if verified => row,
+            Some(row) => {
+                self.record_login_failure(&mut *transaction, row.id).await?;
+                transaction.commit().await.map_err(StoreError::from)?;
+                return Err(AuthError::InvalidCredentials);
+            }
+            None => return Err(AuthError::InvalidCredentials),
+        };
+        if needs_rehash(&row.password_hash, current_hash) {
+            let password_hash = hash_password(password).await?;
+            let rehashed = sqlx::query_as!(
+                UserRow,
+                // language=PostgreSQL
+                r#"
+                    update users set password_hash = $1, updated_at = $2, version = version + 1 where id = $3
+                    returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
+                password_hash,
+                Utc::now().naive_utc(),
+                row.id
+            )
+            .fetch_one(&mut *transaction)
+            .await
+            .map_err(StoreError::from)?;
+            self.auditor
+                .record(&mut transaction, USER_COLUMNS.name(), row.id, AuditOperation::Update, Some(&row), Some(&rehashed))
+                .await?;
+            log::info!("rehashed the password of user {}", row.id);
+        }
+        let row = self.record_login_success(&mut *transaction, row.id).await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+}
+
+impl PgJsonTrait for UserPGStore {
+    fn columns(&self) -> &'static ColumnRegistry {
+        &USER_COLUMNS
     }
     fn row_to_json(&self,row: &PgRow) -> Result<serde_json::Value, sqlx::Error> {
         let mut json_obj = serde_json::Map::new();
-        println!("trying to convert to json");
         for column in row.columns() {
             let column_name = column.name();
-            println!("column type is: {:?}",column.type_info().name());
             let column_value: serde_json::Value = match column.type_info().name() {
                 // Handle different types as needed
                 "UUID" => serde_json::json!(row.try_get::<uuid::Uuid, _>(column_name)?),
@@ -125,48 +1039,160 @@ impl StoreTrait for UserPGStore {
     
         Ok(serde_json::Value::Object(json_obj))
     }
-    async fn insert(
-        &self,
-        connection: &Pool<Postgres>,
-        item: serde_json::Value,
-    ) -> Result<(), StoreError> {
-        let user_obj: User = serde_json::from_value(item).map_err(StoreError::JsonError)?;
+}
+
+impl TypedStore<User, Uuid> for UserPGStore {
+    type Row = UserRow;
 
+    async fn insert_row(&self, connection: &mut PgConnection, user_obj: User) -> Result<UserRow, StoreError> {
         let name = user_obj.get_name().to_string();
-        let password = user_obj.get_password().to_string();
-        let crypto_op = CryptoOp::default();
-        let password = crypto_op.generate_hash(password).await.map_err(StoreError::OtherError)?;
+        let password = hash_password(user_obj.get_password()).await?;
         let user_role = user_obj.get_role();
         let email = user_obj.get_email();
         let confirmed = user_obj.get_confirmed_status();
-        // TODO
-        // Store Hash instead of clear password, will be done after implementation of crypto library
-        sqlx::query!(
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let row = sqlx::query_as!(
+            UserRow,
             // language=PostgreSQL
             r#"
                     insert into "users"(username,email, password_hash,user_role,confirmed)
-                    values ($1, $2, $3,$4,$5)"#,
+                    values ($1, $2, $3,$4,$5)
+                    returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
             name,
             email,
             password,
             user_role as UserRoles,
             confirmed
         )
-        .execute(connection)
+        .fetch_one(&mut *transaction)
         .await
-        .map_err(StoreError::SqlxError)?;
-        Ok(())
+        .map_err(StoreError::from)?;
+        self.auditor
+            .record(&mut transaction, USER_COLUMNS.name(), row.id, AuditOperation::Insert, None, Some(&row))
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    async fn get_row(&self, connection: &mut PgConnection, id: Uuid) -> Result<Option<UserRow>, StoreError> {
+        let row = sqlx::query_as!(UserRow, r#"SELECT id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version FROM users WHERE id = $1 and ($2 or deleted_at is null)"#, id, self.include_deleted)
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(row)
+    }
+
+    async fn update_row(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        user_data: User,
+    ) -> Result<UserRow, StoreError> {
+        self.update_checked(connection, id, user_data, None).await
+    }
+
+    async fn get_rows_page(
+        &self,
+        connection: &mut PgConnection,
+        request: &PageRequest,
+    ) -> Result<Page<UserRow>, StoreError> {
+        let mut params = QueryParams::default();
+        let request = request.clone().filter(self.visible(&request.filter));
+        let (clause, backwards) = request.to_sql(&USER_COLUMNS, &mut params)?;
+        let custom_query = format!("SELECT * FROM {} {}", USER_COLUMNS.table(), clause);
+        log::debug!("final query is: {custom_query}");
+        let custom_query = params.bind(sqlx::query(&custom_query))?;
+        let rows = custom_query
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        let rows = rows
+            .iter()
+            .map(|row| UserRow::from_row(row).map_err(StoreError::from))
+            .collect::<Result<Vec<UserRow>, StoreError>>()?;
+        request.to_page(rows, backwards)
+    }
+
+    async fn get_rows_by_slug(
+        &self,
+        connection: &mut PgConnection,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<UserRow>, StoreError> {
+        let filter = FilterQuery::from_json(&json_slug)?;
+        self.get_rows_by_filter(connection, &filter).await
+    }
+
+    async fn get_rows_by_filter(
+        &self,
+        connection: &mut PgConnection,
+        filter: &FilterQuery,
+    ) -> Result<Vec<UserRow>, StoreError> {
+        let filter = FilterQuery {
+            filter: self.visible(&filter.filter),
+            ..filter.clone()
+        };
+        let mut params = QueryParams::default();
+        let custom_query = format!(
+            "SELECT * FROM {} {}",
+            USER_COLUMNS.table(),
+            filter.to_sql(&USER_COLUMNS, &mut params)?
+        );
+        log::debug!("final query is: {custom_query}");
+        let custom_query = params.bind(sqlx::query(&custom_query))?;
+        let rows = custom_query
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        let rows = rows
+            .iter()
+            .map(|row| UserRow::from_row(row).map_err(StoreError::from))
+            .collect::<Result<Vec<UserRow>, StoreError>>()?;
+        Ok(rows)
+    }
+
+    async fn get_rows_paginate(
+        &self,
+        connection: &mut PgConnection,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<UserRow>, StoreError> {
+        let rows = sqlx::query_as!(
+            UserRow,
+             r#"SELECT id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version FROM users where $3 or deleted_at is null order by created_at asc, id asc limit $1 offset $2"#,
+             limit,offset,self.include_deleted)
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(StoreError::from)?;
+        Ok(rows)
+    }
+
+    async fn patch_row(
+        &self,
+        connection: &mut PgConnection,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<UserRow, StoreError> {
+        self.patch_checked(connection, id, patch, None).await
+    }
+}
+
+impl<'c> StoreTrait<&'c mut PgConnection> for UserPGStore {
+    async fn insert(
+        &self,
+        connection: &'c mut PgConnection,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let user_obj: User = serde_json::from_value(item).map_err(StoreError::JsonError)?;
+        let row = self.insert_row(connection, user_obj).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
     }
 
     async fn get(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         id: Uuid,
     ) -> Result<Vec<serde_json::Value>, StoreError> {
-        let rows = sqlx::query_as!(UserRow, r#"SELECT id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at FROM users WHERE id = $1"#, id)
-            .fetch_all(connection)
-            .await
-            .map_err(StoreError::SqlxError)?;
+        let rows = self.get_row(connection, id).await?;
         let user_datas = rows
             .iter()
             .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
@@ -174,78 +1200,53 @@ impl StoreTrait for UserPGStore {
         Ok(user_datas)
     }
 
-    async fn delete(&self, connection: &Pool<Postgres>, id: Uuid) -> Result<(), StoreError> {
-        sqlx::query!(
+    // Soft deletes the user together with its tokens, restore brings back
+    // only the user so it has to log in again.
+    async fn delete(&self, connection: &'c mut PgConnection, id: Uuid) -> Result<(), StoreError> {
+        let mut transaction = connection.begin().await.map_err(StoreError::from)?;
+        let before = self.locked_row(&mut transaction, id).await?;
+        let row = sqlx::query_as!(
+            UserRow,
             // language=PostgreSQL
             r#"
-                    delete from  users where id=$1"#,
+                    update users set deleted_at = $1 where id = $2 and deleted_at is null
+                    returning id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at,failed_login_count,locked_until,last_login_at,deleted_at,version"#,
+            Utc::now().naive_utc(),
             id
         )
-        .execute(connection)
+        .fetch_optional(&mut *transaction)
         .await
-        .map_err(StoreError::SqlxError)?;
+        .map_err(StoreError::from)?
+        .ok_or(StoreError::NotFound)?;
+        self.auditor
+            .record(&mut transaction, USER_COLUMNS.name(), id, AuditOperation::Delete, before.as_ref(), Some(&row))
+            .await?;
+        let tokens = TokenPGStore::default()
+            .audited_by(&self.auditor)
+            .delete_all_for_user(&mut *transaction, id)
+            .await?;
+        transaction.commit().await.map_err(StoreError::from)?;
+        log::debug!("deleted user {} and {} token(s)", id, tokens);
         Ok(())
     }
 
     async fn update(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         id: Uuid,
         item: serde_json::Value,
-    ) -> Result<(), StoreError> {
+    ) -> Result<serde_json::Value, StoreError> {
         let user_data: User = serde_json::from_value(item).map_err(StoreError::JsonError)?;
-        let naive_now: NaiveDateTime = Utc::now().naive_utc();
-        sqlx::query!(
-            // language=PostgreSQL
-            r#"
-                update users set username = $1, email = $2, password_hash =$3, user_role = $4, confirmed = $5, updated_at = $6 where id=$7"#,
-            user_data.get_name(),
-            user_data.get_email(),
-            user_data.get_password(),
-            user_data.get_role() as UserRoles,
-            user_data.get_confirmed_status(),
-            naive_now,
-            id
-        )
-        .execute(connection)
-        .await
-        .map_err(StoreError::SqlxError)?;
-        Ok(())
+        let row = self.update_row(connection, id, user_data).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
     }
 
     async fn get_by_slug(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         json_slug: serde_json::Value,
     ) -> Result<Vec<serde_json::Value>, StoreError> {
-        let mut custom_query: String = String::from(r#"SELECT * FROM users WHERE "#);
-        let mut values = Vec::new();
-        let mut conditions = Vec::new();
-        // Check if the parsed value is an object
-        if let serde_json::Value::Object(map) = json_slug {
-            // Iterate over the key-value pairs in the object
-
-            for (key, value) in map {
-                println!("Key: {}, Value: {}", key, value);
-                values.push(value.to_string().trim_matches('"').to_string());
-                conditions.push(format!("{} = ${}", key, conditions.len() + 1));
-            }
-        } else {
-            log::debug!("The JSON data is not an object");
-            return Err(StoreError::NotFound);
-        }
-        custom_query.push_str(&conditions.join(" AND "));
-        println!("final query is: {custom_query}");
-        let mut custom_query = sqlx::query_as::<_, UserRow>(&custom_query);
-        for value in values {
-            custom_query = custom_query.bind(value.clone());
-            println!("value to bind is: {value}");
-        }
-        //print!("final query is: {:?}",custom_query);
-        let rows = custom_query
-            .fetch_all(connection)
-            .await
-            .map_err(StoreError::SqlxError)?;
+        let rows = self.get_rows_by_slug(connection, json_slug).await?;
         let user_datas = rows
             .iter()
             .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
@@ -253,32 +1254,45 @@ impl StoreTrait for UserPGStore {
         Ok(user_datas)
     }
 
-    async fn count(&self, connection: &Pool<Postgres>) -> Result<usize, StoreError> {
+    async fn get_page(
+        &self,
+        connection: &'c mut PgConnection,
+        request: PageRequest,
+    ) -> Result<Page<serde_json::Value>, StoreError> {
+        let page = self.get_rows_page(connection, &request).await?;
+        let user_datas = page
+            .items
+            .iter()
+            .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
+            .collect::<Result<Vec<serde_json::Value>, StoreError>>()?;
+        Ok(Page {
+            items: user_datas,
+            next_cursor: page.next_cursor,
+            prev_cursor: page.prev_cursor,
+        })
+    }
+
+    async fn count(&self, connection: &'c mut PgConnection) -> Result<usize, StoreError> {
         let count: Option<i64> = sqlx::query_scalar(
             // language=PostgreSQL
             r#"
-                    SELECT COUNT(id) FROM users"#,
+                    SELECT COUNT(id) FROM users WHERE $1 OR deleted_at IS NULL"#,
         )
-        .fetch_one(connection)
+        .bind(self.include_deleted)
+        .fetch_one(&mut *connection)
         .await
-        .map_err(StoreError::SqlxError)?;
+        .map_err(StoreError::from)?;
 
         let count = if let Some(count) = count { count } else { 0 };
         Ok(count as usize)
     }
     async fn get_all_paginate(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         limit: i64,
         offset: i64,
     ) -> Result<Vec<serde_json::Value>, StoreError> {
-        let rows = sqlx::query_as!(
-            UserRow,
-             r#"SELECT id,username,email,password_hash, user_role AS "user_role!: UserRoles",confirmed,created_at,updated_at FROM users order by id asc limit $1 offset $2"#,
-             limit,offset)
-            .fetch_all(connection)
-            .await
-            .map_err(StoreError::SqlxError)?;
+        let rows = self.get_rows_paginate(connection, limit, offset).await?;
         let user_datas = rows
             .iter()
             .map(|row| serde_json::to_value(row.clone()).map_err(StoreError::JsonError))
@@ -287,38 +1301,96 @@ impl StoreTrait for UserPGStore {
     }
     async fn patch(
         &self,
-        connection: &Pool<Postgres>,
+        connection: &'c mut PgConnection,
         id: Uuid,
         patch: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let row = self.patch_row(connection, id, patch).await?;
+        serde_json::to_value(row).map_err(StoreError::JsonError)
+    }
+}
+
+impl<'c> StoreTrait<&'c Pool<Postgres>> for UserPGStore {
+    async fn insert(
+        &self,
+        connection: &'c Pool<Postgres>,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.insert(&mut *conn, item).await
+    }
+
+    async fn get(
+        &self,
+        connection: &'c Pool<Postgres>,
+        id: Uuid,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get(&mut *conn, id).await
+    }
+
+    async fn delete(
+        &self,
+        connection: &'c Pool<Postgres>,
+        id: Uuid,
     ) -> Result<(), StoreError> {
-        let mut custom_query: String = String::from(r#"update users set "#);
-        let mut values = Vec::new();
-        let mut conditions = Vec::new();
-        let mut max_variable = 0;
-        // Check if the parsed value is an object
-        if let serde_json::Value::Object(map) = patch.clone() {
-            // Iterate over the key-value pairs in the object
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.delete(&mut *conn, id).await
+    }
 
-            for (key, value) in map {
-                println!("Key: {}, Value: {}", key, value);
-                values.push(value.to_string().trim_matches('"').to_string());
-                conditions.push(format!("{} = ${}", key, conditions.len() + 1));
-                max_variable = conditions.len() + 1;
-            }
-        } else {
-            log::debug!("The JSON data is not an object");
-            return Err(StoreError::NotFound);
-        }
-        custom_query.push_str(&conditions.join(" , "));
-        custom_query.push_str(format!(" WHERE id = ${}", max_variable).as_str());
-        println!("final query is: {custom_query}");
-        let mut custom_query = sqlx::query(&custom_query);
-        custom_query = self.bind_values(custom_query, &patch)?;
-        custom_query = custom_query.bind(id);
-        println!("id to patch {}", id.to_string());
-        let affected_rows = custom_query.execute(connection).await.map_err(StoreError::SqlxError)?;
+    async fn update(
+        &self,
+        connection: &'c Pool<Postgres>,
+        id: Uuid,
+        item: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.update(&mut *conn, id, item).await
+    }
 
-        println!("Updated {} row(s)", affected_rows.rows_affected());
-        Ok(())
+    async fn get_by_slug(
+        &self,
+        connection: &'c Pool<Postgres>,
+        json_slug: serde_json::Value,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_by_slug(&mut *conn, json_slug).await
+    }
+
+    async fn get_page(
+        &self,
+        connection: &'c Pool<Postgres>,
+        request: PageRequest,
+    ) -> Result<Page<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_page(&mut *conn, request).await
+    }
+
+    async fn count(
+        &self,
+        connection: &'c Pool<Postgres>,
+    ) -> Result<usize, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.count(&mut *conn).await
+    }
+
+    async fn get_all_paginate(
+        &self,
+        connection: &'c Pool<Postgres>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.get_all_paginate(&mut *conn, limit, offset).await
+    }
+
+    async fn patch(
+        &self,
+        connection: &'c Pool<Postgres>,
+        id: Uuid,
+        patch: serde_json::Value,
+    ) -> Result<serde_json::Value, StoreError> {
+        let mut conn = connection.acquire().await.map_err(StoreError::from)?;
+        self.patch(&mut *conn, id, patch).await
     }
 }